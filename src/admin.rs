@@ -0,0 +1,200 @@
+// Commands operators send over the admin socket, one per line. Every command gets a reply ending in
+// a line of "ok" or "error: <reason>".
+
+use libc;
+use mio::unix::UnixStream;
+use std::io::{Error, Read, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+
+// Longest command line taken before the connection is dropped.
+const MAX_LINE_LENGTH: usize = 4096;
+
+pub const HELP: &'static str = concat!("help                  This list.\n",
+                                       "list                  Casters and watchers with their tokens and addresses.\n",
+                                       "kick <name>           Disconnect the caster with that name.\n",
+                                       "kick token:<token>    Disconnect a caster or watcher by their token.\n",
+                                       "broadcast <message>   Show a message to every watcher.\n",
+                                       "reload                Read the configuration file again and apply it.\n",
+                                       "shutdown              Stop the server.\n",
+                                       "upgrade               Hand every connection over to a new copy of the server binary.\n");
+
+
+#[derive(Debug, PartialEq)]
+pub enum AdminCommand {
+    Broadcast(String),
+    Help,
+    Kick(KickTarget),
+    List,
+    Reload,
+    Shutdown,
+    Upgrade,
+}
+
+/// Who to kick. Caster names can be made of digits, so tokens are told apart by their prefix.
+#[derive(Debug, PartialEq)]
+pub enum KickTarget {
+    Name(String),
+    Token(usize),
+}
+
+/// Who is at the other end of an admin connection, as the kernel tells it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Operator {
+    pub uid: libc::uid_t,
+    pub pid: libc::pid_t,
+}
+
+pub struct AdminConnection {
+    sock: UnixStream,
+    input: Vec<u8>,
+    operator: Option<Operator>,
+}
+
+
+impl AdminCommand {
+    pub fn parse(line: &str) -> Result<AdminCommand, String> {
+        let line = line.trim();
+        let (command, argument) = match line.find(' ') {
+            Some(idx) => (&line[..idx], line[idx+1..].trim()),
+            None => (line, ""),
+        };
+
+        match (command, argument) {
+            ("help", "") => Ok(AdminCommand::Help),
+            ("list", "") => Ok(AdminCommand::List),
+            ("kick", "") => Err(String::from("kick needs a token or caster name")),
+            ("kick", target) if target.starts_with("token:") => {
+                match target["token:".len()..].parse() {
+                    Ok(token) => Ok(AdminCommand::Kick(KickTarget::Token(token))),
+                    Err(_) => Err(format!("{} is not a token", &target["token:".len()..])),
+                }
+            },
+            ("kick", name) => Ok(AdminCommand::Kick(KickTarget::Name(String::from(name)))),
+            ("broadcast", "") => Err(String::from("broadcast needs a message")),
+            ("broadcast", message) => Ok(AdminCommand::Broadcast(String::from(message))),
+            ("reload", "") => Ok(AdminCommand::Reload),
+            ("shutdown", "") => Ok(AdminCommand::Shutdown),
+            ("upgrade", "") => Ok(AdminCommand::Upgrade),
+            ("help", _) | ("list", _) | ("reload", _) | ("shutdown", _) | ("upgrade", _) => {
+                Err(format!("{} takes no arguments", command))
+            },
+            _ => Err(format!("unknown command {}, try help", command)),
+        }
+    }
+}
+
+impl AdminConnection {
+    pub fn new(sock: UnixStream) -> Self {
+        let operator = peer_credentials(&sock);
+        AdminConnection {
+            sock: sock,
+            input: Vec::new(),
+            operator: operator,
+        }
+    }
+
+    /// The user and process that connected, unless the kernel wouldn't say.
+    pub fn operator(&self) -> Option<Operator> {
+        self.operator
+    }
+
+    /// Read whatever has arrived and return the complete, non-empty lines in it, along with
+    /// whether the connection is still open. An overlong line closes the connection.
+    pub fn read_lines(&mut self) -> (Vec<String>, bool) {
+        let mut buf = [0; 1024];
+        let mut open = true;
+        loop {
+            match self.sock.read(&mut buf) {
+                Ok(0) => {
+                    open = false;
+                    break;
+                },
+                Ok(num_bytes) => self.input.extend_from_slice(&buf[..num_bytes]),
+                Err(_) => break,
+            }
+        }
+
+        let mut lines = Vec::new();
+        while let Some(newline_idx) = self.input.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.input.drain(..newline_idx + 1).collect();
+            let line = String::from_utf8_lossy(&line).trim().to_string();
+            if !line.is_empty() {
+                lines.push(line);
+            }
+        }
+        if self.input.len() > MAX_LINE_LENGTH {
+            open = false;
+        }
+        (lines, open)
+    }
+
+    pub fn reply(&mut self, reply: &str) -> Result<(), Error> {
+        self.sock.write_all(reply.as_bytes())
+    }
+
+    pub fn socket(&self) -> &UnixStream {
+        &self.sock
+    }
+}
+
+fn peer_credentials(sock: &UnixStream) -> Option<Operator> {
+    let mut credentials = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut length = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(sock.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED,
+                         &mut credentials as *mut libc::ucred as *mut libc::c_void, &mut length)
+    };
+    if res == -1 {
+        return None;
+    }
+    Some(Operator {
+        uid: credentials.uid,
+        pid: credentials.pid,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use libc;
+    use mio::unix::{UnixListener, UnixStream};
+    use std::env;
+    use std::fs;
+    use super::{AdminCommand, AdminConnection, KickTarget, Operator};
+
+    #[test]
+    fn parse() {
+        assert_eq!(AdminCommand::parse("list"), Ok(AdminCommand::List));
+        assert_eq!(AdminCommand::parse("  shutdown\r"), Ok(AdminCommand::Shutdown));
+        assert_eq!(AdminCommand::parse("upgrade"), Ok(AdminCommand::Upgrade));
+        assert_eq!(AdminCommand::parse("kick token:12"), Ok(AdminCommand::Kick(KickTarget::Token(12))));
+        assert_eq!(AdminCommand::parse("kick 12"), Ok(AdminCommand::Kick(KickTarget::Name(String::from("12")))));
+        assert_eq!(AdminCommand::parse("kick caster1"),
+                   Ok(AdminCommand::Kick(KickTarget::Name(String::from("caster1")))));
+        assert_eq!(AdminCommand::parse("broadcast Back in  5 minutes"),
+                   Ok(AdminCommand::Broadcast(String::from("Back in  5 minutes"))));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(AdminCommand::parse("kick").is_err());
+        assert!(AdminCommand::parse("kick token:").is_err());
+        assert!(AdminCommand::parse("kick token:caster1").is_err());
+        assert!(AdminCommand::parse("broadcast ").is_err());
+        assert!(AdminCommand::parse("reload now").is_err());
+        assert!(AdminCommand::parse("restart").is_err());
+    }
+
+    #[test]
+    fn operator() {
+        let path = env::temp_dir().join(format!("termcastd-test-{}-admin.sock", unsafe { libc::getpid() }));
+        let _listener = UnixListener::bind(&path).unwrap();
+        // The listening end is this process too.
+        let connection = AdminConnection::new(UnixStream::connect(&path).unwrap());
+        let _ = fs::remove_file(&path);
+        assert_eq!(connection.operator(), Some(Operator {
+            uid: unsafe { libc::getuid() },
+            pid: unsafe { libc::getpid() },
+        }));
+    }
+}
@@ -0,0 +1,147 @@
+// An append-only record of who logged in and what operators did, kept apart from the log so it
+// can be handed to whoever looks after security. Each line is a JSON object, such as
+//
+//     {"time":"2016-03-01T12:00:00Z","event":"login","name":"foo","peer":"192.0.2.1:4321"}
+
+use chrono::{DateTime, UTC};
+use mio::Token;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use admin::Operator;
+use json::json_string;
+
+
+pub enum AuditEvent<'a> {
+    /// A caster logged in with a name that was already registered.
+    Login { name: &'a str, peer: Option<SocketAddr> },
+    /// A caster logged in with a name nobody had used, registering it.
+    Register { name: &'a str, peer: Option<SocketAddr> },
+    /// A caster was refused. The name is only known if they got as far as giving one.
+    LoginFailed { name: Option<&'a str>, reason: &'a str, peer: Option<SocketAddr> },
+    /// An operator disconnected a caster or watcher.
+    Kick { token: Token, kind: &'a str, name: Option<&'a str>, peer: Option<SocketAddr> },
+    /// A line sent to the admin socket, whether it was accepted and who sent it.
+    AdminCommand { command: &'a str, ok: bool, operator: Option<Operator> },
+}
+
+/// The audit log file, if one is configured. Recording to a log that isn't configured does
+/// nothing.
+pub struct AuditLog {
+    path: Option<PathBuf>,
+    file: Option<File>,
+}
+
+
+impl AuditLog {
+    pub fn disabled() -> Self {
+        AuditLog {
+            path: None,
+            file: None,
+        }
+    }
+
+    /// Open the log for appending. A new file is only readable by the user termcastd runs as.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file = try!(OpenOptions::new().append(true).create(true).mode(0o600).open(path));
+        Ok(AuditLog {
+            path: Some(path.to_path_buf()),
+            file: Some(file),
+        })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_ref().map(|path| path.as_path())
+    }
+
+    pub fn record(&mut self, event: &AuditEvent) {
+        if let Some(ref mut file) = self.file {
+            let line = format_event(&UTC::now(), event);
+            // Written in one go so lines from an upgraded server's old and new process don't mix.
+            if let Err(e) = file.write_all(line.as_bytes()) {
+                error!("event=audit_failed error={:?}", e.to_string());
+            }
+        }
+    }
+}
+
+fn format_event(time: &DateTime<UTC>, event: &AuditEvent) -> String {
+    let mut fields = vec![("time", json_string(&time.format("%Y-%m-%dT%H:%M:%SZ").to_string()))];
+    let peer = match *event {
+        AuditEvent::Login { name, peer } => {
+            fields.push(("event", json_string("login")));
+            fields.push(("name", json_string(name)));
+            peer
+        },
+        AuditEvent::Register { name, peer } => {
+            fields.push(("event", json_string("register")));
+            fields.push(("name", json_string(name)));
+            peer
+        },
+        AuditEvent::LoginFailed { name, reason, peer } => {
+            fields.push(("event", json_string("login_failed")));
+            if let Some(name) = name {
+                fields.push(("name", json_string(name)));
+            }
+            fields.push(("reason", json_string(reason)));
+            peer
+        },
+        AuditEvent::Kick { token, kind, name, peer } => {
+            fields.push(("event", json_string("kick")));
+            fields.push(("token", token.as_usize().to_string()));
+            fields.push(("kind", json_string(kind)));
+            if let Some(name) = name {
+                fields.push(("name", json_string(name)));
+            }
+            peer
+        },
+        AuditEvent::AdminCommand { command, ok, operator } => {
+            fields.push(("event", json_string("admin_command")));
+            fields.push(("command", json_string(command)));
+            fields.push(("ok", ok.to_string()));
+            if let Some(operator) = operator {
+                fields.push(("uid", operator.uid.to_string()));
+                fields.push(("pid", operator.pid.to_string()));
+            }
+            None
+        },
+    };
+    if let Some(peer) = peer {
+        fields.push(("peer", json_string(&peer.to_string())));
+    }
+
+    let fields: Vec<String> = fields.iter()
+        .map(|&(key, ref value)| format!("{}:{}", json_string(key), value))
+        .collect();
+    format!("{{{}}}\n", fields.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, UTC};
+    use admin::Operator;
+    use super::{AuditEvent, format_event};
+
+    #[test]
+    fn lines() {
+        let time = UTC.ymd(2016, 3, 1).and_hms(12, 0, 0);
+        let peer = Some("192.0.2.1:4321".parse().unwrap());
+        assert_eq!(format_event(&time, &AuditEvent::LoginFailed {
+                       name: Some("foo"),
+                       reason: "invalid_login",
+                       peer: peer,
+                   }),
+                   concat!(r#"{"time":"2016-03-01T12:00:00Z","event":"login_failed","name":"foo","#,
+                           r#""reason":"invalid_login","peer":"192.0.2.1:4321"}"#, "\n"));
+        assert_eq!(format_event(&time, &AuditEvent::AdminCommand {
+                       command: "broadcast \"hi\"",
+                       ok: true,
+                       operator: Some(Operator { uid: 1000, pid: 4321 }),
+                   }),
+                   concat!(r#"{"time":"2016-03-01T12:00:00Z","event":"admin_command","#,
+                           r#""command":"broadcast \"hi\"","ok":true,"uid":1000,"pid":4321}"#, "\n"));
+    }
+}
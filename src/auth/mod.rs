@@ -7,6 +7,13 @@ pub struct CasterAuth {
     logins: HashMap<String, pwhash::HashedPassword>,
 }
 
+/// Whether a successful login used a name that was already registered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Login {
+    Existing,
+    Registered,
+}
+
 impl CasterAuth {
     pub fn new() -> Self {
         CasterAuth {
@@ -17,7 +24,7 @@ impl CasterAuth {
     // Given a name and password, check the list of accounts. If the name is not registered,
     // register it. If the name is registered, check the password; if the password does not match
     // then return an error.
-    pub fn login(&mut self, name: &str, password: &str) -> Result<(), ()> {
+    pub fn login(&mut self, name: &str, password: &str) -> Result<Login, ()> {
         let name = String::from(name);
 
         let password_bytes = password.as_bytes();
@@ -25,9 +32,10 @@ impl CasterAuth {
                                       pwhash::OPSLIMIT_INTERACTIVE,
                                       pwhash::MEMLIMIT_INTERACTIVE));
 
+        let login = if self.logins.contains_key(&name) { Login::Existing } else { Login::Registered };
         let pwhash_entry = self.logins.entry(name).or_insert(pwh);
         if pwhash::pwhash_verify(&pwhash_entry, password_bytes) {
-            Ok(())
+            Ok(login)
         }
         else {
             Err(())
@@ -37,14 +45,14 @@ impl CasterAuth {
 
 #[cfg(test)]
 mod tests {
-    use super::CasterAuth;
+    use super::{CasterAuth, Login};
 
     #[test]
     fn register() {
         let mut ca = CasterAuth::new();
         let name = "foo";
         let pass = "";
-        assert!(ca.login(&name, &pass).is_ok(), "Can register new name.");
+        assert_eq!(ca.login(&name, &pass), Ok(Login::Registered), "Can register new name.");
         assert_eq!(ca.logins.len(), 1);
     }
 
@@ -73,8 +81,8 @@ mod tests {
         let pass = "";
         ca.login(&name, &pass);
 
-        assert!(ca.login(&name, &pass).is_ok(),
-                "Logging in works.");
+        assert_eq!(ca.login(&name, &pass), Ok(Login::Existing),
+                   "Logging in works.");
         assert_eq!(ca.logins.len(), 1);
     }
 
@@ -1,26 +1,65 @@
-use chrono::{DateTime, UTC};
+use chrono::{DateTime, Duration, UTC};
 use core::slice::Iter;
-use mio::Token;
-use mio::tcp::TcpStream;
-use std::io::Error;
+use std::collections::VecDeque;
+use libc;
+use mio::{EventSet, Evented, PollOpt, Selector, Timeout, Token};
+use mio::tcp::{Shutdown, TcpStream};
+use mio::unix::UnixStream;
+use std::io::{Error, ErrorKind};
 use std::io::Read;
 use std::io::Write;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::PathBuf;
 use std::str;
 
-use auth::CasterAuth;
+use audit::{AuditEvent, AuditLog};
+use auth::{CasterAuth, Login};
+use config::{DelayConfig, HistoryConfig, RecordConfig};
+use handover::SavedCaster;
+use history::History;
+use json::json_string;
+use logger::Conn;
+use metrics::Metrics;
+use record::Recorder;
 use ring::RingBuffer;
-use term;
-use watcher::WatcherLite;
+use watcher::{TerminalInfo, WatcherLite};
+use web::WebSession;
+use DisconnectReason;
+
+// Most delayed input held for one caster. Past this the oldest is released early, so a caster
+// sending faster than the delay allows gets a shorter delay instead of using up all the memory.
+const MAX_DELAYED: usize = 10_000_000;
+// Most of the replies to a WebSocket caster, such as pongs, left waiting for their socket.
+const MAX_REPLY_BACKLOG: usize = 64 * 1024;
 
 #[derive(Debug)]
 pub struct Caster {
-    sock: TcpStream,
+    sock: CasterStream,
     token: Token,
     name: Option<String>,
     cast_buffer: RingBuffer,
+    history: History,
     watchers: Vec<WatcherLite>,
     connected: DateTime<UTC>,
     last_byte_received: DateTime<UTC>,
+    recorder: Option<Recorder>,
+    delay: Option<Duration>,
+    // Input waiting for the delay to pass before it is broadcast.
+    delayed: VecDeque<(DateTime<UTC>, Vec<u8>)>,
+    delayed_size: usize,
+    release_timeout: Option<Timeout>,
+    // Set for casters connected over WebSocket, whose input comes wrapped in frames.
+    websocket: Option<WebSession>,
+    // WebSocket replies the socket couldn't take yet.
+    reply_backlog: Vec<u8>,
+}
+
+/// Casters connect over TCP, or over the caster Unix socket from the same machine.
+#[derive(Debug)]
+pub enum CasterStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
 }
 
 #[derive(Debug)]
@@ -36,7 +75,8 @@ pub struct CasterMenuEntry {
 
 #[derive(Debug)]
 enum AuthResults {
-    InvalidLogin,
+    /// The password didn't match the one the name was registered with.
+    InvalidLogin(String),
     InvalidName,
     MissingHello,
     NotEnoughParts,
@@ -47,48 +87,144 @@ enum AuthResults {
 
 
 impl Caster {
-    pub fn new(token: Token, sock: TcpStream) -> Self {
+    pub fn new(token: Token, sock: CasterStream, history: &HistoryConfig) -> Self {
         Caster {
             sock: sock,
             token: token,
             name: None,
             cast_buffer: RingBuffer::new(90_000),
+            history: History::new(history.size, Duration::minutes(history.minutes as i64)),
             watchers: Vec::new(),
             connected: UTC::now(),
             last_byte_received: UTC::now(),
+            recorder: None,
+            delay: None,
+            delayed: VecDeque::new(),
+            delayed_size: 0,
+            release_timeout: None,
+            websocket: None,
+            reply_backlog: Vec::new(),
         }
     }
 
-    pub fn input(&mut self, caster_auth: &mut CasterAuth) -> Result<(), ()> {
+    /// A caster connecting over WebSocket. Their first text message is the usual `hello` line.
+    pub fn with_websocket(token: Token, sock: TcpStream, history: &HistoryConfig) -> Self {
+        let mut caster = Caster::new(token, CasterStream::Tcp(sock), history);
+        caster.websocket = Some(WebSession::for_caster());
+        caster
+    }
+
+    /// A caster handed over by the server this one replaced, carrying on with their recording.
+    /// The delay is looked up again in case the configuration changed.
+    pub fn restore(saved: SavedCaster, record_config: Option<&RecordConfig>,
+                   delay_config: &DelayConfig, history: &HistoryConfig) -> Self {
+        let sock = unsafe { CasterStream::from_raw_fd(saved.fd, saved.unix) };
+        let mut caster = Caster::new(Token(saved.token), sock, history);
+        caster.cast_buffer.add(&saved.cast_buffer);
+        for &(ref when, ref chunk) in &saved.history {
+            caster.history.add(when, chunk);
+        }
+        caster.delayed = saved.delayed.into_iter().collect();
+        caster.delayed_size = caster.delayed.iter().map(|&(_, ref input)| input.len()).sum();
+        caster.connected = saved.connected;
+        caster.last_byte_received = saved.last_byte_received;
+        if let Some(ref name) = saved.name {
+            if let (true, Some(config)) = (saved.recording, record_config) {
+                caster.recorder = Recorder::new(config, name, &caster.connected, saved.token).ok();
+            }
+            caster.delay = delay_config.for_caster(name).map(|delay| Duration::seconds(delay as i64));
+        }
+        caster.name = saved.name;
+        caster
+    }
+
+    /// Read everything the caster has sent. Returns the watchers that could no longer be written
+    /// to, who have to be disconnected. An error means the caster has to be disconnected, and says
+    /// why.
+    pub fn input(&mut self, caster_auth: &mut CasterAuth, record_config: Option<&RecordConfig>,
+                 delay_config: &DelayConfig, metrics: &mut Metrics,
+                 audit: &mut AuditLog) -> Result<Vec<Token>, DisconnectReason> {
         let mut bytes_received = [0u8; 1024];
+        let mut failed = Vec::new();
         loop {
             match self.sock.read(&mut bytes_received) {
+                Ok(0) => {
+                    return Err(DisconnectReason::Hangup);
+                },
                 Ok(num_bytes) => {
                     self.last_byte_received = UTC::now();
+                    let (input, reply, closed) = match self.websocket {
+                        Some(ref mut session) => {
+                            let input = try!(session.receive(&bytes_received[..num_bytes])
+                                                    .map_err(|_| DisconnectReason::IoError));
+                            (input, session.take_reply(), session.is_closed())
+                        },
+                        None => (bytes_received[..num_bytes].to_vec(), Vec::new(), false),
+                    };
+                    if !reply.is_empty() {
+                        self.reply_backlog.extend(reply);
+                        try!(self.flush_reply().map_err(|_| DisconnectReason::IoError));
+                    }
+                    if closed {
+                        return Err(DisconnectReason::Quit);
+                    }
                     // If a name is set then all bytes go straight to the watchers.
                     if self.name.is_some() {
-                        self.relay_input(&bytes_received[..num_bytes]);
+                        failed.extend(self.relay_input(&input, metrics));
                     }
                     else {
-                        let auth = self.handle_auth(&bytes_received[..num_bytes], caster_auth);
+                        let auth = self.handle_auth(&input, caster_auth);
                         match auth {
-                            Ok((offset, name)) => {
+                            Ok((offset, name, record, login)) => {
+                                metrics.auth_result("success");
+                                info!("{} event=login name={}", Conn(self.token, self.sock.peer_addr()), name);
+                                let peer = self.sock.peer_addr();
+                                audit.record(&match login {
+                                    Login::Existing => AuditEvent::Login { name: &name, peer: peer },
+                                    Login::Registered => AuditEvent::Register { name: &name, peer: peer },
+                                });
+                                if record {
+                                    if let Some(config) = record_config {
+                                        // Failing to record is not a reason to refuse the caster.
+                                        self.recorder = Recorder::new(config, &name, &self.connected,
+                                                                      self.token.as_usize()).ok();
+                                    }
+                                }
+                                self.delay = delay_config.for_caster(&name)
+                                                         .map(|delay| Duration::seconds(delay as i64));
                                 self.name = Some(name);
-                                self.relay_input(&bytes_received[offset..num_bytes]);
+                                failed.extend(self.relay_input(&input[offset..], metrics));
                             },
                             // Not enough data sent so try again later.
                             Err(AuthResults::TryAgain) => {},
-                            Err(_) => return Err(()),
+                            Err(e) => {
+                                metrics.auth_result(e.name());
+                                info!("{} event=login_failed reason={}", Conn(self.token, self.sock.peer_addr()),
+                                      e.name());
+                                let name = match e {
+                                    AuthResults::InvalidLogin(ref name) => Some(&name[..]),
+                                    _ => None,
+                                };
+                                audit.record(&AuditEvent::LoginFailed {
+                                    name: name,
+                                    reason: e.name(),
+                                    peer: self.sock.peer_addr(),
+                                });
+                                return Err(DisconnectReason::AuthFailed);
+                            },
                         }
                     }
                 },
-                Err(_e) => {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
                     break;
                 },
+                Err(_) => {
+                    return Err(DisconnectReason::IoError);
+                },
             }
         }
 
-        Ok(())
+        Ok(failed)
     }
 
     pub fn menu_entry(&self) -> Option<CasterMenuEntry> {
@@ -107,10 +243,12 @@ impl Caster {
         }
     }
 
-    pub fn add_watcher(&mut self, mut watcher: WatcherLite) -> Result<(), Error> {
-        try!(watcher.write(term::clear_screen().as_bytes()));
-        try!(watcher.write(term::reset_cursor().as_bytes()));
-        try!(self.send_buffer(&mut watcher));
+    pub fn add_watcher(&mut self, mut watcher: WatcherLite, metrics: &mut Metrics) -> Result<(), Error> {
+        let (clear, reset) = (watcher.terminal.clear_screen(), watcher.terminal.reset_cursor());
+        try!(watcher.write(clear.as_bytes()));
+        try!(watcher.write(reset.as_bytes()));
+        let replayed = try!(self.send_buffer(&mut watcher));
+        metrics.replay(replayed);
         self.watchers.push(watcher);
         Ok(())
     }
@@ -122,11 +260,183 @@ impl Caster {
         }
     }
 
+    /// Stop sending live output to the watcher and return what the screen looked like at `when`,
+    /// as well as it can be rebuilt from the history. If the watcher's screen is already as it was
+    /// at an earlier `from`, only what happened since is returned. None if the watcher isn't
+    /// watching this caster.
+    pub fn time_shift(&mut self, token: Token, from: Option<&DateTime<UTC>>,
+                      when: &DateTime<UTC>) -> Option<Vec<u8>> {
+        let watcher = match self.watchers.iter_mut().find(|w| w.token() == token) {
+            Some(watcher) => watcher,
+            None => return None,
+        };
+        watcher.time_shifted = true;
+
+        let forward = match from {
+            Some(from) if from <= when => self.history.between(from, when),
+            _ => None,
+        };
+        if let Some(output) = forward {
+            return Some(output);
+        }
+        let mut output = Vec::new();
+        output.extend_from_slice(watcher.terminal.clear_screen().as_bytes());
+        output.extend_from_slice(watcher.terminal.reset_cursor().as_bytes());
+        output.extend(self.history.until(when));
+        Some(output)
+    }
+
+    /// Time of the oldest output a watcher can step back to.
+    pub fn oldest_history(&self) -> Option<DateTime<UTC>> {
+        self.history.oldest()
+    }
+
+    /// Bring a time shifted watcher back to the live stream.
+    pub fn go_live(&mut self, token: Token, metrics: &mut Metrics) -> Result<(), Error> {
+        let cast_buffer = self.cast_buffer.clone();
+        if let Some(watcher) = self.watchers.iter_mut().find(|w| w.token() == token) {
+            watcher.time_shifted = false;
+            let (clear, reset) = (watcher.terminal.clear_screen(), watcher.terminal.reset_cursor());
+            try!(watcher.write(clear.as_bytes()));
+            try!(watcher.write(reset.as_bytes()));
+            try!(watcher.write(&cast_buffer));
+            metrics.replay(cast_buffer.len());
+        }
+        Ok(())
+    }
+
+    /// Milliseconds until the oldest delayed input is due to be broadcast. None if nothing is
+    /// waiting.
+    pub fn release_delay(&self) -> Option<u64> {
+        match (self.delay, self.delayed.front()) {
+            (Some(delay), Some(&(received, _))) => {
+                let remaining = received + delay - UTC::now();
+                Some(if remaining.num_milliseconds() > 0 { remaining.num_milliseconds() as u64 } else { 0 })
+            },
+            _ => None,
+        }
+    }
+
+    /// Broadcast all delayed input whose delay has passed. Returns the watchers that could no
+    /// longer be written to.
+    pub fn release_delayed(&mut self, metrics: &mut Metrics) -> Vec<Token> {
+        let mut failed = Vec::new();
+        let delay = match self.delay {
+            Some(delay) => delay,
+            None => return failed,
+        };
+        let now = UTC::now();
+        while self.delayed.front().map(|&(received, _)| received + delay <= now).unwrap_or(false) {
+            failed.extend(self.release_oldest(&now, metrics));
+        }
+        failed
+    }
+
+    /// Send whatever WebSocket replies the socket couldn't take before. An error means the caster
+    /// has to be disconnected.
+    pub fn flush_reply(&mut self) -> Result<(), Error> {
+        while !self.reply_backlog.is_empty() {
+            match self.sock.write(&self.reply_backlog) {
+                Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "caster closed")),
+                Ok(num_bytes) => {
+                    self.reply_backlog.drain(..num_bytes);
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        if self.reply_backlog.len() > MAX_REPLY_BACKLOG {
+            return Err(Error::new(ErrorKind::Other, "caster isn't reading replies"));
+        }
+        Ok(())
+    }
+
+    pub fn set_release_timeout(&mut self, timeout: Timeout) {
+        self.release_timeout = Some(timeout);
+    }
+
+    pub fn take_release_timeout(&mut self) -> Option<Timeout> {
+        self.release_timeout.take()
+    }
+
+    pub fn has_release_timeout(&self) -> bool {
+        self.release_timeout.is_some()
+    }
+
+    /// Finish the recording and hang up. Input still waiting on the delay goes into the recording
+    /// but is never shown to the watchers.
+    pub fn close(&mut self) {
+        if let Some(mut recorder) = self.recorder.take() {
+            for &(_, ref input) in &self.delayed {
+                if recorder.record(input).is_err() {
+                    break;
+                }
+            }
+            let _ = recorder.finish();
+        }
+        self.delayed.clear();
+        self.delayed_size = 0;
+        let _ = self.sock.shutdown();
+    }
+
+    /// Everything needed to carry on with this caster in another server. None for casters
+    /// connected over WebSocket, whose framing can't be handed over.
+    pub fn save(&self) -> Option<SavedCaster> {
+        if self.websocket.is_some() {
+            return None;
+        }
+        Some(SavedCaster {
+            token: self.token.as_usize(),
+            fd: self.sock.as_raw_fd(),
+            unix: match self.sock {
+                CasterStream::Unix(_) => true,
+                CasterStream::Tcp(_) => false,
+            },
+            name: self.name.clone(),
+            connected: self.connected,
+            last_byte_received: self.last_byte_received,
+            recording: self.recorder.is_some(),
+            cast_buffer: self.cast_buffer.clone(),
+            history: self.history.chunks(),
+            delayed: self.delayed.iter().cloned().collect(),
+        })
+    }
+
+    /// The files being recorded to, if any.
+    pub fn recording_paths(&self) -> &[PathBuf] {
+        self.recorder.as_ref().map(|recorder| recorder.paths()).unwrap_or(&[])
+    }
+
+    /// Write out everything recorded so far, for when another server is about to carry on with
+    /// the recording.
+    pub fn flush_recording(&mut self) {
+        if let Some(ref mut recorder) = self.recorder {
+            let _ = recorder.finish();
+        }
+    }
+
+    /// Attach a watcher who already has the screen, such as one handed over along with this
+    /// caster.
+    pub fn resume_watcher(&mut self, watcher: WatcherLite) {
+        self.watchers.push(watcher);
+    }
+
+    pub fn update_watcher_terminal(&mut self, token: Token, terminal: &TerminalInfo) {
+        if let Some(watcher) = self.watchers.iter_mut().find(|w| w.token() == token) {
+            watcher.terminal = terminal.clone();
+        }
+    }
+
     pub fn each_watcher(&self) -> Iter<WatcherLite> {
         self.watchers.iter()
     }
 
-    pub fn socket(&self) -> &TcpStream {
+    /// The name the caster logged in with, if they have yet.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|name| &name[..])
+    }
+
+    pub fn socket(&self) -> &CasterStream {
         &self.sock
     }
 
@@ -136,7 +446,8 @@ impl Caster {
 
     // The very first bytes sent should be in utf-8:
     //   hello <name> <password>
-    fn handle_auth(&mut self, raw_input: &[u8], caster_auth: &mut CasterAuth) -> Result<(usize, String), AuthResults> {
+    // Greeting with "hello-norecord" instead of "hello" opts the caster out of being recorded.
+    fn handle_auth(&mut self, raw_input: &[u8], caster_auth: &mut CasterAuth) -> Result<(usize, String, bool, Login), AuthResults> {
         // Limit the buffer used for the authentication to 1024 bytes. This is to limit a DoS and
         // reduce the possibility of getting into an unknown state.
         let mut auth_buffer = [0; 1024];
@@ -171,9 +482,12 @@ impl Caster {
                 if parts.len() < 2 {
                     return Err(AuthResults::NotEnoughParts);
                 }
-                else if parts[0] != "hello" {
-                    return Err(AuthResults::MissingHello);
-                }
+
+                let record = match parts[0] {
+                    "hello" => true,
+                    "hello-norecord" => false,
+                    _ => return Err(AuthResults::MissingHello),
+                };
 
                 let name = parts[1];
                 // Valid names must have a length and consist of characters/bytes greater than 32.
@@ -189,7 +503,7 @@ impl Caster {
                 let password = if parts.len() >= 3 { parts[2] } else { "" };
                 // Would like to use this but can't get the types to quite work out.
                 //let password = parts.get(2).unwrap_or("");
-                if let Ok(_) = caster_auth.login(&name, &password) {
+                if let Ok(login) = caster_auth.login(&name, &password) {
                     // Determine if there are any remaining bytes in raw_input. Reset the
                     // cast_buffer to contain those bytes.
                     self.cast_buffer.clear();
@@ -202,10 +516,10 @@ impl Caster {
                     else {
                         0
                     };
-                    return Ok((offset, String::from(name)));
+                    return Ok((offset, String::from(name), record, login));
                 }
                 else {
-                    return Err(AuthResults::InvalidLogin);
+                    return Err(AuthResults::InvalidLogin(String::from(name)));
                 }
             }
             else {
@@ -223,14 +537,71 @@ impl Caster {
         }
     }
 
-    fn relay_input(&mut self, input: &[u8]) {
+    /// Send the input on to the watchers, either now or once the delay has passed. Returns the
+    /// watchers that could no longer be written to.
+    fn relay_input(&mut self, input: &[u8], metrics: &mut Metrics) -> Vec<Token> {
+        let mut failed = Vec::new();
+        if input.is_empty() {
+            return failed;
+        }
+        if let Some(ref name) = self.name {
+            metrics.caster_bytes_received(name, input.len());
+        }
+
+        if self.delay.is_some() {
+            self.delayed.push_back((self.last_byte_received, input.to_vec()));
+            self.delayed_size += input.len();
+            if self.delayed_size > MAX_DELAYED {
+                warn!("{} event=delay_overflow queued={}", Conn(self.token, self.sock.peer_addr()),
+                      self.delayed_size);
+                let now = UTC::now();
+                while self.delayed_size > MAX_DELAYED {
+                    failed.extend(self.release_oldest(&now, metrics));
+                }
+            }
+        }
+        else {
+            let received = self.last_byte_received;
+            failed = self.broadcast(&received, input, metrics);
+        }
+        failed
+    }
+
+    fn release_oldest(&mut self, now: &DateTime<UTC>, metrics: &mut Metrics) -> Vec<Token> {
+        match self.delayed.pop_front() {
+            Some((_, input)) => {
+                self.delayed_size -= input.len();
+                self.broadcast(now, &input, metrics)
+            },
+            None => Vec::new(),
+        }
+    }
+
+    // Recorded here rather than as the input arrives so a recording never shows anything before
+    // the watchers could see it.
+    // Watchers that can't be written to are taken off the caster straight away, so they aren't
+    // tried again before they are disconnected.
+    fn broadcast(&mut self, when: &DateTime<UTC>, input: &[u8], metrics: &mut Metrics) -> Vec<Token> {
+        let recorded = self.recorder.as_mut().map(|r| r.record(&input));
+        if let Some(Err(_)) = recorded {
+            // Stop recording rather than keep failing on every write.
+            self.recorder = None;
+        }
         self.cast_buffer.add(&input);
-        for watcher in self.watchers.iter_mut() {
-            let res = watcher.write(&input);
-            // Need to notify the watcher has an error.
-            if res.is_err() {
+        self.history.add(when, &input);
+        let mut failed = Vec::new();
+        for watcher in self.watchers.iter_mut().filter(|w| !w.time_shifted) {
+            if watcher.write(&input).is_err() {
+                metrics.dropped_write();
+                failed.push(watcher.token());
             }
+            else {
+                metrics.watcher_bytes_sent(input.len());
+            }
+            metrics.short_writes(watcher.take_short_writes());
         }
+        self.watchers.retain(|w| !failed.contains(&w.token()));
+        failed
     }
 
     fn send_buffer(&self, watcher: &mut WatcherLite) -> Result<usize, Error> {
@@ -239,7 +610,126 @@ impl Caster {
     }
 }
 
+impl AuthResults {
+    /// How the result is labelled in the metrics.
+    fn name(&self) -> &'static str {
+        match *self {
+            AuthResults::InvalidLogin(_) => "invalid_login",
+            AuthResults::InvalidName => "invalid_name",
+            AuthResults::MissingHello => "missing_hello",
+            AuthResults::NotEnoughParts => "not_enough_parts",
+            AuthResults::TooLong => "too_long",
+            AuthResults::TryAgain => "try_again",
+            AuthResults::Utf8Error => "utf8_error",
+        }
+    }
+}
+
+impl CasterStream {
+    /// Take over a caster's socket, such as one handed over by another server.
+    pub unsafe fn from_raw_fd(fd: RawFd, unix: bool) -> Self {
+        if unix {
+            CasterStream::Unix(UnixStream::from_raw_fd(fd))
+        }
+        else {
+            CasterStream::Tcp(TcpStream::from_raw_fd(fd))
+        }
+    }
+
+    /// None for casters on the Unix socket, who have no address worth showing.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match *self {
+            CasterStream::Tcp(ref sock) => sock.peer_addr().ok(),
+            CasterStream::Unix(_) => None,
+        }
+    }
+
+    fn shutdown(&self) -> Result<(), Error> {
+        match *self {
+            CasterStream::Tcp(ref sock) => sock.shutdown(Shutdown::Both),
+            // The mio Unix stream doesn't offer this.
+            CasterStream::Unix(ref sock) => {
+                if unsafe { libc::shutdown(sock.as_raw_fd(), libc::SHUT_RDWR) } == -1 {
+                    return Err(Error::last_os_error());
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl Read for CasterStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match *self {
+            CasterStream::Tcp(ref mut sock) => sock.read(buf),
+            CasterStream::Unix(ref mut sock) => sock.read(buf),
+        }
+    }
+}
+
+impl Write for CasterStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match *self {
+            CasterStream::Tcp(ref mut sock) => sock.write(buf),
+            CasterStream::Unix(ref mut sock) => sock.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match *self {
+            CasterStream::Tcp(ref mut sock) => sock.flush(),
+            CasterStream::Unix(ref mut sock) => sock.flush(),
+        }
+    }
+}
+
+impl Evented for CasterStream {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet,
+                opts: PollOpt) -> Result<(), Error> {
+        match *self {
+            CasterStream::Tcp(ref sock) => sock.register(selector, token, interest, opts),
+            CasterStream::Unix(ref sock) => sock.register(selector, token, interest, opts),
+        }
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet,
+                  opts: PollOpt) -> Result<(), Error> {
+        match *self {
+            CasterStream::Tcp(ref sock) => sock.reregister(selector, token, interest, opts),
+            CasterStream::Unix(ref sock) => sock.reregister(selector, token, interest, opts),
+        }
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> Result<(), Error> {
+        match *self {
+            CasterStream::Tcp(ref sock) => sock.deregister(selector),
+            CasterStream::Unix(ref sock) => sock.deregister(selector),
+        }
+    }
+}
+
+impl AsRawFd for CasterStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            CasterStream::Tcp(ref sock) => sock.as_raw_fd(),
+            CasterStream::Unix(ref sock) => sock.as_raw_fd(),
+        }
+    }
+}
+
 impl CasterMenuEntry {
+    /// The entry as a JSON object for the status API.
+    pub fn to_json(&self, now: &DateTime<UTC>) -> String {
+        format!(concat!("{{\"name\": {}, \"connected\": {}, \"last_activity\": {}, ",
+                        "\"idle_seconds\": {}, \"watchers\": {}, \"buffer_size\": {}}}"),
+                json_string(&self.name),
+                json_string(&self.connected.format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+                json_string(&self.last_byte_received.format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+                (*now - self.last_byte_received).num_seconds(),
+                self.num_watchers,
+                self.buffer_size)
+    }
+
     pub fn name(&self) -> &String {
         &self.name
     }
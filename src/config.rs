@@ -1,15 +1,133 @@
+use std::collections::HashMap;
 use std::default::Default;
 use std::fs::File;
 use std::io;
 use std::io::Read;
 use std::net;
+use std::path::PathBuf;
 
+use log::LogLevelFilter;
 use toml;
 
 pub struct TermcastConfig {
-    pub caster: net::SocketAddr,
-    pub watcher: net::SocketAddr,
+    /// Addresses casters and watchers connect to. There can be any number of each, such as one
+    /// for IPv4 and one for IPv6, or one per interface.
+    pub caster: Vec<net::SocketAddr>,
+    pub watcher: Vec<net::SocketAddr>,
+    /// Addresses watchers connect to without telnet, see `WatcherProtocol::Raw`.
+    pub raw_watcher: Vec<net::SocketAddr>,
+    /// Unix socket casters on the same machine can connect to instead of using TCP.
+    pub caster_socket: Option<PathBuf>,
+    /// Whether browsers can use the watcher port too, which means waiting a moment on every new
+    /// watcher to see if they send an HTTP request.
+    pub watcher_http: bool,
+    /// File to write the process ID to, and lock, when running in the background.
+    pub pidfile: Option<PathBuf>,
+    /// User and group to switch to once the listeners are bound.
+    pub user: Option<String>,
+    pub group: Option<String>,
+    /// Seconds to wait on shutdown for watchers to be sent everything before exiting anyway.
+    pub shutdown_timeout: u64,
     pub motd: Option<String>,
+    pub record: Option<RecordConfig>,
+    /// Directory of ttyrec files watchers can choose to play back.
+    pub playback: Option<PathBuf>,
+    pub delay: DelayConfig,
+    pub history: HistoryConfig,
+    pub ssh: Option<SshConfig>,
+    /// Address for the web page and WebSocket watchers use from a browser.
+    pub web: Option<net::SocketAddr>,
+    /// Address casters can connect to over WebSocket.
+    pub web_caster: Option<net::SocketAddr>,
+    /// Address for the JSON status API.
+    pub status: Option<net::SocketAddr>,
+    /// Address for the Prometheus metrics endpoint.
+    pub metrics: Option<net::SocketAddr>,
+    /// Path of the Unix socket operators can send admin commands to.
+    pub admin_socket: Option<PathBuf>,
+    pub log: LogConfig,
+    /// File recording logins, registrations and what operators did, one JSON object per line.
+    pub audit_log: Option<PathBuf>,
+    /// File the configuration was read from, so it can be read again on reload.
+    pub config_file: Option<PathBuf>,
+    /// What was wrong with the file and left out, to be logged once the logger is up.
+    pub warnings: Vec<String>,
+}
+
+/// How watchers talk to a listener. Raw watchers get no telnet negotiation or escaping, for
+/// clients like netcat and scripts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatcherProtocol {
+    Telnet,
+    Raw,
+    /// Only used for watchers on the SSH listener.
+    Ssh,
+    /// Only used for watchers on the web listener.
+    WebSocket,
+}
+
+/// The optional SSH listener for watchers.
+#[derive(Clone, Debug)]
+pub struct SshConfig {
+    pub listen: net::SocketAddr,
+    /// Raw libsodium ed25519 secret key. Generated on startup if the file doesn't exist.
+    pub host_key: PathBuf,
+    /// The password watchers have to give. None only when `anonymous` is set, letting anyone in.
+    pub password: Option<String>,
+}
+
+/// Where casts are recorded and how long recordings are kept around. Either limit can be left
+/// unset to keep recordings forever.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordConfig {
+    pub directory: PathBuf,
+    /// Combined size, in bytes, all recordings are allowed to use.
+    pub max_size: Option<u64>,
+    /// Age, in days, after which a recording is removed.
+    pub max_age: Option<u64>,
+    /// Also write an asciicast v2 file next to each ttyrec file.
+    pub asciicast: bool,
+    /// Terminal size given in asciicast headers. Casters don't report theirs.
+    pub width: u16,
+    pub height: u16,
+}
+
+/// How long, in seconds, casts are held back before watchers see them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DelayConfig {
+    pub default: Option<u64>,
+    pub casters: HashMap<String, u64>,
+}
+
+/// How much of each caster's recent output watchers can step back through.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryConfig {
+    /// Bytes kept per caster.
+    pub size: usize,
+    pub minutes: u64,
+}
+
+/// Where log lines go and how much detail they have. Only read at startup.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogConfig {
+    pub target: LogTarget,
+    pub level: LogLevelFilter,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogTarget {
+    Stderr,
+    File(LogFile),
+    /// Syslog, with the facility to log as.
+    Syslog(String),
+}
+
+/// A log file, moved aside once it grows past `max_size` bytes. `keep` old files are kept.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogFile {
+    pub path: PathBuf,
+    pub max_size: Option<u64>,
+    pub keep: usize,
 }
 
 
@@ -18,18 +136,58 @@ pub enum ConfigError {
     Nothing,
     InvalidAddr(net::AddrParseError),
     Io(io::Error),
+    /// The file isn't valid TOML.
+    Parse(String),
 }
 
 const CASTER_LISTEN: &'static str = "127.0.0.1:31337";
 const WATCHER_LISTEN: &'static str = "127.0.0.1:2300";
 const MOTD: Option<String> = None;
+const SHUTDOWN_TIMEOUT: u64 = 5;
+const HISTORY_SIZE: usize = 1_000_000;
+const HISTORY_MINUTES: u64 = 10;
+const LOG_KEEP: usize = 5;
+const LOG_FACILITY: &'static str = "daemon";
 
 impl Default for TermcastConfig {
     fn default() -> Self {
         TermcastConfig {
-            caster: CASTER_LISTEN.parse().unwrap(),
-            watcher: WATCHER_LISTEN.parse().unwrap(),
+            caster: vec![CASTER_LISTEN.parse().unwrap()],
+            watcher: vec![WATCHER_LISTEN.parse().unwrap()],
+            raw_watcher: Vec::new(),
+            caster_socket: None,
+            watcher_http: false,
+            pidfile: None,
+            user: None,
+            group: None,
+            shutdown_timeout: SHUTDOWN_TIMEOUT,
             motd: MOTD,
+            record: None,
+            playback: None,
+            delay: DelayConfig::default(),
+            history: HistoryConfig {
+                size: HISTORY_SIZE,
+                minutes: HISTORY_MINUTES,
+            },
+            ssh: None,
+            web: None,
+            web_caster: None,
+            status: None,
+            metrics: None,
+            admin_socket: None,
+            log: LogConfig::default(),
+            audit_log: None,
+            config_file: None,
+            warnings: Vec::new(),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            target: LogTarget::Stderr,
+            level: LogLevelFilter::Info,
         }
     }
 }
@@ -63,43 +221,357 @@ fn get_option(toml_value: &toml::Value, option_name: &str) -> Option<String> {
     }
 }
 
+/// A list of strings, which can also be given as a single string.
+fn get_list_option(toml_value: &toml::Value, option_name: &str) -> Option<Vec<String>> {
+    match toml_value {
+        &toml::Value::Table(ref table) => {
+            if let Some(option_value) = table.get(option_name) {
+                get_list_option(option_value, "")
+            }
+            else {
+                None
+            }
+        },
+        &toml::Value::String(ref string) => {
+            Some(vec![string.clone()])
+        },
+        &toml::Value::Array(ref array) => {
+            array.iter().map(|value| get_option(value, "")).collect()
+        },
+        _ => None,
+    }
+}
+
+fn get_integer_option(toml_value: &toml::Value, option_name: &str) -> Option<i64> {
+    match toml_value {
+        &toml::Value::Table(ref table) => {
+            if let Some(option_value) = table.get(option_name) {
+                get_integer_option(option_value, "")
+            }
+            else {
+                None
+            }
+        },
+        &toml::Value::Integer(integer) => {
+            Some(integer)
+        },
+        _ => None,
+    }
+}
+
+fn get_bool_option(toml_value: &toml::Value, option_name: &str) -> Option<bool> {
+    match toml_value {
+        &toml::Value::Table(ref table) => {
+            if let Some(option_value) = table.get(option_name) {
+                get_bool_option(option_value, "")
+            }
+            else {
+                None
+            }
+        },
+        &toml::Value::Boolean(boolean) => {
+            Some(boolean)
+        },
+        _ => None,
+    }
+}
+
 fn parse_socketaddr(addr: String) -> Result<net::SocketAddr, ConfigError> {
     addr.parse().map_err(ConfigError::InvalidAddr)
 }
 
+fn parse_socketaddrs(addrs: Vec<String>) -> Result<Vec<net::SocketAddr>, ConfigError> {
+    addrs.into_iter().map(parse_socketaddr).collect()
+}
+
+fn non_negative(value: i64) -> Option<u64> {
+    if value >= 0 { Some(value as u64) } else { None }
+}
+
+impl DelayConfig {
+    /// The delay for the named caster, if any.
+    pub fn for_caster(&self, name: &str) -> Option<u64> {
+        self.casters.get(name).cloned().or(self.default)
+                    .and_then(|delay| if delay > 0 { Some(delay) } else { None })
+    }
+}
+
 impl TermcastConfig {
     pub fn from_config(config_file_path: &str) -> Result<Self, ConfigError> {
         let mut config = TermcastConfig::default();
+        config.config_file = Some(PathBuf::from(config_file_path));
 
         let mut config_file = try!(File::open(&config_file_path));
         let mut contents = String::new();
         try!(config_file.read_to_string(&mut contents));
         let mut parser = toml::Parser::new(&contents);
-        let options = parser.parse().unwrap();
+        let options = match parser.parse() {
+            Some(options) => options,
+            None => {
+                let errors: Vec<String> = parser.errors.iter().map(|e| e.desc.clone()).collect();
+                return Err(ConfigError::Parse(errors.join(", ")));
+            },
+        };
         if let Some(server_config) = options.get("server") {
-            let c = get_option(&server_config, "caster_listen")
+            let c = get_list_option(&server_config, "caster_listen")
+                        .ok_or(ConfigError::Nothing)
+                        .and_then(parse_socketaddrs);
+            match c {
+                Ok(addrs) => { config.caster = addrs }
+                Err(ConfigError::InvalidAddr(e)) => {
+                    config.warnings.push(format!("Invalid caster listen address: {}.", e));
+                }
+                Err(_) => { }
+            }
+
+            let c = get_list_option(&server_config, "watcher_listen")
+                        .ok_or(ConfigError::Nothing)
+                        .and_then(parse_socketaddrs);
+            match c {
+                Ok(addrs) => { config.watcher = addrs }
+                Err(ConfigError::InvalidAddr(e)) => {
+                    config.warnings.push(format!("Invalid watcher listen address: {}.", e));
+                }
+                Err(_) => { }
+            }
+
+            let c = get_list_option(&server_config, "raw_watcher_listen")
+                        .ok_or(ConfigError::Nothing)
+                        .and_then(parse_socketaddrs);
+            match c {
+                Ok(addrs) => { config.raw_watcher = addrs }
+                Err(ConfigError::InvalidAddr(e)) => {
+                    config.warnings.push(format!("Invalid raw watcher listen address: {}.", e));
+                }
+                Err(_) => { }
+            }
+            let raw_watcher = &config.raw_watcher;
+            let warnings = &mut config.warnings;
+            config.watcher.retain(|addr| {
+                if raw_watcher.contains(addr) {
+                    warnings.push(format!("Watchers on {} are raw, as it is also a raw watcher listen address.", addr));
+                    return false;
+                }
+                true
+            });
+
+            config.caster_socket = get_option(&server_config, "caster_socket").map(PathBuf::from);
+            config.watcher_http = get_bool_option(&server_config, "watcher_http").unwrap_or(false);
+
+            config.motd = get_option(&server_config, "motd");
+            config.pidfile = get_option(&server_config, "pidfile").map(PathBuf::from);
+            config.user = get_option(&server_config, "user");
+            config.group = get_option(&server_config, "group");
+
+            if let Some(timeout) = get_integer_option(&server_config, "shutdown_timeout") {
+                match non_negative(timeout) {
+                    Some(timeout) => { config.shutdown_timeout = timeout },
+                    None => {
+                        config.warnings.push(format!("Invalid shutdown timeout: {}.", timeout));
+                    },
+                }
+            }
+        }
+
+        if let Some(record_config) = options.get("record") {
+            if let Some(directory) = get_option(&record_config, "directory") {
+                let max_size = get_integer_option(&record_config, "max_size")
+                                   .and_then(non_negative);
+                let max_age = get_integer_option(&record_config, "max_age")
+                                  .and_then(non_negative);
+                let asciicast = get_bool_option(&record_config, "asciicast")
+                                    .unwrap_or(false);
+                let mut size = [80, 24];
+                for (option, value) in ["width", "height"].iter().zip(size.iter_mut()) {
+                    if let Some(integer) = get_integer_option(&record_config, option) {
+                        if integer > 0 && integer <= u16::max_value() as i64 {
+                            *value = integer as u16;
+                        }
+                        else {
+                            config.warnings.push(format!("Invalid recording {}: {}.", option, integer));
+                        }
+                    }
+                }
+                config.record = Some(RecordConfig {
+                    directory: PathBuf::from(directory),
+                    max_size: max_size,
+                    max_age: max_age,
+                    asciicast: asciicast,
+                    width: size[0],
+                    height: size[1],
+                });
+            }
+            else {
+                config.warnings.push(String::from("Recording disabled: no directory given."));
+            }
+        }
+
+        if let Some(playback_config) = options.get("playback") {
+            config.playback = get_option(&playback_config, "directory").map(PathBuf::from);
+        }
+
+        if let Some(delay_config) = options.get("delay") {
+            config.delay.default = get_integer_option(&delay_config, "default")
+                                       .and_then(non_negative);
+            if let Some(&toml::Value::Table(ref casters)) = delay_config.lookup("casters") {
+                for (name, delay) in casters {
+                    match get_integer_option(delay, "").and_then(non_negative) {
+                        Some(delay) => { config.delay.casters.insert(name.clone(), delay); },
+                        None => {
+                            config.warnings.push(format!("Invalid delay for caster {}.", name));
+                        },
+                    }
+                }
+            }
+        }
+
+        if let Some(history_config) = options.get("history") {
+            if let Some(size) = get_integer_option(&history_config, "size") {
+                match non_negative(size) {
+                    Some(size) => { config.history.size = size as usize },
+                    None => { config.warnings.push(format!("Invalid history size: {}.", size)) },
+                }
+            }
+            if let Some(minutes) = get_integer_option(&history_config, "minutes") {
+                match non_negative(minutes) {
+                    Some(minutes) => { config.history.minutes = minutes },
+                    None => { config.warnings.push(format!("Invalid history minutes: {}.", minutes)) },
+                }
+            }
+        }
+
+        if let Some(ssh_config) = options.get("ssh") {
+            let listen = get_option(&ssh_config, "listen")
+                             .ok_or(ConfigError::Nothing)
+                             .and_then(parse_socketaddr);
+            let host_key = get_option(&ssh_config, "host_key");
+            let password = get_option(&ssh_config, "password");
+            let anonymous = get_bool_option(&ssh_config, "anonymous").unwrap_or(false);
+            match (listen, host_key) {
+                (Ok(addr), Some(host_key)) if anonymous || password.is_some() => {
+                    config.ssh = Some(SshConfig {
+                        listen: addr,
+                        host_key: PathBuf::from(host_key),
+                        password: if anonymous { None } else { password },
+                    });
+                },
+                (Ok(_), Some(_)) => {
+                    config.warnings.push(String::from("SSH disabled: set a password, or \
+                                                       anonymous = true to let anyone watch."));
+                },
+                (Err(ConfigError::InvalidAddr(e)), _) => {
+                    config.warnings.push(format!("Invalid SSH listen address: {}.", e));
+                },
+                _ => {
+                    config.warnings.push(String::from("SSH disabled: listen and host_key are both \
+                                                       needed."));
+                },
+            }
+        }
+
+        if let Some(web_config) = options.get("web") {
+            let c = get_option(&web_config, "listen")
+                        .ok_or(ConfigError::Nothing)
+                        .and_then(parse_socketaddr);
+            match c {
+                Ok(addr) => { config.web = Some(addr) }
+                Err(ConfigError::InvalidAddr(e)) => {
+                    config.warnings.push(format!("Invalid web listen address: {}.", e));
+                }
+                Err(_) => { }
+            }
+
+            let c = get_option(&web_config, "caster_listen")
+                        .ok_or(ConfigError::Nothing)
+                        .and_then(parse_socketaddr);
+            match c {
+                Ok(addr) => { config.web_caster = Some(addr) }
+                Err(ConfigError::InvalidAddr(e)) => {
+                    config.warnings.push(format!("Invalid web caster listen address: {}.", e));
+                }
+                Err(_) => { }
+            }
+        }
+
+        if let Some(status_config) = options.get("status") {
+            let c = get_option(&status_config, "listen")
                         .ok_or(ConfigError::Nothing)
                         .and_then(parse_socketaddr);
             match c {
-                Ok(addr) => { config.caster = addr }
+                Ok(addr) => { config.status = Some(addr) }
                 Err(ConfigError::InvalidAddr(e)) => {
-                    println!("Invalid caster listen address: {}.", e);
+                    config.warnings.push(format!("Invalid status listen address: {}.", e));
                 }
                 Err(_) => { }
             }
+        }
 
-            let c = get_option(&server_config, "watcher_listen")
+        if let Some(metrics_config) = options.get("metrics") {
+            let c = get_option(&metrics_config, "listen")
                         .ok_or(ConfigError::Nothing)
                         .and_then(parse_socketaddr);
             match c {
-                Ok(addr) => { config.watcher = addr }
+                Ok(addr) => { config.metrics = Some(addr) }
                 Err(ConfigError::InvalidAddr(e)) => {
-                    println!("Invalid watcher listen address: {}.", e);
+                    config.warnings.push(format!("Invalid metrics listen address: {}.", e));
                 }
                 Err(_) => { }
             }
         }
 
+        if let Some(admin_config) = options.get("admin") {
+            config.admin_socket = get_option(&admin_config, "socket").map(PathBuf::from);
+        }
+
+        if let Some(audit_config) = options.get("audit") {
+            config.audit_log = get_option(&audit_config, "file").map(PathBuf::from);
+        }
+
+        if let Some(log_config) = options.get("log") {
+            if let Some(level) = get_option(&log_config, "level") {
+                match level.parse() {
+                    Ok(level) => { config.log.level = level },
+                    Err(_) => { config.warnings.push(format!("Invalid log level: {}.", level)); },
+                }
+            }
+
+            match get_option(&log_config, "target") {
+                Some(ref target) if target == "stderr" => {
+                    config.log.target = LogTarget::Stderr;
+                },
+                Some(ref target) if target == "file" => {
+                    match get_option(&log_config, "file") {
+                        Some(path) => {
+                            let max_size = get_integer_option(&log_config, "max_size")
+                                               .and_then(non_negative);
+                            let keep = get_integer_option(&log_config, "keep")
+                                           .and_then(non_negative)
+                                           .map(|keep| keep as usize)
+                                           .unwrap_or(LOG_KEEP);
+                            config.log.target = LogTarget::File(LogFile {
+                                path: PathBuf::from(path),
+                                max_size: max_size,
+                                keep: keep,
+                            });
+                        },
+                        None => {
+                            config.warnings.push(String::from("Logging to stderr: no log file \
+                                                               given."));
+                        },
+                    }
+                },
+                Some(ref target) if target == "syslog" => {
+                    let facility = get_option(&log_config, "facility")
+                                       .unwrap_or(String::from(LOG_FACILITY));
+                    config.log.target = LogTarget::Syslog(facility);
+                },
+                Some(target) => {
+                    config.warnings.push(format!("Invalid log target: {}.", target));
+                },
+                None => { },
+            }
+        }
+
         return Ok(config);
     }
 }
@@ -0,0 +1,233 @@
+// Running in the background as a well-behaved Unix daemon: detaching from the terminal, keeping a
+// pidfile, giving up root once the listeners are bound and replacing itself with a new binary.
+
+use libc;
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::ptr;
+
+// Not in the libc crate yet.
+#[allow(dead_code)]
+#[repr(C)]
+struct group {
+    gr_name: *mut libc::c_char,
+    gr_passwd: *mut libc::c_char,
+    gr_gid: libc::gid_t,
+    gr_mem: *mut *mut libc::c_char,
+}
+
+extern "C" {
+    fn getpwnam(name: *const libc::c_char) -> *mut libc::passwd;
+    fn getgrnam(name: *const libc::c_char) -> *mut group;
+    fn initgroups(user: *const libc::c_char, group: libc::gid_t) -> libc::c_int;
+}
+
+
+/// A file holding the server's process ID. It stays locked while the server runs so a second
+/// server can't start with the same pidfile, and is removed when dropped. The lock carries over
+/// to forked children.
+pub struct Pidfile {
+    // Closing the file would release the lock.
+    file: File,
+    path: PathBuf,
+}
+
+impl Pidfile {
+    pub fn create(path: &Path) -> Result<Self, Error> {
+        let mut file = try!(OpenOptions::new().write(true).create(true).open(path));
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+            let err = Error::last_os_error();
+            if err.kind() == ErrorKind::WouldBlock {
+                return Err(Error::new(ErrorKind::AlreadyExists,
+                                      format!("{} is locked by a running server", path.display())));
+            }
+            return Err(err);
+        }
+
+        let mut pidfile = Pidfile {
+            file: file,
+            path: PathBuf::from(path),
+        };
+        try!(pidfile.write_pid());
+        Ok(pidfile)
+    }
+
+    /// Carry on with a pidfile, lock and all, left open by the binary this one replaced.
+    pub fn inherit(fd: RawFd, path: &Path) -> Self {
+        Pidfile {
+            file: unsafe { File::from_raw_fd(fd) },
+            path: PathBuf::from(path),
+        }
+    }
+
+    /// Replace the process ID in the file with this process's, such as after forking.
+    pub fn write_pid(&mut self) -> Result<(), Error> {
+        // Only ever emptied with the lock held so a running server's pid is never lost.
+        try!(self.file.set_len(0));
+        write!(self.file, "{}\n", unsafe { libc::getpid() })
+    }
+}
+
+impl AsRawFd for Pidfile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        // Fails if privileges were dropped and the directory belongs to root, which leaves a
+        // stale but unlocked file the next server will happily take over.
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Detach from the terminal: fork, start a new session and fork again so a controlling terminal
+/// can never be picked up, then point stdin, stdout and stderr at /dev/null. Only the final child
+/// returns. The working directory is kept as paths in the configuration may be relative to it.
+pub fn daemonize() -> Result<(), Error> {
+    try!(fork_and_exit_parent());
+    if unsafe { libc::setsid() } == -1 {
+        return Err(Error::last_os_error());
+    }
+    try!(fork_and_exit_parent());
+
+    let dev_null = try!(OpenOptions::new().read(true).write(true).open("/dev/null"));
+    for fd in 0..3 {
+        if unsafe { libc::dup2(dev_null.as_raw_fd(), fd) } == -1 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Run as `user` and `group` from now on. The group defaults to the user's own, and
+/// supplementary groups are reset to the user's. Does nothing if neither is given.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<(), Error> {
+    let user = match user {
+        Some(name) => {
+            let c_name = try!(c_string(name));
+            let passwd = unsafe { getpwnam(c_name.as_ptr()) };
+            if passwd.is_null() {
+                return Err(Error::new(ErrorKind::NotFound, format!("no user named {}", name)));
+            }
+            Some(unsafe { (c_name, (*passwd).pw_uid, (*passwd).pw_gid) })
+        },
+        None => None,
+    };
+    let gid = match group {
+        Some(name) => {
+            let c_name = try!(c_string(name));
+            let group = unsafe { getgrnam(c_name.as_ptr()) };
+            if group.is_null() {
+                return Err(Error::new(ErrorKind::NotFound, format!("no group named {}", name)));
+            }
+            Some(unsafe { (*group).gr_gid })
+        },
+        None => user.as_ref().map(|&(_, _, gid)| gid),
+    };
+
+    // Groups have to go first; once the user has changed there's no permission to change them.
+    if let Some(gid) = gid {
+        let res = match user {
+            Some((ref c_name, _, _)) => unsafe { initgroups(c_name.as_ptr(), gid) },
+            None => unsafe { libc::setgroups(0, ptr::null()) },
+        };
+        if res == -1 || unsafe { libc::setgid(gid) } == -1 {
+            return Err(Error::last_os_error());
+        }
+    }
+    if let Some((_, uid, _)) = user {
+        if unsafe { libc::setuid(uid) } == -1 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Replace this process with a fresh start of the binary, run the same way with the same
+/// arguments so an upgraded binary is picked up. Each of `fds` is left open for it, with its number
+/// in the named environment variable. Only returns if the exec failed.
+pub fn reexec(fds: &[(&str, RawFd)]) -> Error {
+    let mut args = env::args_os();
+    let program = match args.next() {
+        Some(program) => program,
+        None => return Error::new(ErrorKind::NotFound, "no program name to run again"),
+    };
+    for &(var, fd) in fds {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, 0) } == -1 {
+            return Error::last_os_error();
+        }
+        env::set_var(var, fd.to_string());
+    }
+
+    let err = Command::new(program).args(&args.collect::<Vec<_>>()).exec();
+    for &(var, fd) in fds {
+        env::remove_var(var);
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    err
+}
+
+/// A descriptor left open by the binary this one replaced, named by the environment variable
+/// `var`. The variable is cleared so it isn't passed on any further.
+pub fn inherited_fd(var: &str) -> Option<RawFd> {
+    let fd = env::var(var).ok().and_then(|fd| fd.parse().ok());
+    env::remove_var(var);
+    if let Some(fd) = fd {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    fd
+}
+
+fn fork_and_exit_parent() -> Result<(), Error> {
+    match unsafe { libc::fork() } {
+        -1 => Err(Error::last_os_error()),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+fn c_string(name: &str) -> Result<CString, Error> {
+    CString::new(name).map_err(|_| Error::new(ErrorKind::InvalidInput, "name contains a NUL byte"))
+}
+
+#[cfg(test)]
+mod tests {
+    use libc;
+    use std::env;
+    use std::fs::File;
+    use std::io::{ErrorKind, Read};
+    use super::Pidfile;
+
+    #[test]
+    fn pidfile_lock() {
+        let path = env::temp_dir().join(format!("termcastd-test-{}.pid", unsafe { libc::getpid() }));
+        let pidfile = Pidfile::create(&path).unwrap();
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, format!("{}\n", unsafe { libc::getpid() }));
+
+        // A lock held through another open file counts, even from the same process.
+        match Pidfile::create(&path) {
+            Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {},
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("a locked pidfile was taken"),
+        }
+        // Failing to take it must leave the running server's pid alone.
+        contents.clear();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, format!("{}\n", unsafe { libc::getpid() }));
+
+        drop(pidfile);
+        assert!(!path.exists());
+        drop(Pidfile::create(&path).unwrap());
+    }
+}
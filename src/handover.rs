@@ -0,0 +1,542 @@
+// Handing a running server over to a newly exec'd one, so it can be upgraded without dropping
+// anyone. The old server forks: the parent execs the new binary, keeping the process ID, while
+// the child sends it the listeners and every connection that can be carried on over a Unix
+// socket, then exits.
+//
+// The sockets go first, one per message as SCM_RIGHTS ancillary data. Then comes the state, which
+// refers to them by the order they were sent in.
+
+use chrono::{DateTime, TimeZone, Timelike, UTC};
+use libc;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+
+use config::WatcherProtocol;
+use watcher::TerminalInfo;
+
+const MAGIC: &'static [u8] = b"termcastd handover 3\n";
+// The single data byte sent with each socket, and the one that starts the state.
+const SOCKET: u8 = b'F';
+const STATE: u8 = b'S';
+
+// Not in the libc crate yet.
+const SCM_RIGHTS: libc::c_int = 1;
+const MSG_CMSG_CLOEXEC: libc::c_int = 0x40000000;
+
+#[repr(C)]
+struct cmsghdr {
+    cmsg_len: libc::size_t,
+    cmsg_level: libc::c_int,
+    cmsg_type: libc::c_int,
+}
+
+// A control message carrying one descriptor, padded out the way CMSG_SPACE would.
+#[repr(C)]
+struct FdMessage {
+    header: cmsghdr,
+    fd: libc::c_int,
+    _pad: libc::c_int,
+}
+
+
+/// Everything the new server needs to carry on where the old one left off.
+pub struct Handover {
+    /// Named the same way as inherited listeners, see `InheritedListeners`.
+    pub listeners: Vec<(String, RawFd)>,
+    pub casters: Vec<SavedCaster>,
+    pub watchers: Vec<SavedWatcher>,
+    /// Connections on the watcher port not yet known to be browsers or watchers, with what they
+    /// have sent so far.
+    pub unknown: Vec<(usize, RawFd, WatcherProtocol, Vec<u8>)>,
+    pub next_token_id: usize,
+    /// The forked child doing the sending, for the new server to wait on.
+    pub sender: libc::pid_t,
+}
+
+pub struct SavedCaster {
+    pub token: usize,
+    pub fd: RawFd,
+    /// Connected over the caster Unix socket rather than TCP.
+    pub unix: bool,
+    /// None while still logging in, in which case `cast_buffer` holds what was sent so far.
+    pub name: Option<String>,
+    pub connected: DateTime<UTC>,
+    pub last_byte_received: DateTime<UTC>,
+    pub recording: bool,
+    pub cast_buffer: Vec<u8>,
+    pub history: Vec<(DateTime<UTC>, Vec<u8>)>,
+    /// Input still being held back by the caster's delay.
+    pub delayed: Vec<(DateTime<UTC>, Vec<u8>)>,
+}
+
+/// Only telnet and raw watchers without compression can be handed over. SSH encryption,
+/// WebSocket framing and zlib streams all have state that can't be picked up from the outside.
+pub struct SavedWatcher {
+    pub token: usize,
+    pub fd: RawFd,
+    pub protocol: WatcherProtocol,
+    pub state: SavedState,
+    pub terminal: TerminalInfo,
+    /// Telnet options agreed on each side.
+    pub telnet_local: Vec<u8>,
+    pub telnet_remote: Vec<u8>,
+    /// Output the socket hadn't taken yet.
+    pub backlog: Vec<u8>,
+}
+
+/// What a watcher was doing. Anything else, such as playing back a recording, puts them back at
+/// the main menu.
+#[derive(Debug, PartialEq)]
+pub enum SavedState {
+    AwaitingName,
+    MainMenu,
+    TimeShifted(usize, DateTime<UTC>),
+    Watching(usize),
+}
+
+struct Encoder {
+    buf: Vec<u8>,
+    fds: Vec<RawFd>,
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+    fds: &'a [RawFd],
+}
+
+
+impl Handover {
+    pub fn new(next_token_id: usize) -> Self {
+        Handover {
+            listeners: Vec::new(),
+            casters: Vec::new(),
+            watchers: Vec::new(),
+            unknown: Vec::new(),
+            next_token_id: next_token_id,
+            sender: 0,
+        }
+    }
+
+    pub fn send(&self, sock: &mut UnixStream) -> Result<(), Error> {
+        let encoder = self.encode();
+        for fd in &encoder.fds {
+            try!(send_fd(sock, *fd));
+        }
+        try!(sock.write_all(&[STATE]));
+        try!(sock.write_all(&encoder.buf));
+        sock.flush()
+    }
+
+    /// Read a handover until the sender hangs up. The sockets received are closed again if the
+    /// state can't be made sense of.
+    pub fn receive(sock: &mut UnixStream) -> Result<Self, Error> {
+        let mut fds = Vec::new();
+        loop {
+            match receive_fd(sock) {
+                Ok(Some(fd)) => fds.push(fd),
+                Ok(None) => break,
+                Err(e) => {
+                    close_all(&fds);
+                    return Err(e);
+                },
+            }
+        }
+
+        let mut buf = Vec::new();
+        let handover = sock.read_to_end(&mut buf).and_then(|_| {
+            Handover::decode(&mut Decoder { buf: &buf, fds: &fds })
+        });
+        if handover.is_err() {
+            close_all(&fds);
+        }
+        handover
+    }
+
+    fn encode(&self) -> Encoder {
+        let mut out = Encoder { buf: Vec::new(), fds: Vec::new() };
+        out.buf.extend_from_slice(MAGIC);
+        out.u64(self.next_token_id as u64);
+        out.u64(self.sender as u64);
+
+        out.u64(self.listeners.len() as u64);
+        for &(ref name, fd) in &self.listeners {
+            out.bytes(name.as_bytes());
+            out.fd(fd);
+        }
+
+        out.u64(self.casters.len() as u64);
+        for caster in &self.casters {
+            out.u64(caster.token as u64);
+            out.fd(caster.fd);
+            out.u64(caster.unix as u64);
+            match caster.name {
+                Some(ref name) => {
+                    out.u64(1);
+                    out.bytes(name.as_bytes());
+                },
+                None => out.u64(0),
+            }
+            out.time(&caster.connected);
+            out.time(&caster.last_byte_received);
+            out.u64(caster.recording as u64);
+            out.bytes(&caster.cast_buffer);
+            out.chunks(&caster.history);
+            out.chunks(&caster.delayed);
+        }
+
+        out.u64(self.watchers.len() as u64);
+        for watcher in &self.watchers {
+            out.u64(watcher.token as u64);
+            out.fd(watcher.fd);
+            out.u64(protocol_id(watcher.protocol));
+            match watcher.state {
+                SavedState::AwaitingName => out.u64(0),
+                SavedState::MainMenu => out.u64(1),
+                SavedState::TimeShifted(caster, ref position) => {
+                    out.u64(2);
+                    out.u64(caster as u64);
+                    out.time(position);
+                },
+                SavedState::Watching(caster) => {
+                    out.u64(3);
+                    out.u64(caster as u64);
+                },
+            }
+            out.u64(watcher.terminal.width as u64);
+            out.u64(watcher.terminal.height as u64);
+            out.bytes(watcher.terminal.terminal_type.as_ref().map(|t| t.as_bytes()).unwrap_or(b""));
+            out.bytes(&watcher.telnet_local);
+            out.bytes(&watcher.telnet_remote);
+            out.bytes(&watcher.backlog);
+        }
+
+        out.u64(self.unknown.len() as u64);
+        for &(token, fd, protocol, ref input) in &self.unknown {
+            out.u64(token as u64);
+            out.fd(fd);
+            out.u64(protocol_id(protocol));
+            out.bytes(input);
+        }
+        out
+    }
+
+    fn decode(input: &mut Decoder) -> Result<Self, Error> {
+        if try!(input.take(MAGIC.len())) != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not a handover from a compatible server"));
+        }
+        let mut handover = Handover::new(try!(input.u64()) as usize);
+        handover.sender = try!(input.u64()) as libc::pid_t;
+
+        for _ in 0..try!(input.u64()) {
+            let name = try!(input.string());
+            handover.listeners.push((name, try!(input.fd())));
+        }
+
+        for _ in 0..try!(input.u64()) {
+            let token = try!(input.u64()) as usize;
+            let fd = try!(input.fd());
+            let unix = try!(input.u64()) != 0;
+            let name = match try!(input.u64()) {
+                0 => None,
+                _ => Some(try!(input.string())),
+            };
+            handover.casters.push(SavedCaster {
+                token: token,
+                fd: fd,
+                unix: unix,
+                name: name,
+                connected: try!(input.time()),
+                last_byte_received: try!(input.time()),
+                recording: try!(input.u64()) != 0,
+                cast_buffer: try!(input.bytes()).to_vec(),
+                history: try!(input.chunks()),
+                delayed: try!(input.chunks()),
+            });
+        }
+
+        for _ in 0..try!(input.u64()) {
+            let token = try!(input.u64()) as usize;
+            let fd = try!(input.fd());
+            let protocol = protocol_from_id(try!(input.u64()));
+            let state = match try!(input.u64()) {
+                0 => SavedState::AwaitingName,
+                2 => {
+                    let caster = try!(input.u64()) as usize;
+                    SavedState::TimeShifted(caster, try!(input.time()))
+                },
+                3 => SavedState::Watching(try!(input.u64()) as usize),
+                _ => SavedState::MainMenu,
+            };
+            let width = try!(input.u64()) as u16;
+            let height = try!(input.u64()) as u16;
+            let terminal_type = try!(input.string());
+            handover.watchers.push(SavedWatcher {
+                token: token,
+                fd: fd,
+                protocol: protocol,
+                state: state,
+                terminal: TerminalInfo {
+                    width: width,
+                    height: height,
+                    terminal_type: if terminal_type.is_empty() { None } else { Some(terminal_type) },
+                },
+                telnet_local: try!(input.bytes()).to_vec(),
+                telnet_remote: try!(input.bytes()).to_vec(),
+                backlog: try!(input.bytes()).to_vec(),
+            });
+        }
+
+        for _ in 0..try!(input.u64()) {
+            let token = try!(input.u64()) as usize;
+            let fd = try!(input.fd());
+            let protocol = protocol_from_id(try!(input.u64()));
+            handover.unknown.push((token, fd, protocol, try!(input.bytes()).to_vec()));
+        }
+        Ok(handover)
+    }
+}
+
+impl Encoder {
+    fn u64(&mut self, value: u64) {
+        for shift in 0..8 {
+            self.buf.push((value >> (shift * 8)) as u8);
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u64(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn time(&mut self, time: &DateTime<UTC>) {
+        self.u64(time.timestamp() as u64);
+        self.u64(time.nanosecond() as u64);
+    }
+
+    fn chunks(&mut self, chunks: &[(DateTime<UTC>, Vec<u8>)]) {
+        self.u64(chunks.len() as u64);
+        for &(ref when, ref chunk) in chunks {
+            self.time(when);
+            self.bytes(chunk);
+        }
+    }
+
+    fn fd(&mut self, fd: RawFd) {
+        let idx = self.fds.len();
+        self.fds.push(fd);
+        self.u64(idx as u64);
+    }
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.buf.len() {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "handover state cut short"));
+        }
+        let (taken, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        let bytes = try!(self.take(8));
+        Ok(bytes.iter().rev().fold(0, |value, byte| value << 8 | *byte as u64))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = try!(self.u64()) as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let bytes = try!(self.bytes());
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "handover state has invalid UTF-8"))
+    }
+
+    fn time(&mut self) -> Result<DateTime<UTC>, Error> {
+        let seconds = try!(self.u64()) as i64;
+        let nanoseconds = try!(self.u64()) as u32;
+        Ok(UTC.timestamp(seconds, nanoseconds))
+    }
+
+    fn chunks(&mut self) -> Result<Vec<(DateTime<UTC>, Vec<u8>)>, Error> {
+        let mut chunks = Vec::new();
+        for _ in 0..try!(self.u64()) {
+            let when = try!(self.time());
+            chunks.push((when, try!(self.bytes()).to_vec()));
+        }
+        Ok(chunks)
+    }
+
+    fn fd(&mut self) -> Result<RawFd, Error> {
+        let idx = try!(self.u64()) as usize;
+        self.fds.get(idx).cloned()
+            .ok_or(Error::new(ErrorKind::InvalidData, "handover state refers to a missing socket"))
+    }
+}
+
+/// A connected pair of Unix sockets: the first is for the new server and is left open across
+/// exec, the second is for sending the handover.
+pub fn socket_pair() -> Result<(UnixStream, UnixStream), Error> {
+    let (receiver, sender) = try!(UnixStream::pair());
+    if unsafe { libc::fcntl(receiver.as_raw_fd(), libc::F_SETFD, 0) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok((receiver, sender))
+}
+
+fn send_fd(sock: &UnixStream, fd: RawFd) -> Result<(), Error> {
+    let mut byte = SOCKET;
+    let mut iov = libc::iovec {
+        iov_base: &mut byte as *mut u8 as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let mut control = FdMessage {
+        header: cmsghdr {
+            cmsg_len: (mem::size_of::<cmsghdr>() + mem::size_of::<libc::c_int>()) as libc::size_t,
+            cmsg_level: libc::SOL_SOCKET,
+            cmsg_type: SCM_RIGHTS,
+        },
+        fd: fd,
+        _pad: 0,
+    };
+    let message = libc::msghdr {
+        msg_name: ptr::null_mut(),
+        msg_namelen: 0,
+        msg_iov: &mut iov,
+        msg_iovlen: 1,
+        msg_control: &mut control as *mut FdMessage as *mut libc::c_void,
+        msg_controllen: mem::size_of::<FdMessage>() as libc::size_t,
+        msg_flags: 0,
+    };
+    if unsafe { libc::sendmsg(sock.as_raw_fd(), &message, 0) } != 1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The next socket sent, or None once the state is about to start.
+fn receive_fd(sock: &UnixStream) -> Result<Option<RawFd>, Error> {
+    let mut byte = 0u8;
+    let mut iov = libc::iovec {
+        iov_base: &mut byte as *mut u8 as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let mut control: FdMessage = unsafe { mem::zeroed() };
+    let mut message = libc::msghdr {
+        msg_name: ptr::null_mut(),
+        msg_namelen: 0,
+        msg_iov: &mut iov,
+        msg_iovlen: 1,
+        msg_control: &mut control as *mut FdMessage as *mut libc::c_void,
+        msg_controllen: mem::size_of::<FdMessage>() as libc::size_t,
+        msg_flags: 0,
+    };
+    // Like every other socket, not to be passed on to anything exec'd later.
+    match unsafe { libc::recvmsg(sock.as_raw_fd(), &mut message, MSG_CMSG_CLOEXEC) } {
+        -1 => return Err(Error::last_os_error()),
+        0 => return Err(Error::new(ErrorKind::UnexpectedEof, "the old server hung up")),
+        _ => {},
+    }
+
+    match byte {
+        STATE => Ok(None),
+        SOCKET if message.msg_controllen as usize >= mem::size_of::<cmsghdr>() &&
+                  control.header.cmsg_level == libc::SOL_SOCKET &&
+                  control.header.cmsg_type == SCM_RIGHTS => Ok(Some(control.fd)),
+        _ => Err(Error::new(ErrorKind::InvalidData, "expected a socket from the old server")),
+    }
+}
+
+// Only telnet and raw watchers are ever handed over.
+fn protocol_id(protocol: WatcherProtocol) -> u64 {
+    match protocol {
+        WatcherProtocol::Raw => 1,
+        _ => 0,
+    }
+}
+
+fn protocol_from_id(id: u64) -> WatcherProtocol {
+    match id {
+        1 => WatcherProtocol::Raw,
+        _ => WatcherProtocol::Telnet,
+    }
+}
+
+fn close_all(fds: &[RawFd]) {
+    for fd in fds {
+        unsafe { libc::close(*fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, UTC};
+    use config::WatcherProtocol;
+    use watcher::TerminalInfo;
+    use super::{Decoder, Handover, SavedCaster, SavedState, SavedWatcher};
+
+    #[test]
+    fn round_trip() {
+        let start = UTC.timestamp(1000, 500);
+        let mut handover = Handover::new(42);
+        handover.sender = 1234;
+        handover.listeners.push((String::from("caster"), 3));
+        handover.casters.push(SavedCaster {
+            token: 9,
+            fd: 5,
+            unix: true,
+            name: Some(String::from("caster1")),
+            connected: start,
+            last_byte_received: start,
+            recording: true,
+            cast_buffer: b"screen".to_vec(),
+            history: vec![(start, b"scr".to_vec()), (start, b"een".to_vec())],
+            delayed: Vec::new(),
+        });
+        handover.watchers.push(SavedWatcher {
+            token: 10,
+            fd: 6,
+            protocol: WatcherProtocol::Raw,
+            state: SavedState::TimeShifted(9, start),
+            terminal: TerminalInfo { width: 132, height: 43, terminal_type: Some(String::from("xterm")) },
+            telnet_local: vec![1, 3],
+            telnet_remote: Vec::new(),
+            backlog: b"een".to_vec(),
+        });
+        handover.unknown.push((11, 7, WatcherProtocol::Raw, b"GET".to_vec()));
+
+        let encoder = handover.encode();
+        assert_eq!(encoder.fds, vec![3, 5, 6, 7]);
+        // The receiving side gets new descriptor numbers.
+        let fds = [13, 15, 16, 17];
+        let decoded = Handover::decode(&mut Decoder { buf: &encoder.buf, fds: &fds }).unwrap();
+
+        assert_eq!(decoded.next_token_id, 42);
+        assert_eq!(decoded.sender, 1234);
+        assert_eq!(decoded.listeners, vec![(String::from("caster"), 13)]);
+        let caster = &decoded.casters[0];
+        assert_eq!((caster.token, caster.fd), (9, 15));
+        assert!(caster.unix);
+        assert_eq!(caster.name, Some(String::from("caster1")));
+        assert_eq!(caster.connected, start);
+        assert!(caster.recording);
+        assert_eq!(caster.cast_buffer, b"screen");
+        assert_eq!(caster.history.len(), 2);
+        let watcher = &decoded.watchers[0];
+        assert_eq!((watcher.token, watcher.fd), (10, 16));
+        assert_eq!(watcher.protocol, WatcherProtocol::Raw);
+        assert_eq!(watcher.state, SavedState::TimeShifted(9, start));
+        assert_eq!(watcher.terminal.terminal_type, Some(String::from("xterm")));
+        assert_eq!(watcher.telnet_local, vec![1, 3]);
+        assert_eq!(watcher.backlog, b"een");
+        assert_eq!(decoded.unknown, vec![(11, 17, WatcherProtocol::Raw, b"GET".to_vec())]);
+
+        let cut_short = &encoder.buf[..encoder.buf.len() - 1];
+        assert!(Handover::decode(&mut Decoder { buf: cut_short, fds: &fds }).is_err());
+    }
+}
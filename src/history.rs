@@ -0,0 +1,170 @@
+use chrono::{DateTime, Duration, UTC};
+use std::collections::VecDeque;
+
+// Sequences that wipe the whole screen, so nothing before them is needed to redraw it.
+const CLEARS: [&'static [u8]; 2] = [b"\x1b[2J", b"\x1bc"];
+
+
+/// Recent caster output, kept as timestamped chunks so a watcher can step back through it. Chunks
+/// are dropped once they are older than `max_age` or the total size exceeds `max_size`.
+#[derive(Debug)]
+pub struct History {
+    chunks: VecDeque<Chunk>,
+    size: usize,
+    max_size: usize,
+    max_age: Duration,
+}
+
+#[derive(Debug)]
+struct Chunk {
+    when: DateTime<UTC>,
+    data: Vec<u8>,
+    // Where the last screen clear in the chunk starts, if there is one.
+    clear: Option<usize>,
+}
+
+impl History {
+    pub fn new(max_size: usize, max_age: Duration) -> Self {
+        History {
+            chunks: VecDeque::new(),
+            size: 0,
+            max_size: max_size,
+            max_age: max_age,
+        }
+    }
+
+    pub fn add(&mut self, when: &DateTime<UTC>, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        self.chunks.push_back(Chunk {
+            when: *when,
+            data: data.to_vec(),
+            clear: last_clear(data),
+        });
+        self.size += data.len();
+
+        let oldest_allowed = *when - self.max_age;
+        while self.size > self.max_size || self.oldest().map(|t| t < oldest_allowed).unwrap_or(false) {
+            match self.chunks.pop_front() {
+                Some(chunk) => self.size -= chunk.data.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Time of the oldest chunk still kept.
+    pub fn oldest(&self) -> Option<DateTime<UTC>> {
+        self.chunks.front().map(|chunk| chunk.when)
+    }
+
+    /// A copy of every chunk kept, oldest first.
+    pub fn chunks(&self) -> Vec<(DateTime<UTC>, Vec<u8>)> {
+        self.chunks.iter().map(|chunk| (chunk.when, chunk.data.clone())).collect()
+    }
+
+    /// What it takes to redraw the screen as it was at `when`: everything kept up to and
+    /// including `when`, starting from the last time the screen was cleared.
+    pub fn until(&self, when: &DateTime<UTC>) -> Vec<u8> {
+        let end = self.chunks.iter().take_while(|chunk| chunk.when <= *when).count();
+        let start = self.chunks.iter().take(end).rposition(|chunk| chunk.clear.is_some());
+        let mut output = Vec::new();
+        if let Some(start) = start {
+            let chunk = &self.chunks[start];
+            output.extend_from_slice(&chunk.data[chunk.clear.unwrap_or(0)..]);
+        }
+        let first = start.map(|start| start + 1).unwrap_or(0);
+        for chunk in self.chunks.iter().take(end).skip(first) {
+            output.extend_from_slice(&chunk.data);
+        }
+        output
+    }
+
+    /// Everything after `from` up to and including `to`, to move a screen drawn as at `from`
+    /// forward. None if some of it is no longer kept.
+    pub fn between(&self, from: &DateTime<UTC>, to: &DateTime<UTC>) -> Option<Vec<u8>> {
+        if self.oldest().map(|oldest| oldest > *from).unwrap_or(true) {
+            return None;
+        }
+        let mut output = Vec::new();
+        for chunk in self.chunks.iter().skip_while(|chunk| chunk.when <= *from) {
+            if chunk.when > *to {
+                break;
+            }
+            output.extend_from_slice(&chunk.data);
+        }
+        Some(output)
+    }
+}
+
+fn last_clear(data: &[u8]) -> Option<usize> {
+    CLEARS.iter()
+          .filter_map(|clear| data.windows(clear.len()).rposition(|w| w == *clear))
+          .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, UTC};
+    use super::History;
+
+    #[test]
+    fn until() {
+        let mut history = History::new(100, Duration::minutes(10));
+        let start = UTC.timestamp(1000, 0);
+        history.add(&start, b"ab");
+        history.add(&(start + Duration::seconds(5)), b"cd");
+        history.add(&(start + Duration::seconds(10)), b"ef");
+
+        assert_eq!(history.until(&(start - Duration::seconds(1))), b"");
+        assert_eq!(history.until(&start), b"ab");
+        assert_eq!(history.until(&(start + Duration::seconds(7))), b"abcd");
+        assert_eq!(history.until(&(start + Duration::seconds(10))), b"abcdef");
+    }
+
+    #[test]
+    fn limits() {
+        let mut history = History::new(4, Duration::minutes(10));
+        let start = UTC.timestamp(1000, 0);
+        history.add(&start, b"ab");
+        history.add(&start, b"cd");
+        history.add(&start, b"ef");
+        assert_eq!(history.until(&start), b"cdef");
+
+        let mut history = History::new(100, Duration::seconds(10));
+        history.add(&start, b"ab");
+        history.add(&(start + Duration::seconds(5)), b"cd");
+        history.add(&(start + Duration::seconds(11)), b"ef");
+        assert_eq!(history.oldest(), Some(start + Duration::seconds(5)));
+    }
+
+    #[test]
+    fn clears() {
+        let mut history = History::new(100, Duration::minutes(10));
+        let start = UTC.timestamp(1000, 0);
+        history.add(&start, b"ab");
+        history.add(&(start + Duration::seconds(5)), b"c\x1b[2Jd\x1bce");
+        history.add(&(start + Duration::seconds(10)), b"fg");
+
+        // Nothing from before the last clear is needed.
+        assert_eq!(history.until(&start), b"ab");
+        assert_eq!(history.until(&(start + Duration::seconds(5))), b"\x1bce");
+        assert_eq!(history.until(&(start + Duration::seconds(10))), b"\x1bcefg");
+    }
+
+    #[test]
+    fn between() {
+        let mut history = History::new(100, Duration::seconds(10));
+        let start = UTC.timestamp(1000, 0);
+        history.add(&start, b"ab");
+        history.add(&(start + Duration::seconds(5)), b"cd");
+        history.add(&(start + Duration::seconds(10)), b"ef");
+
+        assert_eq!(history.between(&start, &(start + Duration::seconds(5))), Some(b"cd".to_vec()));
+        assert_eq!(history.between(&start, &(start + Duration::seconds(20))), Some(b"cdef".to_vec()));
+
+        // The start of the screen being moved forward has gone.
+        history.add(&(start + Duration::seconds(15)), b"gh");
+        assert_eq!(history.between(&start, &(start + Duration::seconds(15))), None);
+    }
+}
@@ -0,0 +1,114 @@
+// Listening sockets opened by someone else: systemd's socket activation (see sd_listen_fds(3)) or
+// the server being upgraded. Each is known by the name of the listener it's for, such as "caster"
+// or "admin".
+
+use libc;
+use mio::tcp::TcpListener;
+use mio::unix::UnixListener;
+use std::env;
+use std::io::Error;
+use std::os::unix::io::{FromRawFd, RawFd};
+
+// systemd passes sockets starting from this descriptor.
+const LISTEN_FDS_START: RawFd = 3;
+const LISTENER_NAMES: [&'static str; 10] = ["caster", "caster_socket", "watcher", "raw_watcher",
+                                            "ssh", "web", "web_caster", "status", "metrics", "admin"];
+
+
+pub struct InheritedListeners {
+    fds: Vec<(String, RawFd)>,
+}
+
+impl InheritedListeners {
+    pub fn new(fds: Vec<(String, RawFd)>) -> Self {
+        InheritedListeners {
+            fds: fds,
+        }
+    }
+
+    /// Sockets systemd passed in, if it started this process. Each socket unit's
+    /// `FileDescriptorName=` picks the listener; sockets without one of the listener names are
+    /// taken as the caster and watcher listeners, in that order. The variables are cleared so
+    /// nothing started from here thinks the sockets are meant for it.
+    pub fn from_systemd() -> Self {
+        let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<libc::pid_t>().ok());
+        let num_fds = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<RawFd>().ok());
+        let names = env::var("LISTEN_FDNAMES").unwrap_or(String::new());
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+
+        let num_fds = match (pid, num_fds) {
+            (Some(pid), Some(num_fds)) if pid == unsafe { libc::getpid() } => num_fds,
+            _ => return InheritedListeners::new(Vec::new()),
+        };
+        let mut names = names.split(':');
+        let mut unnamed = ["caster", "watcher"].iter();
+        let mut fds = Vec::new();
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START + num_fds {
+            let name = match names.next() {
+                Some(name) if LISTENER_NAMES.iter().any(|n| *n == name) => Some(name),
+                _ => unnamed.next().map(|name| *name),
+            };
+            match name {
+                Some(name) => fds.push((String::from(name), fd)),
+                None => {
+                    warn!("event=ignored_socket fd={} reason=no_listener_name", fd);
+                    unsafe { libc::close(fd) };
+                },
+            }
+        }
+        InheritedListeners::new(fds)
+    }
+
+    pub fn tcp(&mut self, name: &str) -> Result<Option<TcpListener>, Error> {
+        match self.take(name) {
+            Some(fd) => {
+                try!(set_nonblocking(fd));
+                Ok(Some(unsafe { TcpListener::from_raw_fd(fd) }))
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn unix(&mut self, name: &str) -> Result<Option<UnixListener>, Error> {
+        match self.take(name) {
+            Some(fd) => {
+                try!(set_nonblocking(fd));
+                Ok(Some(unsafe { UnixListener::from_raw_fd(fd) }))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn take(&mut self, name: &str) -> Option<RawFd> {
+        let idx = self.fds.iter().position(|&(ref fd_name, _)| fd_name == name);
+        idx.map(|idx| self.fds.remove(idx).1)
+    }
+}
+
+impl Drop for InheritedListeners {
+    fn drop(&mut self) {
+        // Listeners that weren't wanted, such as for SSH without any SSH configuration, or more
+        // caster or watcher sockets than there are addresses configured for them.
+        for &(ref name, fd) in &self.fds {
+            warn!("event=ignored_socket fd={} name={} reason=not_configured", fd, name);
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+/// Make an inherited socket non-blocking, as the event loop needs, and make sure it isn't passed
+/// on to anything exec'd later.
+fn set_nonblocking(fd: RawFd) -> Result<(), Error> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags == -1 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) == -1 {
+            return Err(Error::last_os_error());
+        }
+        if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) == -1 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -1,5 +1,7 @@
 extern crate chrono;
 extern crate core;
+extern crate flate2;
+extern crate libc;
 extern crate mio;
 #[macro_use]
 extern crate log;
@@ -7,34 +9,84 @@ extern crate sodiumoxide;
 extern crate toml;
 
 pub mod config;
+pub mod daemon;
+pub mod logger;
 
+mod admin;
+mod audit;
 mod auth;
 mod caster;
 mod duration;
+mod handover;
+mod history;
+mod inherit;
+mod json;
+mod metrics;
+mod output;
+mod playback;
+mod record;
 mod ring;
+mod signal;
+mod ssh;
+mod telnet;
 mod term;
 mod watcher;
+mod web;
 
-use chrono::{DateTime, UTC};
+use chrono::{DateTime, Duration, UTC};
 use mio::*;
 use std::io::{Error, ErrorKind};
 use std::io::Read;
 use std::io::Write;
-use mio::tcp::TcpListener;
+use mio::tcp::{TcpListener, TcpSocket, TcpStream};
+use mio::unix::UnixListener;
 use std::collections::HashMap;
-use std::collections::hash_map::Entry;
+use std::fs;
+use std::mem;
 use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::thread;
+use std::time::SystemTime;
 
+use admin::{AdminCommand, AdminConnection, KickTarget};
+use audit::{AuditEvent, AuditLog};
 use auth::CasterAuth;
-use caster::{Caster, CasterMenuEntry};
+use caster::{Caster, CasterMenuEntry, CasterStream};
 use duration::relative_duration_format;
-use config::TermcastConfig;
-use watcher::{Watcher, WatcherAction, WatcherState};
+use config::{DelayConfig, HistoryConfig, RecordConfig, TermcastConfig, WatcherProtocol};
+use handover::Handover;
+use inherit::InheritedListeners;
+use log::LogLevel;
+use logger::Conn;
+use metrics::{Metrics, WatcherCounts};
+use playback::{Playback, PlaybackCommand, RecordingEntry};
+use signal::SignalPipe;
+use ssh::{HostKey, SshSession};
+use watcher::{TerminalInfo, Watcher, WatcherAction, WatcherState};
+use web::{Request, WebSession};
 
 
-const CASTER: Token = Token(0);
-const WATCHER: Token = Token(1);
-const CASTERS_PER_SCREEN: usize = 16;
+// Caster and watcher listeners get their tokens as they're added, as there can be any number.
+const CASTER_SOCKET: Token = Token(0);
+const SSH_WATCHER: Token = Token(2);
+const WEB_WATCHER: Token = Token(3);
+const WEB_CASTER: Token = Token(4);
+const STATUS: Token = Token(5);
+const METRICS: Token = Token(6);
+const ADMIN: Token = Token(7);
+const SIGNALS: Token = Token(8);
+// Connections, and the caster and watcher listeners, are numbered from here.
+const FIRST_TOKEN: usize = 9;
+// How long a new connection on the watcher port has to show it's a browser.
+const SNIFF_TIMEOUT_MS: u64 = 500;
+// How often old recordings are cleaned up.
+const CLEANUP_INTERVAL_MS: u64 = 10 * 60 * 1000;
+// Lines the menu header and footer take up, leaving the rest of the screen for entries.
+const MENU_CHROME_LINES: usize = 6;
 const MENU_CHOICES: [&'static str; 16] = ["a", "b", "c", "d", "e", "f", "g",
                                           "h", "i", "j", "k", "l", "m", "n",
                                           "o", "p"];
@@ -44,75 +96,208 @@ struct MenuView {
     motd: String,
     caster_entries: Vec<CasterMenuEntry>,
     total_watchers: usize,
+    has_recordings: bool,
+}
+
+struct RecordingsView {
+    recordings: Vec<RecordingEntry>,
 }
 
 struct Termcastd {
-    listen_caster: TcpListener,
-    listen_watcher: TcpListener,
+    listeners: HashMap<Token, Listener>,
+    listen_caster_socket: Option<UnixListener>,
     clients: HashMap<Token, Client>,
     watchers: HashMap<Token, Watcher>,
+    unknown: HashMap<Token, UnknownConnection>,
     casters: HashMap<Token, Caster>,
     caster_auth: CasterAuth,
+    audit: AuditLog,
     next_token_id: usize,
     motd: String,
+    record_config: Option<RecordConfig>,
+    playback_directory: Option<PathBuf>,
+    delay_config: DelayConfig,
+    history_config: HistoryConfig,
+    // Watcher listen addresses whose watchers are raw.
+    raw_watcher: Vec<SocketAddr>,
+    watcher_http: bool,
+    listen_ssh: Option<TcpListener>,
+    ssh_host_key: Option<HostKey>,
+    ssh_host_key_path: Option<PathBuf>,
+    ssh_password: Option<String>,
+    listen_web: Option<TcpListener>,
+    listen_web_caster: Option<TcpListener>,
+    listen_status: Option<TcpListener>,
+    http_connections: HashMap<Token, HttpConnection>,
+    listen_metrics: Option<TcpListener>,
+    metrics: Metrics,
+    listen_admin: Option<UnixListener>,
+    admin_connections: HashMap<Token, AdminConnection>,
+    config_file: Option<PathBuf>,
+    listen_addrs: ListenAddrs,
+    signals: Option<SignalPipe>,
+    shutting_down: bool,
+    shutdown_timeout: u64,
+    // Set when the event loop has stopped so everything can be handed over to a new server.
+    upgrading: bool,
+    // Watchers sent the goodbye because they can't be handed over.
+    told_to_reconnect: Vec<Token>,
 }
 
 pub struct TermcastServer {
     termcastd: Termcastd,
     config: TermcastConfig,
     event_loop: EventLoop<Termcastd>,
+    // The child sending the last handover, if the new server never started.
+    handover_sender: Option<libc::pid_t>,
 }
 
 
 pub enum TermcastdMessage {
+    /// Show a message to every watcher.
+    Broadcast(String),
     CasterDisconnected(Token),
+    /// Disconnect a caster or watcher.
+    Kick(Token),
+    /// Read the configuration file again.
+    Reload,
+    /// Stop accepting connections, tell everyone the server is going away and exit once watchers
+    /// have been sent everything, or the shutdown timeout has passed.
+    Shutdown,
+    /// Hand everything over to a new copy of the server binary, see `TermcastServer::hand_over`.
+    Upgrade,
     WatcherDisconnected(Token),
+    /// Exit right away.
+    Quit,
+}
+
+/// Why a connection was torn down, as logged and counted in the metrics.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DisconnectReason {
+    /// The client was done: a watcher quit from the menu, a WebSocket or SSH session was closed
+    /// or an HTTP request was answered.
     Quit,
+    /// The other end closed the connection.
+    Hangup,
+    /// Reading or writing failed, or the client sent something that made no sense.
+    IoError,
+    /// A caster's log in was refused.
+    AuthFailed,
+    /// An operator kicked them.
+    Kicked,
+    /// A watcher was still being sent the goodbye when the shutdown timeout passed.
+    Timeout,
+    /// The server is shutting down.
+    Shutdown,
 }
 
 #[derive(Clone, Copy, Debug)]
 enum Client {
+    // An operator on the admin socket.
+    Admin,
+    Caster,
+    // A one-off HTTP request, such as for the status API or metrics.
+    Http,
+    // Connected to the watcher port, but not yet known to be a browser or a watcher.
+    Unknown,
+    Watcher,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum TermcastdTimeout {
+    Cleanup,
+    Playback(Token),
+    Release(Token),
+    Shutdown,
+    Sniff(Token),
+}
+
+/// Which listener an HTTP request came in on, and so what it can ask for.
+#[derive(Clone, Copy, Debug)]
+enum HttpEndpoint {
+    Metrics,
+    Status,
+}
+
+/// What casters or watchers a listener is for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ListenerRole {
     Caster,
     Watcher,
 }
 
+/// One of the caster or watcher listeners, along with the address it was configured with.
+struct Listener {
+    role: ListenerRole,
+    addr: SocketAddr,
+    // How watchers on it talk. Always telnet for caster listeners.
+    protocol: WatcherProtocol,
+    socket: TcpListener,
+}
+
+/// The address each listener was configured with, to tell what a reload changed. Bound addresses
+/// can differ, such as when the configured port is 0. Caster and watcher listeners each keep
+/// their own, see `Listener`.
+struct ListenAddrs {
+    caster_socket: Option<PathBuf>,
+    ssh: Option<SocketAddr>,
+    web: Option<SocketAddr>,
+    web_caster: Option<SocketAddr>,
+    status: Option<SocketAddr>,
+    metrics: Option<SocketAddr>,
+    admin: Option<PathBuf>,
+}
+
+struct HttpConnection {
+    sock: TcpStream,
+    input: Vec<u8>,
+    endpoint: HttpEndpoint,
+}
+
+struct UnknownConnection {
+    sock: TcpStream,
+    protocol: WatcherProtocol,
+    // Everything read so far, handed to the watcher once it's known what kind it is.
+    input: Vec<u8>,
+    timeout: Option<Timeout>,
+}
+
 
 impl MenuView {
-    fn render(&self, offset: usize) -> (String, Option<usize>) {
+    /// The menu starting from the entry at `offset`, and the offset actually used, which is moved
+    /// back to the last page if it's past the end.
+    fn render(&self, offset: usize, terminal: &TerminalInfo) -> (String, usize) {
         fn caster_menu_entry(now: &DateTime<UTC>, choice: &'static str,
-                             caster: &CasterMenuEntry) -> String {
-            format!(" {}) {} (idle {}, connected {}, {} watching, {} bytes)\r\n",
-                    choice, caster.name(),
-                    relative_duration_format(&now, caster.last_byte_received()),
-                    relative_duration_format(&now, caster.connected_when()),
-                    caster.num_watchers(),
-                    caster.buffer_size())
+                             caster: &CasterMenuEntry, width: u16) -> String {
+            let entry = format!(" {}) {} (idle {}, connected {}, {} watching, {} bytes)",
+                                choice, caster.name(),
+                                relative_duration_format(&now, caster.last_byte_received()),
+                                relative_duration_format(&now, caster.connected_when()),
+                                caster.num_watchers(),
+                                caster.buffer_size());
+            // Keep each entry on one line so the menu fits on the screen.
+            let mut line: String = entry.chars().take((width as usize).saturating_sub(1)).collect();
+            line.push_str("\r\n");
+            line
         }
 
         let num_casters = self.caster_entries.len();
-        // If the offset is too high, reset it to the last page.
-        let actual_offset = if offset < num_casters {
-            offset
-        }
-        else {
-            let page_length = MENU_CHOICES.len();
-            let pages = num_casters / page_length;
-            if num_casters == 0 || num_casters % page_length != 0 {
-                pages * page_length
-            }
-            else {
-                (pages - 1) * page_length
-            }
-        };
+        let rows = terminal.menu_rows();
+        let actual_offset = fix_page_offset(offset, num_casters, rows);
 
+        // The MOTD takes the place of the blank first line so the menu stays the same height.
+        let motd: String = self.motd.chars()
+                                    .filter(|c| !c.is_control())
+                                    .take((terminal.width as usize).saturating_sub(1))
+                                    .collect();
         let menu_header = format!(
             concat!(
                 "{}{}",
-                "\r\n",
+                "{}\r\n",
                 " ## Termcast\r\n",
                 " ## {} sessions available. {} watchers connected.\r\n\r\n",
             ),
-            term::clear_screen(), term::reset_cursor(),
+            terminal.clear_screen(), terminal.reset_cursor(), motd,
             num_casters, self.total_watchers);
 
         let mut menu = String::with_capacity(80*24);
@@ -120,46 +305,209 @@ impl MenuView {
 
         let now = UTC::now();
         let caster_choices = self.caster_entries.iter()
-                    .skip(offset)
-                    .take(CASTERS_PER_SCREEN);
+                    .skip(actual_offset)
+                    .take(rows);
         for c in caster_choices.zip(&MENU_CHOICES) {
             let (caster, choice) = c;
-            menu.push_str(&caster_menu_entry(&now, choice, caster));
+            menu.push_str(&caster_menu_entry(&now, choice, caster, terminal.width));
+        }
+
+        let menu_footer = if self.has_recordings {
+            concat!(
+                "\r\n",
+                "Watch which session? ('<' '>' change page, 'r' recordings, 'q' quits)",
+                " ",
+            )
+        }
+        else {
+            concat!(
+                "\r\n",
+                "Watch which session? ('<' '>' change page, 'q' quits)",
+                " ",
+            )
+        };
+        menu.push_str(&menu_footer);
+
+        (menu, actual_offset)
+    }
+
+    fn get_offset_token(&self, offset: usize) -> Option<Token> {
+        self.caster_entries.get(offset)
+                           .map(|entry| entry.token())
+    }
+
+    fn get_name_token(&self, name: &str) -> Option<Token> {
+        self.caster_entries.iter()
+                           .find(|entry| entry.name() == name)
+                           .map(|entry| entry.token())
+    }
+
+    fn has_recordings(&self) -> bool {
+        self.has_recordings
+    }
+}
+
+impl RecordingsView {
+    /// List the recordings in the playback directory. A missing or unreadable directory is shown
+    /// as having no recordings.
+    fn new(directory: Option<&PathBuf>) -> Self {
+        let recordings = directory.and_then(|d| playback::recordings(d).ok())
+                                  .unwrap_or(Vec::new());
+        RecordingsView {
+            recordings: recordings,
+        }
+    }
+
+    fn render(&self, offset: usize, terminal: &TerminalInfo) -> (String, usize) {
+        let num_recordings = self.recordings.len();
+        let rows = terminal.menu_rows();
+        let actual_offset = fix_page_offset(offset, num_recordings, rows);
+
+        let menu_header = format!(
+            concat!(
+                "{}{}",
+                "\r\n",
+                " ## Termcast recordings\r\n",
+                " ## {} recordings available.\r\n\r\n",
+            ),
+            terminal.clear_screen(), terminal.reset_cursor(),
+            num_recordings);
+
+        let mut menu = String::with_capacity(80*24);
+        menu.push_str(&menu_header);
+
+        let recording_choices = self.recordings.iter()
+                    .skip(actual_offset)
+                    .take(rows);
+        for (recording, choice) in recording_choices.zip(&MENU_CHOICES) {
+            // File names can be anything, so leave out what the terminal would act on and keep
+            // each entry on one line.
+            let file_name: String = recording.file_name().chars().filter(|c| !c.is_control()).collect();
+            let entry = format!(" {}) {} ({}, {} bytes)",
+                                choice, file_name,
+                                recording.modified().format("%Y-%m-%d %H:%M"),
+                                recording.size());
+            menu.extend(entry.chars().take((terminal.width as usize).saturating_sub(1)));
+            menu.push_str("\r\n");
         }
 
         let menu_footer = concat!(
             "\r\n",
-            "Watch which session? ('q' quits)",
+            "Play which recording? ('<' '>' change page, 'q' returns)",
             " ",
         );
         menu.push_str(&menu_footer);
 
-        if actual_offset != offset {
-            return (menu, None);
+        (menu, actual_offset)
+    }
+
+    fn get_offset_file_name(&self, offset: usize) -> Option<&String> {
+        self.recordings.get(offset)
+                       .map(|entry| entry.file_name())
+    }
+}
+
+/// If the offset is past the last entry, reset it to the start of the last page.
+fn fix_page_offset(offset: usize, num_entries: usize, page_length: usize) -> usize {
+    if offset < num_entries {
+        offset
+    }
+    else {
+        let pages = num_entries / page_length;
+        if num_entries == 0 || num_entries % page_length != 0 {
+            pages * page_length
         }
         else {
-            return (menu, Some(actual_offset));
+            (pages - 1) * page_length
         }
     }
+}
 
-    fn get_offset_token(&self, offset: usize) -> Option<Token> {
-        self.caster_entries.get(offset)
-                           .map(|entry| entry.token())
+impl ListenerRole {
+    /// Also the name its inherited sockets go by, see `InheritedListeners`.
+    fn name(&self) -> &'static str {
+        match *self {
+            ListenerRole::Caster => "caster",
+            ListenerRole::Watcher => "watcher",
+        }
+    }
+}
+
+impl DisconnectReason {
+    /// How the reason is labelled in the log and metrics.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DisconnectReason::Quit => "quit",
+            DisconnectReason::Hangup => "hangup",
+            DisconnectReason::IoError => "io_error",
+            DisconnectReason::AuthFailed => "auth_failed",
+            DisconnectReason::Kicked => "kicked",
+            DisconnectReason::Timeout => "timeout",
+            DisconnectReason::Shutdown => "shutdown",
+        }
     }
 }
 
 impl Termcastd {
-    fn new(listen_caster: TcpListener, listen_watcher: TcpListener) -> Self {
-        Termcastd {
-            listen_caster: listen_caster,
-            listen_watcher: listen_watcher,
+    fn new(listeners: Vec<Listener>, listen_caster_socket: Option<UnixListener>,
+           listen_ssh: Option<(TcpListener, HostKey)>, listen_web: Option<TcpListener>,
+           listen_web_caster: Option<TcpListener>, listen_status: Option<TcpListener>,
+           listen_metrics: Option<TcpListener>, listen_admin: Option<UnixListener>,
+           audit: AuditLog, config: &TermcastConfig, next_token_id: usize) -> Self {
+        let (listen_ssh, ssh_host_key) = match listen_ssh {
+            Some((listener, host_key)) => (Some(listener), Some(host_key)),
+            None => (None, None),
+        };
+        let mut termcastd = Termcastd {
+            listeners: HashMap::new(),
+            listen_caster_socket: listen_caster_socket,
             clients: HashMap::new(),
             casters: HashMap::new(),
             caster_auth: CasterAuth::new(),
+            audit: audit,
             watchers: HashMap::new(),
-            next_token_id: 2,
-            motd: String::from(""),
+            unknown: HashMap::new(),
+            next_token_id: next_token_id,
+            motd: config.motd.clone().unwrap_or(String::new()),
+            record_config: config.record.clone(),
+            playback_directory: config.playback.clone(),
+            delay_config: config.delay.clone(),
+            history_config: config.history.clone(),
+            raw_watcher: config.raw_watcher.clone(),
+            watcher_http: config.watcher_http,
+            listen_ssh: listen_ssh,
+            ssh_host_key: ssh_host_key,
+            ssh_host_key_path: config.ssh.as_ref().map(|ssh| ssh.host_key.clone()),
+            ssh_password: config.ssh.as_ref().and_then(|ssh| ssh.password.clone()),
+            listen_web: listen_web,
+            listen_web_caster: listen_web_caster,
+            listen_status: listen_status,
+            http_connections: HashMap::new(),
+            listen_metrics: listen_metrics,
+            metrics: Metrics::new(),
+            listen_admin: listen_admin,
+            admin_connections: HashMap::new(),
+            config_file: config.config_file.clone(),
+            listen_addrs: ListenAddrs {
+                caster_socket: config.caster_socket.clone(),
+                ssh: config.ssh.as_ref().map(|ssh| ssh.listen),
+                web: config.web,
+                web_caster: config.web_caster,
+                status: config.status,
+                metrics: config.metrics,
+                admin: config.admin_socket.clone(),
+            },
+            signals: None,
+            shutting_down: false,
+            shutdown_timeout: config.shutdown_timeout,
+            upgrading: false,
+            told_to_reconnect: Vec::new(),
+        };
+        for listener in listeners {
+            let token = termcastd.next_token();
+            termcastd.listeners.insert(token, listener);
         }
+        termcastd
     }
 
     fn next_token(&mut self) -> Token {
@@ -176,175 +524,550 @@ impl Termcastd {
             motd: self.motd.clone(),
             caster_entries: valid_casters.collect(),
             total_watchers: self.watchers.len(),
+            has_recordings: self.playback_directory.is_some(),
         };
         return view;
     }
 
+    /// Live sessions as JSON, for the status API.
+    fn status_json(&self) -> String {
+        let now = UTC::now();
+        let casters: Vec<String> = self.casters.values()
+            .filter_map(|c| c.menu_entry())
+            .map(|entry| entry.to_json(&now))
+            .collect();
+        format!("{{\"casters\": [{}], \"total_watchers\": {}}}\n",
+                casters.join(", "), self.watchers.len())
+    }
+
+    /// Counters so far along with the current gauges, for Prometheus.
+    fn metrics_text(&self) -> String {
+        let casters_connected = self.casters.values().filter(|c| c.menu_entry().is_some()).count();
+        let mut watchers = WatcherCounts::default();
+        for watcher in self.watchers.values() {
+            match watcher.state {
+                WatcherState::AwaitingName | WatcherState::Connecting | WatcherState::MainMenu |
+                WatcherState::RecordingsMenu => watchers.menu += 1,
+                WatcherState::Watching(_) | WatcherState::TimeShifted(_, _) => watchers.watching += 1,
+                WatcherState::Playback => watchers.playback += 1,
+                WatcherState::Disconnecting => {},
+            }
+        }
+        self.metrics.render(casters_connected, &watchers)
+    }
+
     // Section for Caster and Watcher functions.
     ////////////////////////////////////
-    fn handle_disconnect(&mut self, event_loop: &mut EventLoop<Termcastd>, token: Token) {
-        if let Entry::Occupied(client) = self.clients.entry(token) {
-            match client.get() {
-                &Client::Caster => {
-                    if let Entry::Occupied(caster_entry) = self.casters.entry(token) {
-                        {
-                            let caster = caster_entry.get();
-                            let res = event_loop.deregister(caster.socket());
-                            let channel = event_loop.channel();
-                            // To not have to do a mutable borrow, send a message to
-                            // reset these watchers back to the main menu. Everything
-                            // will be dropped after the end of the match when the
-                            // entry is removed.
-                            for watcher in caster.each_watcher() {
-                                let res = channel.send(TermcastdMessage::CasterDisconnected(watcher.token()));
-                            }
-                        }
-                        caster_entry.remove();
+    /// The one way connections of every kind are torn down. Takes the connection out of every
+    /// table and the event loop and lets anyone it affects know: a caster's watchers go back to
+    /// the main menu, and a watcher is taken off the caster they were watching. Events can still
+    /// be queued up for a connection already torn down, so unknown tokens are ignored.
+    fn handle_disconnect(&mut self, event_loop: &mut EventLoop<Termcastd>, token: Token,
+                         reason: DisconnectReason) {
+        let client = match self.clients.remove(&token) {
+            Some(client) => client,
+            None => return,
+        };
+        let (kind, peer) = match client {
+            Client::Admin => {
+                if let Some(connection) = self.admin_connections.remove(&token) {
+                    let _ = event_loop.deregister(connection.socket());
+                }
+                ("admin", None)
+            },
+            Client::Caster => {
+                let mut peer = None;
+                if let Some(mut caster) = self.casters.remove(&token) {
+                    peer = caster.socket().peer_addr();
+                    let _ = event_loop.deregister(caster.socket());
+                    if let Some(timeout) = caster.take_release_timeout() {
+                        event_loop.clear_timeout(timeout);
                     }
-                },
-                &Client::Watcher => {
-                    if let Entry::Occupied(watcher_entry) = self.watchers.entry(token) {
-                        {
-                            let watcher = watcher_entry.get();
-                            let res = event_loop.deregister(watcher.sock());
+                    caster.close();
+                    if let Some(name) = caster.name() {
+                        self.metrics.caster_disconnected(name);
+                    }
+                    // Watchers have already been sent the goodbye when shutting down.
+                    if !self.shutting_down {
+                        for watcher in caster.each_watcher() {
+                            self.reset_watcher(watcher.token());
                         }
-                        watcher_entry.remove();
                     }
+                }
+                ("caster", peer)
+            },
+            Client::Http => {
+                let mut peer = None;
+                if let Some(connection) = self.http_connections.remove(&token) {
+                    peer = connection.sock.peer_addr().ok();
+                    let _ = event_loop.deregister(&connection.sock);
+                }
+                ("http", peer)
+            },
+            Client::Unknown => {
+                let mut peer = None;
+                if let Some(connection) = self.unknown.remove(&token) {
+                    peer = connection.sock.peer_addr().ok();
+                    let _ = event_loop.deregister(&connection.sock);
+                    if let Some(timeout) = connection.timeout {
+                        event_loop.clear_timeout(timeout);
+                    }
+                }
+                ("watcher", peer)
+            },
+            Client::Watcher => {
+                let mut peer = None;
+                if let Some(mut watcher) = self.watchers.remove(&token) {
+                    peer = watcher.sock().peer_addr().ok();
+                    let _ = event_loop.deregister(watcher.sock());
+                    if let Some(timeout) = watcher.playback.as_mut().and_then(|p| p.take_timeout()) {
+                        event_loop.clear_timeout(timeout);
+                    }
+                    let watching = match watcher.state {
+                        WatcherState::Watching(caster_token) => Some(caster_token),
+                        WatcherState::TimeShifted(caster_token, _) => Some(caster_token),
+                        _ => None,
+                    };
+                    if let Some(caster) = watching.and_then(|caster_token| self.casters.get_mut(&caster_token)) {
+                        caster.remove_watcher(token);
+                    }
+                }
+                ("watcher", peer)
+            },
+        };
+
+        let level = match client {
+            Client::Http => LogLevel::Debug,
+            _ => LogLevel::Info,
+        };
+        self.disconnected(token, peer, kind, level, reason);
+    }
+
+    /// Log a connection that has gone and count it.
+    fn disconnected(&mut self, token: Token, peer: Option<SocketAddr>, kind: &str, level: LogLevel,
+                    reason: DisconnectReason) {
+        log!(level, "{} event=disconnect kind={} reason={}", Conn(token, peer), kind, reason.name());
+        self.metrics.disconnect(reason.name());
+    }
+
+    /// Accept a caster or watcher on one of their listeners.
+    fn accept(&mut self, event_loop: &mut EventLoop<Termcastd>, token: Token) {
+        let (role, protocol, accepted) = match self.listeners.get(&token) {
+            Some(listener) => (listener.role, listener.protocol, listener.socket.accept()),
+            None => return,
+        };
+        if let Ok(Some(sock)) = accepted {
+            match role {
+                ListenerRole::Caster => self.new_caster(event_loop, CasterStream::Tcp(sock)),
+                ListenerRole::Watcher => {
+                    let _ = self.new_watcher(event_loop, sock, protocol);
                 },
             }
-            client.remove();
-        }
-        else {
-            panic!("Couldn't find token {:?} in self.clients", token);
         }
     }
 
     // Section for Caster functions.
     ////////////////////////////////////
-    fn new_caster(&mut self, event_loop: &mut EventLoop<Termcastd>) {
-        if let Ok(opt) = self.listen_caster.accept() {
-            if let Some(sock) = opt {
-                let token = self.next_token();
-                let caster = Caster::new(token, sock);
-                let res = event_loop.register_opt(
-                    caster.socket(),
-                    token,
-                    EventSet::all(),
-                    PollOpt::edge(),
-                );
-                if res.is_ok() {
-                    let client = Client::Caster;
-                    self.clients.insert(token, client);
-                    self.casters.insert(token, caster);
-                }
+    fn new_caster(&mut self, event_loop: &mut EventLoop<Termcastd>, sock: CasterStream) {
+        let token = self.next_token();
+        let caster = Caster::new(token, sock, &self.history_config);
+        let res = event_loop.register_opt(
+            caster.socket(),
+            token,
+            EventSet::all(),
+            PollOpt::edge(),
+        );
+        if res.is_ok() {
+            info!("{} event=connect kind=caster", Conn(token, caster.socket().peer_addr()));
+            let client = Client::Caster;
+            self.clients.insert(token, client);
+            self.casters.insert(token, caster);
+        }
+    }
+
+    fn new_unix_caster(&mut self, event_loop: &mut EventLoop<Termcastd>) {
+        let accepted = match self.listen_caster_socket {
+            Some(ref listener) => listener.accept(),
+            None => return,
+        };
+        if let Ok(Some(sock)) = accepted {
+            self.new_caster(event_loop, CasterStream::Unix(sock));
+        }
+    }
+
+    fn new_web_caster(&mut self, event_loop: &mut EventLoop<Termcastd>) {
+        let accepted = match self.listen_web_caster {
+            Some(ref listener) => listener.accept(),
+            None => return,
+        };
+        if let Ok(Some(sock)) = accepted {
+            let token = self.next_token();
+            let caster = Caster::with_websocket(token, sock, &self.history_config);
+            let res = event_loop.register_opt(
+                caster.socket(),
+                token,
+                EventSet::all(),
+                PollOpt::edge(),
+            );
+            if res.is_ok() {
+                info!("{} event=connect kind=web_caster", Conn(token, caster.socket().peer_addr()));
+                self.clients.insert(token, Client::Caster);
+                self.casters.insert(token, caster);
             }
         }
     }
 
-    /// Wrapper function for handling caster input. The caster will be removed if an error is
-    /// returned from the wrapped function. Any watchers of that caster will be reset back to the
-    /// main menu.
+    /// Wrapper function for handling caster input. The caster is disconnected if the wrapped
+    /// function returns an error.
     fn read_caster(&mut self, event_loop: &mut EventLoop<Termcastd>, token: Token) {
-        if let Err(watchers) = self.caster_input(event_loop, token) {
-            for watcher in &watchers {
-                self.reset_watcher(*watcher);
-            }
+        match self.caster_input(event_loop, token) {
+            Ok(failed) => self.disconnect_failed_watchers(event_loop, failed),
+            Err(reason) => self.handle_disconnect(event_loop, token, reason),
+        }
+    }
 
-            let _ = self.casters.remove(&token);
+    /// Actual method to interface between the Caster method to parse the input and Termcastd.
+    /// Returns the watchers who couldn't be sent the input, or why the caster has to be
+    /// disconnected.
+    fn caster_input(&mut self, event_loop: &mut EventLoop<Termcastd>, token: Token) -> Result<Vec<Token>, DisconnectReason> {
+        let mut failed = Vec::new();
+        if let Some(caster) = self.casters.get_mut(&token) {
+            failed = try!(caster.input(&mut self.caster_auth, self.record_config.as_ref(),
+                                       &self.delay_config, &mut self.metrics, &mut self.audit));
+            if !caster.has_release_timeout() {
+                Termcastd::schedule_release(event_loop, caster);
+            }
         }
+        Ok(failed)
+    }
+
+    /// Remove old recordings in the background so a big recording directory never holds up the
+    /// event loop. Recordings casters are still writing to are left alone.
+    fn cleanup_recordings(&self) {
+        let config = match self.record_config {
+            Some(ref config) => config.clone(),
+            None => return,
+        };
+        let open: Vec<PathBuf> = self.casters.values()
+                                     .flat_map(|caster| caster.recording_paths().iter().cloned())
+                                     .collect();
+        let since = SystemTime::now();
+        thread::spawn(move || record::cleanup(&config, &open, since));
     }
 
-    /// Actual method to interface between the Caster method to parse the input and Termcastd. If
-    /// there is an error, then the watchers for that caster will be grouped together and sent up
-    /// the call chain to have them reset.
-    fn caster_input(&mut self, event_loop: &mut EventLoop<Termcastd>, token: Token) -> Result<(), Vec<Token>> {
+    /// Broadcast the caster's delayed input that is now due.
+    fn release_caster(&mut self, event_loop: &mut EventLoop<Termcastd>, token: Token) {
+        let mut failed = Vec::new();
         if let Some(caster) = self.casters.get_mut(&token) {
-            if let Err(_) = caster.input(&mut self.caster_auth) {
-                // Assemble all of the watchers, stuff them in a vector, and send it up to have
-                // those watchers reset to the main menu.
-                let watchers = caster.each_watcher()
-                    .map(|w| w.token()).collect();
+            // This timeout has fired so there is nothing to clear.
+            let _ = caster.take_release_timeout();
+            failed = caster.release_delayed(&mut self.metrics);
+            Termcastd::schedule_release(event_loop, caster);
+        }
+        self.disconnect_failed_watchers(event_loop, failed);
+    }
 
-                event_loop.deregister(caster.socket());
+    /// Send a WebSocket caster the replies their socket couldn't take earlier.
+    fn flush_caster(&mut self, event_loop: &mut EventLoop<Termcastd>, token: Token) {
+        let failed = self.casters.get_mut(&token).map(|caster| caster.flush_reply().is_err()).unwrap_or(false);
+        if failed {
+            self.handle_disconnect(event_loop, token, DisconnectReason::IoError);
+        }
+    }
+
+    /// Disconnect watchers whose output couldn't be written, such as those too far behind.
+    fn disconnect_failed_watchers(&mut self, event_loop: &mut EventLoop<Termcastd>, failed: Vec<Token>) {
+        for token in failed {
+            self.handle_disconnect(event_loop, token, DisconnectReason::IoError);
+        }
+    }
 
-                return Err(watchers);
+    /// Start the timer for the caster's oldest delayed input, if there is any.
+    fn schedule_release(event_loop: &mut EventLoop<Termcastd>, caster: &mut Caster) {
+        if let Some(delay) = caster.release_delay() {
+            let timeout = TermcastdTimeout::Release(caster.token());
+            if let Ok(timeout) = event_loop.timeout_ms(timeout, delay) {
+                caster.set_release_timeout(timeout);
             }
         }
+    }
+
+    // Section for Watcher functions.
+    ////////////////////////////////////
+    fn new_watcher(&mut self, event_loop: &mut EventLoop<Termcastd>, sock: TcpStream,
+                   protocol: WatcherProtocol) -> Result<(), Error> {
+        let token = self.next_token();
+        try!(event_loop.register_opt(
+            &sock,
+            token,
+            EventSet::all(),
+            PollOpt::edge(),
+        ));
+
+        // When browsers share this port, wait to see what the client sends first.
+        let timeout = if self.watcher_http {
+            event_loop.timeout_ms(TermcastdTimeout::Sniff(token), SNIFF_TIMEOUT_MS).ok()
+        }
         else {
-            // Got an event for a token with no matching socket.
-            return Err(Vec::new());
+            None
+        };
+        info!("{} event=connect kind=watcher", Conn(token, sock.peer_addr().ok()));
+        self.clients.insert(token, Client::Unknown);
+        self.unknown.insert(token, UnknownConnection {
+            sock: sock,
+            protocol: protocol,
+            input: Vec::new(),
+            timeout: timeout,
+        });
+        if !self.watcher_http {
+            self.start_watcher(event_loop, token, false);
         }
         Ok(())
     }
 
-    // Section for Watcher functions.
-    ////////////////////////////////////
-    fn new_watcher(&mut self, event_loop: &mut EventLoop<Termcastd>) -> Result<(), Error> {
-        match self.listen_watcher.accept() {
-            Ok(Some(sock)) => {
-                let token = self.next_token();
-                let watcher = Watcher::new(token, sock);
-
-                try!(event_loop.register_opt(
-                    watcher.sock(),
-                    token,
-                    EventSet::all(),
-                    PollOpt::edge(),
-                ));
-
-                self.clients.insert(token, Client::Watcher);
-                self.watchers.insert(token, watcher);
-
-                let menu_view = self.menu_view();
-
-                let watcher_init = self.watchers.get_mut(&token)
-                    .ok_or(Error::new(ErrorKind::NotFound, ""))
-                    .and_then(|w| {
-                        w.write(&term::disable_linemode())
-                            .map_err(|_err| event_loop.deregister(w.sock()))
-                            .map_err(|_| Error::new(ErrorKind::Other, ""))
-                            .map(|_| w)
-                    })
-                    .and_then(|w| {
-                        w.write(&term::disable_local_echo())
-                            .map_err(|_err| event_loop.deregister(w.sock()))
-                            .map_err(|_| Error::new(ErrorKind::Other, ""))
-                            .map(|_| w)
-                    })
-                    .and_then(|w| {
-                        w.state = WatcherState::MainMenu;
-                        w.send_menu(&menu_view)
-                            .map_err(|_err| event_loop.deregister(w.sock()))
-                            .map_err(|_| Error::new(ErrorKind::Other, ""))
-                            .map(|_| w)
-                    });
+    /// Read what a new connection on the watcher port has sent, and hand it off once it's clear
+    /// whether it is a browser.
+    fn read_unknown(&mut self, event_loop: &mut EventLoop<Termcastd>, token: Token) {
+        let (closed, is_http) = match self.unknown.get_mut(&token) {
+            Some(connection) => {
+                let mut buf = [0; 128];
+                let mut closed = false;
+                loop {
+                    match connection.sock.read(&mut buf) {
+                        Ok(0) => {
+                            closed = true;
+                            break;
+                        },
+                        Ok(num_bytes) => connection.input.extend_from_slice(&buf[..num_bytes]),
+                        Err(_) => break,
+                    }
+                }
+                (closed, web::looks_like_http(&connection.input))
+            },
+            None => return,
+        };
 
-                // Success does not need to return anything.
-                watcher_init.map(|_| ())
-            }
-            Ok(None) => {
-                Ok(())
+        if closed {
+            self.handle_disconnect(event_loop, token, DisconnectReason::Hangup);
+        }
+        else if let Some(is_http) = is_http {
+            self.start_watcher(event_loop, token, is_http);
+        }
+    }
+
+    /// Turn a connection on the watcher port into a web or ordinary watcher.
+    fn start_watcher(&mut self, event_loop: &mut EventLoop<Termcastd>, token: Token, is_http: bool) {
+        let connection = match self.unknown.remove(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        if let Some(timeout) = connection.timeout {
+            event_loop.clear_timeout(timeout);
+        }
+        let peer = connection.sock.peer_addr().ok();
+        let watcher = if is_http {
+            Watcher::with_web(token, connection.sock, WebSession::new())
+        }
+        else {
+            Watcher::new(token, connection.sock, connection.protocol)
+        };
+        let mut watcher = match watcher {
+            Ok(watcher) => watcher,
+            Err(_) => {
+                // The socket went with the failed watcher, which also took it out of the event
+                // loop, so there's nothing left to tear down.
+                self.clients.remove(&token);
+                self.disconnected(token, peer, "watcher", LogLevel::Info, DisconnectReason::IoError);
+                return;
             },
-            Err(err) => {
-                Err(err)
+        };
+        debug!("{} event=protocol protocol={:?}", Conn(token, peer), watcher.protocol());
+        watcher.unread(connection.input);
+        let protocol = watcher.protocol();
+        self.clients.insert(token, Client::Watcher);
+        self.watchers.insert(token, watcher);
+
+        if !is_http {
+            let menu_view = self.menu_view();
+            let started = match self.watchers.get_mut(&token) {
+                Some(watcher) => watcher.negotiate().and_then(|_| {
+                    // Raw watchers first get a chance to name the caster they want.
+                    if protocol == WatcherProtocol::Raw {
+                        watcher.state = WatcherState::AwaitingName;
+                        return Ok(0);
+                    }
+                    watcher.state = WatcherState::MainMenu;
+                    watcher.send_menu(&menu_view)
+                }),
+                None => return,
+            };
+            if started.is_err() {
+                self.handle_disconnect(event_loop, token, DisconnectReason::IoError);
+                return;
+            }
+        }
+        self.read_watcher(event_loop, token);
+    }
+
+    fn new_ssh_watcher(&mut self, event_loop: &mut EventLoop<Termcastd>) -> Result<(), Error> {
+        let accepted = match self.listen_ssh {
+            Some(ref listener) => try!(listener.accept()),
+            None => None,
+        };
+        let (sock, host_key) = match (accepted, self.ssh_host_key.clone()) {
+            (Some(sock), Some(host_key)) => (sock, host_key),
+            _ => return Ok(()),
+        };
+
+        let token = self.next_token();
+        let session = SshSession::new(host_key, self.ssh_password.clone());
+        let mut watcher = try!(Watcher::with_ssh(token, sock, session));
+        try!(event_loop.register_opt(
+            watcher.sock(),
+            token,
+            EventSet::all(),
+            PollOpt::edge(),
+        ));
+
+        // The menu waits until the client has asked for a shell.
+        if let Err(err) = watcher.negotiate() {
+            let _ = event_loop.deregister(watcher.sock());
+            return Err(err);
+        }
+
+        info!("{} event=connect kind=ssh_watcher", Conn(token, watcher.sock().peer_addr().ok()));
+        self.clients.insert(token, Client::Watcher);
+        self.watchers.insert(token, watcher);
+        Ok(())
+    }
+
+    fn new_web_watcher(&mut self, event_loop: &mut EventLoop<Termcastd>) -> Result<(), Error> {
+        let sock = match self.listen_web {
+            Some(ref listener) => try!(listener.accept()),
+            None => None,
+        };
+        let sock = match sock {
+            Some(sock) => sock,
+            None => return Ok(()),
+        };
+
+        // The menu waits until the browser has opened the WebSocket.
+        let token = self.next_token();
+        let watcher = try!(Watcher::with_web(token, sock, WebSession::new()));
+        try!(event_loop.register_opt(
+            watcher.sock(),
+            token,
+            EventSet::all(),
+            PollOpt::edge(),
+        ));
+
+        info!("{} event=connect kind=web_watcher", Conn(token, watcher.sock().peer_addr().ok()));
+        self.clients.insert(token, Client::Watcher);
+        self.watchers.insert(token, watcher);
+        Ok(())
+    }
+
+    fn new_http_client(&mut self, event_loop: &mut EventLoop<Termcastd>, endpoint: HttpEndpoint) {
+        let listener = match endpoint {
+            HttpEndpoint::Metrics => self.listen_metrics.as_ref(),
+            HttpEndpoint::Status => self.listen_status.as_ref(),
+        };
+        let accepted = match listener {
+            Some(listener) => listener.accept(),
+            None => return,
+        };
+        if let Ok(Some(sock)) = accepted {
+            let token = self.next_token();
+            let res = event_loop.register_opt(&sock, token, EventSet::readable(), PollOpt::edge());
+            if res.is_ok() {
+                debug!("{} event=connect kind=http", Conn(token, sock.peer_addr().ok()));
+                self.clients.insert(token, Client::Http);
+                self.http_connections.insert(token, HttpConnection {
+                    sock: sock,
+                    input: Vec::new(),
+                    endpoint: endpoint,
+                });
+            }
+        }
+    }
+
+    /// Read a request for the status API or metrics and answer it once it has all arrived.
+    fn read_http(&mut self, event_loop: &mut EventLoop<Termcastd>, token: Token) {
+        let (request, endpoint) = match self.http_connections.get_mut(&token) {
+            Some(connection) => {
+                let mut buf = [0; 1024];
+                let mut closed = false;
+                loop {
+                    match connection.sock.read(&mut buf) {
+                        Ok(0) => {
+                            closed = true;
+                            break;
+                        },
+                        Ok(num_bytes) => connection.input.extend_from_slice(&buf[..num_bytes]),
+                        Err(_) => break,
+                    }
+                }
+                let request = match Request::read(&mut connection.input) {
+                    Ok(None) if !closed => return,
+                    Ok(request) => request,
+                    Err(_) => None,
+                };
+                (request, connection.endpoint)
             },
+            None => return,
+        };
+
+        if let Some(request) = request {
+            let response = if request.method != "GET" {
+                web::response("405 Method Not Allowed", "text/plain", "Method not allowed.\n")
+            }
+            else {
+                match (endpoint, &request.path[..]) {
+                    (HttpEndpoint::Status, "/") | (HttpEndpoint::Status, "/status") => {
+                        web::response("200 OK", "application/json", &self.status_json())
+                    },
+                    (HttpEndpoint::Metrics, "/") | (HttpEndpoint::Metrics, "/metrics") => {
+                        web::response("200 OK", "text/plain; version=0.0.4", &self.metrics_text())
+                    },
+                    _ => web::response("404 Not Found", "text/plain", "Not found.\n"),
+                }
+            };
+            if let Some(connection) = self.http_connections.get_mut(&token) {
+                let _ = connection.sock.write_all(&response);
+            }
+        }
+        self.handle_disconnect(event_loop, token, DisconnectReason::Quit);
+    }
+
+    /// Send a watcher whatever output their socket couldn't take earlier.
+    fn flush_watcher(&mut self, event_loop: &mut EventLoop<Termcastd>, token: Token) {
+        let mut failed = false;
+        if let Some(watcher) = self.watchers.get_mut(&token) {
+            failed = watcher.flush_output().is_err();
+            self.metrics.short_writes(watcher.take_short_writes());
+        }
+        if failed {
+            self.handle_disconnect(event_loop, token, DisconnectReason::IoError);
+        }
+        if self.shutting_down && self.watchers.values().all(|w| w.output_flushed()) {
+            event_loop.shutdown();
         }
     }
 
     /// Wrapper function for when the casters structure needs to be modified.
-    fn read_watcher(&mut self, token: Token) {
-        match self.watcher_input(token) {
-            Ok(WatcherAction::Exit) => {
-                let _ = self.watchers.remove(&token);
-            },
-            Ok(_) => {},
-            Err(_) => {}
+    fn read_watcher(&mut self, event_loop: &mut EventLoop<Termcastd>, token: Token) {
+        let action = self.watcher_input(event_loop, token);
+        if let Some(watcher) = self.watchers.get(&token) {
+            self.metrics.short_writes(watcher.take_short_writes());
+        }
+        if let Ok(WatcherAction::Exit(reason)) = action {
+            self.handle_disconnect(event_loop, token, reason);
         }
     }
 
     /// Handle actions affecting the watcher in this method. Actions that affect casters will be
     /// sent up the call chain.
-    fn watcher_input(&mut self, token: Token) -> Result<WatcherAction, ()> {
+    fn watcher_input(&mut self, event_loop: &mut EventLoop<Termcastd>, token: Token) -> Result<WatcherAction, ()> {
         let menu_view = self.menu_view();
         if let Some(mut watcher) = self.watchers.get_mut(&token) {
             loop {
@@ -354,44 +1077,140 @@ impl Termcastd {
                     // refresh the menu for that watcher.
                     WatcherAction::Watch(offset) => {
                         let caster_token = menu_view.get_offset_token(offset);
-                        if caster_token.is_none() {
-                            watcher.send_menu(&menu_view);
-                            continue;
+                        if !Termcastd::start_watching(&mut self.casters, watcher, caster_token, &mut self.metrics) {
+                            let _ = watcher.send_menu(&menu_view);
                         }
-                        let caster = self.casters.get_mut(&caster_token.unwrap());
-                        if caster.is_none() {
-                            // Huh...
-                            // TODO: Add log statement here.
-                            watcher.send_menu(&menu_view);
-                            continue;
+                    },
+                    WatcherAction::WatchName(name) => {
+                        let caster_token = menu_view.get_name_token(&name);
+                        if !Termcastd::start_watching(&mut self.casters, watcher, caster_token, &mut self.metrics) {
+                            let _ = watcher.send_message(&format!("No caster named {}.\r\n", name));
+                            return Ok(WatcherAction::Exit(DisconnectReason::Quit));
                         }
-                        let caster = caster.unwrap();
-                        let watcherlite = watcher.caster_copy();
-                        if watcherlite.is_err() {
-                            watcher.send_menu(&menu_view);
+                    },
+                    WatcherAction::TimeShift(seconds) => {
+                        let now = UTC::now();
+                        let (caster_token, shown) = match watcher.state {
+                            WatcherState::Watching(caster_token) => (caster_token, None),
+                            WatcherState::TimeShifted(caster_token, position) => (caster_token, Some(position)),
+                            _ => continue,
+                        };
+                        let caster = match self.casters.get_mut(&caster_token) {
+                            Some(caster) => caster,
+                            None => continue,
+                        };
+
+                        let position = shown.unwrap_or(now) + Duration::seconds(seconds);
+                        if position >= now {
+                            let _ = caster.go_live(watcher.token(), &mut self.metrics);
+                            watcher.state = WatcherState::Watching(caster_token);
                             continue;
                         }
-                        let watcherlite = watcherlite.unwrap();
-                        caster.add_watcher(watcherlite);
-                        watcher.state = WatcherState::Watching(caster.token());
+                        // Don't step back past the start of the history.
+                        let position = match caster.oldest_history() {
+                            Some(oldest) if oldest > position => oldest,
+                            _ => position,
+                        };
+                        if let Some(output) = caster.time_shift(watcher.token(), shown.as_ref(), &position) {
+                            let _ = watcher.write(&output);
+                            watcher.state = WatcherState::TimeShifted(caster_token, position);
+                        }
+                    },
+                    WatcherAction::GoLive => {
+                        if let WatcherState::TimeShifted(caster_token, _) = watcher.state {
+                            if let Some(caster) = self.casters.get_mut(&caster_token) {
+                                let _ = caster.go_live(watcher.token(), &mut self.metrics);
+                            }
+                            watcher.state = WatcherState::Watching(caster_token);
+                        }
                     },
                     WatcherAction::StopWatching => {
-                        if let WatcherState::Watching(caster_token) = watcher.state {
+                        let watching = match watcher.state {
+                            WatcherState::Watching(caster_token) => Some(caster_token),
+                            WatcherState::TimeShifted(caster_token, _) => Some(caster_token),
+                            _ => None,
+                        };
+                        if let Some(caster_token) = watching {
                             watcher.state = WatcherState::MainMenu;
+                            let conn = Conn(watcher.token(), watcher.sock().peer_addr().ok());
                             if let Some(caster) = self.casters.get_mut(&caster_token) {
                                 caster.remove_watcher(watcher.token());
+                                info!("{} event=unwatch caster={}", conn, caster.name().unwrap_or("-"));
                             }
                             else {
-                                // Huh...
-                                // TODO: Add log statement here.
+                                warn!("{} event=unwatch caster_token={} reason=no_such_caster", conn,
+                                      caster_token.as_usize());
                                 continue;
                             }
                             // FIXME: This is a now stale menu view.
                             watcher.send_menu(&menu_view);
                         }
                     },
-                    WatcherAction::Exit => {
-                        return Ok(WatcherAction::Exit);
+                    WatcherAction::ShowRecordings => {
+                        let recordings_view = RecordingsView::new(self.playback_directory.as_ref());
+                        let _ = watcher.send_recordings(recordings_view);
+                    },
+                    WatcherAction::Play(offset) => {
+                        let playback_directory = self.playback_directory.as_ref();
+                        let playback = watcher.recording_file_name(offset)
+                            .and_then(|file_name| playback_directory.map(|d| d.join(file_name)))
+                            .and_then(|path| Playback::open(&path).ok());
+                        if let Some(playback) = playback {
+                            let (clear, reset) = (watcher.terminal().clear_screen(), watcher.terminal().reset_cursor());
+                            let _ = watcher.write(clear.as_bytes());
+                            let _ = watcher.write(reset.as_bytes());
+                            watcher.playback = Some(playback);
+                            watcher.state = WatcherState::Playback;
+                            Termcastd::schedule_playback(event_loop, watcher);
+                        }
+                        else {
+                            let _ = watcher.resend_recordings();
+                        }
+                    },
+                    WatcherAction::Playback(command) => {
+                        let terminal = watcher.terminal().clone();
+                        let output = watcher.playback.as_mut().map(|playback| {
+                            match command {
+                                PlaybackCommand::TogglePause => playback.toggle_pause(),
+                                PlaybackCommand::Faster => playback.faster(),
+                                PlaybackCommand::Slower => playback.slower(),
+                                PlaybackCommand::Seek(offset) => return playback.seek(offset, &terminal),
+                            }
+                            Vec::new()
+                        });
+                        if let Some(output) = output {
+                            let _ = watcher.write(&output);
+                        }
+                        Termcastd::schedule_playback(event_loop, watcher);
+                    },
+                    WatcherAction::StopPlayback => {
+                        if let Some(mut playback) = watcher.playback.take() {
+                            if let Some(timeout) = playback.take_timeout() {
+                                event_loop.clear_timeout(timeout);
+                            }
+                        }
+                        watcher.state = WatcherState::RecordingsMenu;
+                        let recordings_view = RecordingsView::new(self.playback_directory.as_ref());
+                        let _ = watcher.send_recordings(recordings_view);
+                    },
+                    WatcherAction::Resized => {
+                        match watcher.state {
+                            WatcherState::MainMenu => {
+                                let _ = watcher.send_menu(&menu_view);
+                            },
+                            WatcherState::RecordingsMenu => {
+                                let _ = watcher.resend_recordings();
+                            },
+                            WatcherState::Watching(caster_token) | WatcherState::TimeShifted(caster_token, _) => {
+                                if let Some(caster) = self.casters.get_mut(&caster_token) {
+                                    caster.update_watcher_terminal(watcher.token(), watcher.terminal());
+                                }
+                            },
+                            _ => {},
+                        }
+                    },
+                    WatcherAction::Exit(reason) => {
+                        return Ok(WatcherAction::Exit(reason));
                     },
                     WatcherAction::Nothing => { break },
                 }
@@ -404,43 +1223,980 @@ impl Termcastd {
         Ok(WatcherAction::Nothing)
     }
 
-    fn reset_watcher(&mut self, token: Token) {
-        let menu_view = self.menu_view();
-        self.watchers.get_mut(&token)
-                     .and_then(|w| {
-                         w.state = WatcherState::MainMenu;
-                         w.send_menu(&menu_view).err()
-                     });
+    /// Attach the watcher to the caster. Returns false if there is no such caster.
+    fn start_watching(casters: &mut HashMap<Token, Caster>, watcher: &mut Watcher,
+                      caster_token: Option<Token>, metrics: &mut Metrics) -> bool {
+        let caster = match caster_token.and_then(|token| casters.get_mut(&token)) {
+            Some(caster) => caster,
+            None => return false,
+        };
+        let watcherlite = match watcher.caster_copy() {
+            Ok(watcherlite) => watcherlite,
+            Err(_) => return false,
+        };
+        let _ = caster.add_watcher(watcherlite, metrics);
+        info!("{} event=watch caster={}", Conn(watcher.token(), watcher.sock().peer_addr().ok()),
+              caster.name().unwrap_or("-"));
+        watcher.state = WatcherState::Watching(caster.token());
+        true
     }
-}
 
-impl Handler for Termcastd {
-    type Timeout = ();
-    type Message = TermcastdMessage;
-
-    fn ready(&mut self, event_loop: &mut EventLoop<Termcastd>, token: Token, event: EventSet) {
+    // Section for playback functions.
+    ////////////////////////////////////
+    /// Send the watcher the next frame of the recording they are playing, along with any frames
+    /// that follow without a delay, then wait for the frame after that.
+    fn playback_frame(&mut self, event_loop: &mut EventLoop<Termcastd>, token: Token) {
+        if let Some(watcher) = self.watchers.get_mut(&token) {
+            let mut frames = Vec::new();
+            if let Some(ref mut playback) = watcher.playback {
+                // This timeout has fired so there is nothing to clear.
+                let _ = playback.take_timeout();
+                while let Some(frame) = playback.next_frame() {
+                    frames.extend(frame);
+                    if playback.delay() != Some(0) {
+                        break;
+                    }
+                }
+            }
+            let _ = watcher.write(&frames);
+            self.metrics.short_writes(watcher.take_short_writes());
+            Termcastd::schedule_playback(event_loop, watcher);
+        }
+    }
+
+    /// (Re)start the timer for the watcher's next playback frame. Nothing is scheduled while
+    /// paused or once the end of the recording is reached.
+    fn schedule_playback(event_loop: &mut EventLoop<Termcastd>, watcher: &mut Watcher) {
+        let token = watcher.token();
+        if let Some(ref mut playback) = watcher.playback {
+            if let Some(timeout) = playback.take_timeout() {
+                event_loop.clear_timeout(timeout);
+            }
+            if playback.is_paused() {
+                return;
+            }
+            if let Some(delay) = playback.delay() {
+                if let Ok(timeout) = event_loop.timeout_ms(TermcastdTimeout::Playback(token), delay) {
+                    playback.set_timeout(timeout);
+                }
+            }
+        }
+    }
+
+    fn reset_watcher(&mut self, token: Token) {
+        let menu_view = self.menu_view();
+        self.watchers.get_mut(&token)
+                     .and_then(|w| {
+                         w.state = WatcherState::MainMenu;
+                         w.send_menu(&menu_view).err()
+                     });
+    }
+
+    // Section for admin functions.
+    ////////////////////////////////////
+    fn new_admin_client(&mut self, event_loop: &mut EventLoop<Termcastd>) {
+        let accepted = match self.listen_admin {
+            Some(ref listener) => listener.accept(),
+            None => return,
+        };
+        if let Ok(Some(sock)) = accepted {
+            let token = self.next_token();
+            let res = event_loop.register_opt(&sock, token, EventSet::readable(), PollOpt::edge());
+            if res.is_ok() {
+                info!("{} event=connect kind=admin", Conn(token, None));
+                self.clients.insert(token, Client::Admin);
+                self.admin_connections.insert(token, AdminConnection::new(sock));
+            }
+        }
+    }
+
+    /// Run each command the operator has sent so far, replying to each in turn.
+    fn read_admin(&mut self, event_loop: &mut EventLoop<Termcastd>, token: Token) {
+        let (lines, open, operator) = match self.admin_connections.get_mut(&token) {
+            Some(connection) => {
+                let (lines, open) = connection.read_lines();
+                (lines, open, connection.operator())
+            },
+            None => return,
+        };
+        for line in lines {
+            let result = AdminCommand::parse(&line)
+                .and_then(|command| self.admin_command(event_loop, command));
+            self.audit.record(&AuditEvent::AdminCommand {
+                command: line.trim(),
+                ok: result.is_ok(),
+                operator: operator,
+            });
+            let reply = match result {
+                Ok(output) => output + "ok\n",
+                Err(e) => format!("error: {}\n", e),
+            };
+            let res = self.admin_connections.get_mut(&token).map(|c| c.reply(&reply));
+            if let Some(Err(_)) = res {
+                self.handle_disconnect(event_loop, token, DisconnectReason::IoError);
+                return;
+            }
+        }
+        if !open {
+            self.handle_disconnect(event_loop, token, DisconnectReason::Hangup);
+        }
+    }
+
+    /// Run a command, returning what to show before the closing "ok", or why it failed.
+    /// Commands that change things are sent through the event loop's channel like any other
+    /// message, so success only means they were accepted.
+    fn admin_command(&mut self, event_loop: &mut EventLoop<Termcastd>,
+                     command: AdminCommand) -> Result<String, String> {
+        let message = match command {
+            AdminCommand::Help => return Ok(String::from(admin::HELP)),
+            AdminCommand::List => return Ok(self.admin_list()),
+            AdminCommand::Kick(target) => {
+                match self.kick_target(target) {
+                    Some(token) => TermcastdMessage::Kick(token),
+                    None => return Err(String::from("no such caster or watcher")),
+                }
+            },
+            AdminCommand::Broadcast(message) => TermcastdMessage::Broadcast(message),
+            AdminCommand::Reload => {
+                let mut output = self.reload(event_loop, "admin").join("\n");
+                if !output.is_empty() {
+                    output.push('\n');
+                }
+                return Ok(output);
+            },
+            AdminCommand::Shutdown => TermcastdMessage::Shutdown,
+            AdminCommand::Upgrade => TermcastdMessage::Upgrade,
+        };
+        match event_loop.channel().send(message) {
+            Ok(_) => Ok(String::new()),
+            Err(_) => Err(String::from("server is busy, try again")),
+        }
+    }
+
+    /// One line per caster and watcher, in the order they connected.
+    fn admin_list(&self) -> String {
+        fn peer_addr(addr: Option<SocketAddr>) -> String {
+            addr.map(|addr| addr.to_string()).unwrap_or(String::from("-"))
+        }
+
+        let mut list = String::new();
+        let mut casters: Vec<&Caster> = self.casters.values().collect();
+        casters.sort_by_key(|c| c.token().as_usize());
+        for caster in casters {
+            let line = match caster.menu_entry() {
+                Some(entry) => format!("caster {} {} {} {} watchers\n", caster.token().as_usize(),
+                                       entry.name(), peer_addr(caster.socket().peer_addr()), entry.num_watchers()),
+                None => format!("caster {} - {} logging in\n", caster.token().as_usize(),
+                                peer_addr(caster.socket().peer_addr())),
+            };
+            list.push_str(&line);
+        }
+
+        let mut watchers: Vec<&Watcher> = self.watchers.values().collect();
+        watchers.sort_by_key(|w| w.token().as_usize());
+        for watcher in watchers {
+            list.push_str(&format!("watcher {} {} {}\n", watcher.token().as_usize(),
+                                   peer_addr(watcher.sock().peer_addr().ok()), describe_state(&watcher.state)));
+        }
+        list
+    }
+
+    /// Where a caster or watcher is connected from, for logging.
+    fn peer_addr(&self, token: Token) -> Option<SocketAddr> {
+        match self.casters.get(&token) {
+            Some(caster) => caster.socket().peer_addr(),
+            None => self.watchers.get(&token).and_then(|w| w.sock().peer_addr().ok()),
+        }
+    }
+
+    fn kick_target(&self, target: KickTarget) -> Option<Token> {
+        match target {
+            KickTarget::Token(token) => {
+                let token = Token(token);
+                if self.casters.contains_key(&token) || self.watchers.contains_key(&token) {
+                    Some(token)
+                }
+                else {
+                    None
+                }
+            },
+            KickTarget::Name(name) => self.menu_view().get_name_token(&name),
+        }
+    }
+
+    /// Disconnect a caster or watcher. Watchers of a kicked caster go back to the main menu.
+    fn kick(&mut self, event_loop: &mut EventLoop<Termcastd>, token: Token) {
+        let client = self.clients.get(&token).cloned();
+        let kind = match client {
+            Some(Client::Caster) => "caster",
+            Some(Client::Watcher) => "watcher",
+            _ => return,
+        };
+        let peer = self.peer_addr(token);
+        info!("{} event=kick kind={}", Conn(token, peer), kind);
+        self.audit.record(&AuditEvent::Kick {
+            token: token,
+            kind: kind,
+            name: self.casters.get(&token).and_then(|c| c.name()),
+            peer: peer,
+        });
+        if let Some(watcher) = self.watchers.get_mut(&token) {
+            let _ = watcher.send_message("\r\nDisconnected by the operator.\r\n");
+        }
+        self.handle_disconnect(event_loop, token, DisconnectReason::Kicked);
+    }
+
+    fn broadcast_message(&mut self, message: &str) {
+        // Keep operators from sending terminal control codes by accident.
+        let message: String = message.chars().filter(|c| !c.is_control()).collect();
+        let line = format!("\r\n*** {} ***\r\n", message);
+        for watcher in self.watchers.values_mut() {
+            let _ = watcher.send_message(&line);
+        }
+    }
+
+    /// Reload the configuration and log what came of it, which is also returned.
+    fn reload(&mut self, event_loop: &mut EventLoop<Termcastd>, source: &str) -> Vec<String> {
+        info!("event=reload source={}", source);
+        let report = self.reload_config(event_loop);
+        for line in &report {
+            info!("event=reload message={:?}", line);
+        }
+        report
+    }
+
+    /// Read the configuration file again and apply what it changed. Listeners are moved by
+    /// binding the new address before closing the old one, so a listener that can't be moved
+    /// carries on as before. Returns a line for each change that needs pointing out: moved
+    /// listeners, failures and settings that only apply to new connections.
+    fn reload_config(&mut self, event_loop: &mut EventLoop<Termcastd>) -> Vec<String> {
+        let config = match self.config_file {
+            Some(ref path) => TermcastConfig::from_config(&path.to_string_lossy()),
+            None => return vec![String::from("No configuration file to reload.")],
+        };
+        let config = match config {
+            Ok(config) => config,
+            Err(e) => return vec![format!("Could not reload the configuration: {:?}.", e)],
+        };
+        for warning in &config.warnings {
+            warn!("event=config_warning message={:?}", warning);
+        }
+
+        let mut report = Vec::new();
+        self.motd = config.motd.unwrap_or(String::new());
+        self.playback_directory = config.playback;
+        self.shutdown_timeout = config.shutdown_timeout;
+        if config.record != self.record_config {
+            self.record_config = config.record;
+            report.push(String::from("Recording settings apply to casters connecting from now on."));
+        }
+        if config.delay != self.delay_config {
+            self.delay_config = config.delay;
+            report.push(String::from("Delays apply to casters connecting from now on."));
+        }
+        if config.history != self.history_config {
+            self.history_config = config.history;
+            report.push(String::from("History limits apply to casters connecting from now on."));
+        }
+        if config.audit_log.as_ref().map(|path| path.as_path()) != self.audit.path() {
+            let audit = match config.audit_log {
+                Some(ref path) => AuditLog::open(path),
+                None => Ok(AuditLog::disabled()),
+            };
+            match audit {
+                Ok(audit) => { self.audit = audit },
+                Err(e) => report.push(format!("Could not open the audit log: {}.", e)),
+            }
+        }
+        self.watcher_http = config.watcher_http;
+        // Listeners that stay but change protocol apply it to watchers connecting from now on.
+        self.raw_watcher = config.raw_watcher.clone();
+        for listener in self.listeners.values_mut().filter(|l| l.role == ListenerRole::Watcher) {
+            let protocol = if config.raw_watcher.contains(&listener.addr) {
+                WatcherProtocol::Raw
+            }
+            else if config.watcher.contains(&listener.addr) {
+                WatcherProtocol::Telnet
+            }
+            else {
+                // Going away, unless it has to be kept.
+                continue;
+            };
+            if protocol != listener.protocol {
+                listener.protocol = protocol;
+                report.push(format!("Watchers connecting to {} from now on are {} watchers.",
+                                    listener.addr, protocol_name(protocol)));
+            }
+        }
+
+        if let Some(ref ssh) = config.ssh {
+            self.ssh_password = ssh.password.clone();
+            if self.ssh_host_key_path.as_ref() != Some(&ssh.host_key) {
+                sodiumoxide::init();
+                match HostKey::load_or_generate(&ssh.host_key) {
+                    Ok(host_key) => {
+                        if self.ssh_host_key.is_some() {
+                            report.push(String::from("SSH watchers already connected keep the old host key."));
+                        }
+                        self.ssh_host_key = Some(host_key);
+                        self.ssh_host_key_path = Some(ssh.host_key.clone());
+                    },
+                    Err(e) => {
+                        report.push(format!("Could not load the SSH host key {}: {}.", ssh.host_key.display(), e));
+                    },
+                }
+            }
+        }
+        // Without a host key there's no serving SSH, so leave the listener as it is.
+        if self.ssh_host_key.is_some() {
+            move_listener(event_loop, &mut self.listen_ssh, &mut self.listen_addrs.ssh,
+                          config.ssh.map(|ssh| ssh.listen), SSH_WATCHER, "SSH", &mut report);
+        }
+
+        self.move_listeners(event_loop, ListenerRole::Caster, &config.caster, &mut report);
+        let watcher_addrs: Vec<SocketAddr> = config.watcher.iter().chain(&config.raw_watcher).cloned().collect();
+        self.move_listeners(event_loop, ListenerRole::Watcher, &watcher_addrs, &mut report);
+        move_unix_listener(event_loop, &mut self.listen_caster_socket, &mut self.listen_addrs.caster_socket,
+                           config.caster_socket, CASTER_SOCKET, "Caster", bind_unix_socket, &mut report);
+        move_listener(event_loop, &mut self.listen_web, &mut self.listen_addrs.web,
+                      config.web, WEB_WATCHER, "Web", &mut report);
+        move_listener(event_loop, &mut self.listen_web_caster, &mut self.listen_addrs.web_caster,
+                      config.web_caster, WEB_CASTER, "Web caster", &mut report);
+        move_listener(event_loop, &mut self.listen_status, &mut self.listen_addrs.status,
+                      config.status, STATUS, "Status", &mut report);
+        move_listener(event_loop, &mut self.listen_metrics, &mut self.listen_addrs.metrics,
+                      config.metrics, METRICS, "Metrics", &mut report);
+        move_unix_listener(event_loop, &mut self.listen_admin, &mut self.listen_addrs.admin,
+                           config.admin_socket, ADMIN, "Admin", bind_admin_socket, &mut report);
+        report
+    }
+
+    /// Listen for casters or watchers on the addresses newly configured for them, then stop
+    /// listening on the ones no longer configured. If any new address can't be listened on, the
+    /// ones already bound are closed again and the old listeners are all kept, so nobody loses
+    /// their way in. Old listeners on the same port are closed first when they're in the way.
+    fn move_listeners(&mut self, event_loop: &mut EventLoop<Termcastd>, role: ListenerRole,
+                      addrs: &[SocketAddr], report: &mut Vec<String>) {
+        // Listeners bound so far, and old ones closed early to make way for them, so both can be
+        // undone if an address fails.
+        let mut bound = Vec::new();
+        let mut closed = Vec::new();
+        let mut all_bound = true;
+        for addr in addrs {
+            if self.listeners.values().any(|l| l.role == role && l.addr == *addr) {
+                continue;
+            }
+            let token = self.next_token();
+            let mut socket = listen_role(event_loop, addr, token);
+            // A listener that's going away can be in the way, such as when 127.0.0.1:2300 is
+            // widened to 0.0.0.0:2300.
+            if addr_in_use(&socket) {
+                let in_the_way: Vec<Token> = self.listeners.iter()
+                    .filter(|&(_, l)| l.role == role && l.addr.port() == addr.port() &&
+                                      !addrs.contains(&l.addr))
+                    .map(|(token, _)| *token)
+                    .collect();
+                if !in_the_way.is_empty() {
+                    for old_token in in_the_way {
+                        if let Some(listener) = self.listeners.remove(&old_token) {
+                            let _ = event_loop.deregister(&listener.socket);
+                            closed.push((old_token, listener.addr, listener.protocol));
+                        }
+                    }
+                    socket = listen_role(event_loop, addr, token);
+                }
+            }
+            match socket {
+                Ok(socket) => {
+                    self.listeners.insert(token, Listener {
+                        role: role,
+                        addr: *addr,
+                        protocol: listener_protocol(role, addr, &self.raw_watcher),
+                        socket: socket,
+                    });
+                    bound.push(token);
+                },
+                Err(e) => {
+                    all_bound = false;
+                    report.push(format!("Could not listen for {}s on {}: {}.", role.name(), addr, e));
+                },
+            }
+        }
+
+        if !all_bound {
+            for token in bound {
+                if let Some(listener) = self.listeners.remove(&token) {
+                    let _ = event_loop.deregister(&listener.socket);
+                }
+            }
+            for (token, addr, protocol) in closed {
+                match listen_role(event_loop, &addr, token) {
+                    Ok(socket) => {
+                        self.listeners.insert(token, Listener {
+                            role: role,
+                            addr: addr,
+                            protocol: protocol,
+                            socket: socket,
+                        });
+                    },
+                    Err(e) => {
+                        report.push(format!("Could not listen for {}s on {} again: {}.",
+                                            role.name(), addr, e));
+                    },
+                }
+            }
+            return;
+        }
+
+        for token in bound {
+            if let Some(listener) = self.listeners.get(&token) {
+                report.push(format!("Now listening for {}s on {}.", role.name(), listener.addr));
+            }
+        }
+        for (_, addr, _) in closed {
+            report.push(format!("No longer listening for {}s on {}.", role.name(), addr));
+        }
+        let stale: Vec<Token> = self.listeners.iter()
+            .filter(|&(_, l)| l.role == role && !addrs.contains(&l.addr))
+            .map(|(token, _)| *token)
+            .collect();
+        for token in stale {
+            if let Some(listener) = self.listeners.remove(&token) {
+                let _ = event_loop.deregister(&listener.socket);
+                report.push(format!("No longer listening for {}s on {}.", role.name(), listener.addr));
+            }
+        }
+    }
+
+    // Section for signal and shutdown functions.
+    ////////////////////////////////////
+    fn read_signals(&mut self, event_loop: &mut EventLoop<Termcastd>) {
+        let signals = match self.signals {
+            Some(ref mut signals) => signals.read(),
+            None => return,
+        };
+        for signal in signals {
+            match signal {
+                libc::SIGHUP => {
+                    self.reload(event_loop, "signal");
+                },
+                libc::SIGTERM | libc::SIGINT => {
+                    // Asking twice means not waiting for the watchers.
+                    if self.shutting_down {
+                        event_loop.shutdown();
+                    }
+                    else {
+                        self.shutdown(event_loop);
+                    }
+                },
+                libc::SIGUSR2 => {
+                    self.upgrade(event_loop);
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /// Stop accepting connections, send every watcher a goodbye screen and close every caster.
+    /// The event loop keeps running until the watchers have been sent everything, or the
+    /// shutdown timeout passes. Casts still held back by a delay are recorded but never shown.
+    fn shutdown(&mut self, event_loop: &mut EventLoop<Termcastd>) {
+        if self.shutting_down {
+            return;
+        }
+        self.shutting_down = true;
+        info!("event=shutdown casters={} watchers={}", self.casters.len(), self.watchers.len());
+        self.stop_listening(event_loop);
+
+        let goodbye = goodbye_screen();
+        for watcher in self.watchers.values_mut() {
+            let _ = watcher.send_goodbye(&goodbye);
+        }
+
+        let tokens: Vec<Token> = self.clients.iter()
+            .filter(|&(_, client)| match *client { Client::Watcher => false, _ => true })
+            .map(|(token, _)| *token)
+            .collect();
+        for token in tokens {
+            self.handle_disconnect(event_loop, token, DisconnectReason::Shutdown);
+        }
+
+        if self.watchers.values().all(|w| w.output_flushed()) {
+            event_loop.shutdown();
+        }
+        else if event_loop.timeout_ms(TermcastdTimeout::Shutdown, self.shutdown_timeout * 1000).is_err() {
+            event_loop.shutdown();
+        }
+    }
+
+    /// Stop the event loop so `TermcastServer::run` returns and everything can be handed over.
+    fn upgrade(&mut self, event_loop: &mut EventLoop<Termcastd>) {
+        if self.shutting_down {
+            return;
+        }
+        info!("event=upgrade");
+        self.upgrading = true;
+        event_loop.shutdown();
+    }
+
+    /// Carry on after the new server couldn't be started. Watchers already told the server is
+    /// restarting are disconnected so they do reconnect; everyone else never noticed.
+    fn upgrade_failed(&mut self, event_loop: &mut EventLoop<Termcastd>) {
+        warn!("event=upgrade_abandoned");
+        for token in mem::replace(&mut self.told_to_reconnect, Vec::new()) {
+            self.handle_disconnect(event_loop, token, DisconnectReason::Shutdown);
+        }
+    }
+
+    /// Gather up the listeners and every connection another server can carry on with. Watchers
+    /// who can't be handed over are told to reconnect.
+    fn hand_over(&mut self) -> Handover {
+        let mut handover = Handover::new(self.next_token_id);
+        handover.listeners = self.listener_fds();
+        for caster in self.casters.values_mut() {
+            caster.flush_recording();
+            if let Some(saved) = caster.save() {
+                handover.casters.push(saved);
+            }
+        }
+
+        let goodbye = goodbye_screen();
+        for (token, watcher) in self.watchers.iter_mut() {
+            match watcher.save() {
+                Some(saved) => handover.watchers.push(saved),
+                None => {
+                    let _ = watcher.send_goodbye(&goodbye);
+                    self.told_to_reconnect.push(*token);
+                },
+            }
+        }
+
+        for (token, connection) in self.unknown.iter() {
+            handover.unknown.push((token.as_usize(), connection.sock.as_raw_fd(), connection.protocol,
+                                   connection.input.clone()));
+        }
+        handover
+    }
+
+    /// Pick up the casters and watchers handed over by the server this one replaced. Watchers
+    /// of a caster that didn't make it go back to the main menu.
+    fn restore(&mut self, event_loop: &mut EventLoop<Termcastd>, handover: Handover) {
+        for saved in handover.casters {
+            let token = Token(saved.token);
+            let mut caster = Caster::restore(saved, self.record_config.as_ref(), &self.delay_config,
+                                             &self.history_config);
+            let res = event_loop.register_opt(caster.socket(), token, EventSet::all(), PollOpt::edge());
+            if res.is_ok() {
+                Termcastd::schedule_release(event_loop, &mut caster);
+                self.clients.insert(token, Client::Caster);
+                self.casters.insert(token, caster);
+            }
+        }
+
+        let menu_view = self.menu_view();
+        for saved in handover.watchers {
+            let token = Token(saved.token);
+            let sock = unsafe { TcpStream::from_raw_fd(saved.fd) };
+            let mut watcher = match Watcher::restore(saved, sock) {
+                Ok(watcher) => watcher,
+                Err(_) => continue,
+            };
+            // Writable straight away, which sends on anything left in the backlog.
+            let res = event_loop.register_opt(watcher.sock(), token, EventSet::all(), PollOpt::edge());
+            if res.is_err() {
+                continue;
+            }
+
+            let watching = match watcher.state {
+                WatcherState::Watching(caster_token) => Some(caster_token),
+                WatcherState::TimeShifted(caster_token, _) => Some(caster_token),
+                _ => None,
+            };
+            let attached = match (watching.and_then(|t| self.casters.get_mut(&t)), watcher.caster_copy()) {
+                (Some(caster), Ok(watcherlite)) => {
+                    caster.resume_watcher(watcherlite);
+                    true
+                },
+                _ => false,
+            };
+            if watching.is_some() && !attached {
+                watcher.state = WatcherState::MainMenu;
+            }
+            // Whatever else they had up, such as a recording, is gone.
+            if let WatcherState::MainMenu = watcher.state {
+                let _ = watcher.send_menu(&menu_view);
+            }
+            self.clients.insert(token, Client::Watcher);
+            self.watchers.insert(token, watcher);
+        }
+
+        for (token, fd, protocol, input) in handover.unknown {
+            let token = Token(token);
+            let sock = unsafe { TcpStream::from_raw_fd(fd) };
+            if event_loop.register_opt(&sock, token, EventSet::all(), PollOpt::edge()).is_err() {
+                continue;
+            }
+            let timeout = event_loop.timeout_ms(TermcastdTimeout::Sniff(token), SNIFF_TIMEOUT_MS)
+                                    .ok();
+            self.clients.insert(token, Client::Unknown);
+            self.unknown.insert(token, UnknownConnection {
+                sock: sock,
+                protocol: protocol,
+                input: input,
+                timeout: timeout,
+            });
+        }
+    }
+
+    /// Every listener, named the way `InheritedListeners` expects. Caster and watcher listeners
+    /// also carry the address they were configured with, see `inherit_or_bind_role`.
+    fn listener_fds(&self) -> Vec<(String, RawFd)> {
+        let mut fds: Vec<(String, RawFd)> = self.listeners.values()
+            .map(|l| (format!("{}@{}", l.role.name(), l.addr), l.socket.as_raw_fd()))
+            .collect();
+        if let Some(ref listener) = self.listen_caster_socket {
+            fds.push((String::from("caster_socket"), listener.as_raw_fd()));
+        }
+        let listeners = [("ssh", &self.listen_ssh), ("web", &self.listen_web),
+                         ("web_caster", &self.listen_web_caster), ("status", &self.listen_status),
+                         ("metrics", &self.listen_metrics)];
+        for &(name, listener) in listeners.iter() {
+            if let Some(ref listener) = *listener {
+                fds.push((String::from(name), listener.as_raw_fd()));
+            }
+        }
+        if let Some(ref listener) = self.listen_admin {
+            fds.push((String::from("admin"), listener.as_raw_fd()));
+        }
+        fds
+    }
+
+    fn stop_listening(&mut self, event_loop: &mut EventLoop<Termcastd>) {
+        for listener in self.listeners.values() {
+            let _ = event_loop.deregister(&listener.socket);
+        }
+        let listeners = [&self.listen_ssh, &self.listen_web, &self.listen_web_caster,
+                         &self.listen_status, &self.listen_metrics];
+        for listener in listeners.iter().filter_map(|l| l.as_ref()) {
+            let _ = event_loop.deregister(listener);
+        }
+        for listener in [&self.listen_caster_socket, &self.listen_admin].iter().filter_map(|l| l.as_ref()) {
+            let _ = event_loop.deregister(listener);
+        }
+    }
+}
+
+/// Move a listener to `new` if that's not the address it was configured with, `old`, or turn it
+/// on or off. Nothing changes if the new address can't be listened on, unless the old listener
+/// was closed to make way and couldn't be put back.
+fn move_listener(event_loop: &mut EventLoop<Termcastd>, listener: &mut Option<TcpListener>,
+                 old: &mut Option<SocketAddr>, new: Option<SocketAddr>, token: Token, name: &str,
+                 report: &mut Vec<String>) {
+    if new == *old {
+        return;
+    }
+    let new_listener = match new {
+        Some(addr) => {
+            let mut bound = listen(event_loop, &addr, token);
+            // The old listener can be in the way, such as when 127.0.0.1:2300 is widened to
+            // 0.0.0.0:2300. It's closed first then, and put back if that didn't help.
+            if addr_in_use(&bound) && listener.is_some() &&
+               old.map(|old| old.port()) == Some(addr.port()) {
+                if let Some(old_listener) = listener.take() {
+                    let _ = event_loop.deregister(&old_listener);
+                }
+                bound = listen(event_loop, &addr, token);
+                if bound.is_err() {
+                    *listener = old.and_then(|old| listen(event_loop, &old, token).ok());
+                    if listener.is_none() {
+                        *old = None;
+                        report.push(format!("{} listener closed.", name));
+                    }
+                }
+            }
+            match bound {
+                Ok(new_listener) => Some(new_listener),
+                Err(e) => {
+                    report.push(format!("Could not move the {} listener to {}: {}.", name.to_lowercase(), addr, e));
+                    return;
+                },
+            }
+        },
+        None => None,
+    };
+    if let Some(ref old_listener) = *listener {
+        let _ = event_loop.deregister(old_listener);
+    }
+    *listener = new_listener;
+    *old = new;
+    report.push(match new {
+        Some(addr) => format!("{} listener moved to {}.", name, addr),
+        None => format!("{} listener closed.", name),
+    });
+}
+
+/// Like `move_listener`, for listeners on a Unix socket. The old socket file is removed.
+fn move_unix_listener(event_loop: &mut EventLoop<Termcastd>, listener: &mut Option<UnixListener>,
+                      old: &mut Option<PathBuf>, new: Option<PathBuf>, token: Token, name: &str,
+                      bind: fn(&Path) -> Result<UnixListener, Error>, report: &mut Vec<String>) {
+    if new == *old {
+        return;
+    }
+    let new_listener = match new {
+        Some(ref path) => {
+            let new_listener = bind(path).and_then(|new_listener| {
+                try!(event_loop.register(&new_listener, token));
+                Ok(new_listener)
+            });
+            match new_listener {
+                Ok(new_listener) => Some(new_listener),
+                Err(e) => {
+                    report.push(format!("Could not move the {} socket to {}: {}.", name.to_lowercase(), path.display(), e));
+                    return;
+                },
+            }
+        },
+        None => None,
+    };
+    if let Some(ref old_listener) = *listener {
+        let _ = event_loop.deregister(old_listener);
+    }
+    if let Some(ref old_path) = *old {
+        let _ = fs::remove_file(old_path);
+    }
+    report.push(match new {
+        Some(ref path) => format!("{} socket moved to {}.", name, path.display()),
+        None => format!("{} socket closed.", name),
+    });
+    *listener = new_listener;
+    *old = new;
+}
+
+fn listen(event_loop: &mut EventLoop<Termcastd>, addr: &SocketAddr, token: Token) -> Result<TcpListener, Error> {
+    let listener = try!(TcpListener::bind(addr));
+    try!(event_loop.register(&listener, token));
+    Ok(listener)
+}
+
+fn listen_role(event_loop: &mut EventLoop<Termcastd>, addr: &SocketAddr, token: Token) -> Result<TcpListener, Error> {
+    let listener = try!(bind_listener(addr));
+    try!(event_loop.register(&listener, token));
+    Ok(listener)
+}
+
+fn addr_in_use<T>(result: &Result<T, Error>) -> bool {
+    match *result {
+        Err(ref e) => e.kind() == ErrorKind::AddrInUse,
+        Ok(_) => false,
+    }
+}
+
+/// Bind a caster or watcher listener. Unlike other listeners, IPv6 ones only take IPv6 so that
+/// "[::]" and "0.0.0.0" can both be listed on the same port.
+fn bind_listener(addr: &SocketAddr) -> Result<TcpListener, Error> {
+    let sock = match *addr {
+        SocketAddr::V4(..) => try!(TcpSocket::v4()),
+        SocketAddr::V6(..) => {
+            let sock = try!(TcpSocket::v6());
+            let on: libc::c_int = 1;
+            let res = unsafe {
+                libc::setsockopt(sock.as_raw_fd(), libc::IPPROTO_IPV6, libc::IPV6_V6ONLY,
+                                 &on as *const libc::c_int as *const libc::c_void,
+                                 mem::size_of::<libc::c_int>() as libc::socklen_t)
+            };
+            if res == -1 {
+                return Err(Error::last_os_error());
+            }
+            sock
+        },
+    };
+    try!(sock.set_reuseaddr(true));
+    try!(sock.bind(addr));
+    sock.listen(1024)
+}
+
+/// Listen for casters or watchers on `addr`, taking over a socket passed in for it if there is
+/// one. A server being upgraded names each socket after the address it was configured with,
+/// while systemd only names them `name`, after the role and, for watchers, the protocol.
+fn inherit_or_bind_role(inherited: &mut InheritedListeners, role: ListenerRole,
+                        addr: &SocketAddr, name: &str) -> Result<TcpListener, Error> {
+    if let Some(listener) = try!(inherited.tcp(&format!("{}@{}", role.name(), addr))) {
+        return Ok(listener);
+    }
+    match try!(inherited.tcp(name)) {
+        Some(listener) => Ok(listener),
+        None => bind_listener(addr),
+    }
+}
+
+/// How watchers on a listener for `role` on `addr` talk, given the raw watcher addresses.
+fn listener_protocol(role: ListenerRole, addr: &SocketAddr, raw_watcher: &[SocketAddr]) -> WatcherProtocol {
+    if role == ListenerRole::Watcher && raw_watcher.contains(addr) {
+        WatcherProtocol::Raw
+    }
+    else {
+        WatcherProtocol::Telnet
+    }
+}
+
+fn protocol_name(protocol: WatcherProtocol) -> &'static str {
+    match protocol {
+        WatcherProtocol::Raw => "raw",
+        WatcherProtocol::Ssh => "SSH",
+        WatcherProtocol::Telnet => "telnet",
+        WatcherProtocol::WebSocket => "WebSocket",
+    }
+}
+
+/// Listen on a socket that was passed in, or else on the configured address if there is one.
+fn inherit_or_bind(inherited: &mut InheritedListeners, name: &str,
+                   addr: Option<&SocketAddr>) -> Result<Option<TcpListener>, Error> {
+    match try!(inherited.tcp(name)) {
+        Some(listener) => Ok(Some(listener)),
+        None => {
+            match addr {
+                Some(addr) => Ok(Some(try!(TcpListener::bind(addr)))),
+                None => Ok(None),
+            }
+        },
+    }
+}
+
+/// What watchers are left with when the server goes away.
+fn goodbye_screen() -> String {
+    format!("{}{}{}{}The server is restarting. Please reconnect in a moment.\r\n",
+            term::reset_attributes(), term::clear_screen(), term::reset_cursor(),
+            term::show_cursor())
+}
+
+/// Short description of what a watcher is doing, for the admin list.
+fn describe_state(state: &WatcherState) -> String {
+    match *state {
+        WatcherState::AwaitingName => String::from("awaiting-name"),
+        WatcherState::Connecting => String::from("connecting"),
+        WatcherState::Disconnecting => String::from("disconnecting"),
+        WatcherState::MainMenu => String::from("menu"),
+        WatcherState::RecordingsMenu => String::from("recordings"),
+        WatcherState::Playback => String::from("playback"),
+        WatcherState::TimeShifted(token, _) => format!("time-shifted {}", token.as_usize()),
+        WatcherState::Watching(token) => format!("watching {}", token.as_usize()),
+    }
+}
+
+/// Bind a Unix socket, replacing one left behind by an earlier run. A socket something still
+/// answers on belongs to a server that is running, and is left alone.
+fn bind_unix_socket(path: &Path) -> Result<UnixListener, Error> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(Error::new(ErrorKind::AddrInUse,
+                                      format!("{} is in use by a running server", path.display())));
+            }
+            try!(fs::remove_file(path));
+        }
+    }
+    UnixListener::bind(path)
+}
+
+/// Bind the admin socket. Only the owner may connect, as anyone who can connect can shut the
+/// server down. The umask keeps it private from the moment it is created rather than from when
+/// its permissions are changed.
+fn bind_admin_socket(path: &Path) -> Result<UnixListener, Error> {
+    let old_umask = unsafe { libc::umask(0o077) };
+    let listener = bind_unix_socket(path);
+    unsafe { libc::umask(old_umask) };
+    let listener = try!(listener);
+    try!(fs::set_permissions(path, fs::Permissions::from_mode(0o600)));
+    Ok(listener)
+}
+
+impl Handler for Termcastd {
+    type Timeout = TermcastdTimeout;
+    type Message = TermcastdMessage;
+
+    fn ready(&mut self, event_loop: &mut EventLoop<Termcastd>, token: Token, event: EventSet) {
         match token {
-            CASTER => {
-                self.new_caster(event_loop);
+            CASTER_SOCKET => {
+                self.new_unix_caster(event_loop);
             },
-            WATCHER => {
-                self.new_watcher(event_loop);
+            SSH_WATCHER => {
+                if let Err(e) = self.new_ssh_watcher(event_loop) {
+                    warn!("event=accept_failed kind=ssh_watcher error={:?}", e.to_string());
+                }
+            },
+            WEB_WATCHER => {
+                if let Err(e) = self.new_web_watcher(event_loop) {
+                    warn!("event=accept_failed kind=web_watcher error={:?}", e.to_string());
+                }
+            },
+            WEB_CASTER => {
+                self.new_web_caster(event_loop);
+            },
+            STATUS => {
+                self.new_http_client(event_loop, HttpEndpoint::Status);
+            },
+            METRICS => {
+                self.new_http_client(event_loop, HttpEndpoint::Metrics);
+            },
+            ADMIN => {
+                self.new_admin_client(event_loop);
+            },
+            SIGNALS => {
+                self.read_signals(event_loop);
+            },
+            _ if self.listeners.contains_key(&token) => {
+                self.accept(event_loop, token);
             },
             _ => {
-                let client = {
-                    *self.clients.get(&token).expect("Expected to find token.")
+                // Torn down earlier in this round of events.
+                let client = match self.clients.get(&token) {
+                    Some(client) => *client,
+                    None => return,
                 };
+                match (event.is_writable(), client) {
+                    (true, Client::Watcher) => self.flush_watcher(event_loop, token),
+                    (true, Client::Caster) => self.flush_caster(event_loop, token),
+                    _ => {},
+                }
+                // Only the goodbye is being sent now, and nobody who hangs up is waited on for it.
+                if self.shutting_down {
+                    if event.is_hup() || event.is_error() {
+                        let reason = if event.is_error() {
+                            DisconnectReason::IoError
+                        }
+                        else {
+                            DisconnectReason::Hangup
+                        };
+                        self.handle_disconnect(event_loop, token, reason);
+                        if self.watchers.values().all(|w| w.output_flushed()) {
+                            event_loop.shutdown();
+                        }
+                    }
+                    return;
+                }
                 match (event.is_readable(), event.is_hup(), event.is_error(), client) {
+                    // Commands sent just before hanging up still get run.
+                    (true, _, false, Client::Admin) => {
+                        self.read_admin(event_loop, token);
+                    },
                     (true, false, false, Client::Caster) => {
                         self.read_caster(event_loop, token);
                     },
                     (true, false, false, Client::Watcher) => {
-                        self.read_watcher(token);
+                        self.read_watcher(event_loop, token);
+                    },
+                    (true, false, false, Client::Http) => {
+                        self.read_http(event_loop, token);
+                    },
+                    (true, false, false, Client::Unknown) => {
+                        self.read_unknown(event_loop, token);
                     },
                     (_, true, false, _) => {
-                        self.handle_disconnect(event_loop, token);
+                        self.handle_disconnect(event_loop, token, DisconnectReason::Hangup);
+                    },
+                    (_, _, true, _) => {
+                        self.handle_disconnect(event_loop, token, DisconnectReason::IoError);
                     },
-                    (_, _, true, _) => {},
                     (false, false, false, _) => {},
                 };
             },
@@ -449,47 +2205,298 @@ impl Handler for Termcastd {
 
     fn notify(&mut self, event_loop: &mut EventLoop<Termcastd>, message: TermcastdMessage) {
         match message {
+            TermcastdMessage::Broadcast(message) => {
+                self.broadcast_message(&message);
+            },
             TermcastdMessage::CasterDisconnected(token) => {
                 self.reset_watcher(token);
             },
+            TermcastdMessage::Kick(token) => {
+                self.kick(event_loop, token);
+            },
+            TermcastdMessage::Reload => {
+                self.reload(event_loop, "message");
+            },
+            TermcastdMessage::Shutdown => {
+                self.shutdown(event_loop);
+            },
+            TermcastdMessage::Upgrade => {
+                self.upgrade(event_loop);
+            },
             TermcastdMessage::WatcherDisconnected(token) => {
-                self.handle_disconnect(event_loop, token);
+                self.handle_disconnect(event_loop, token, DisconnectReason::Hangup);
             },
             TermcastdMessage::Quit => {
                 event_loop.shutdown();
             }
         }
     }
+
+    fn timeout(&mut self, event_loop: &mut EventLoop<Termcastd>, timeout: TermcastdTimeout) {
+        match timeout {
+            TermcastdTimeout::Cleanup => {
+                self.cleanup_recordings();
+                let _ = event_loop.timeout_ms(TermcastdTimeout::Cleanup, CLEANUP_INTERVAL_MS);
+            },
+            TermcastdTimeout::Playback(token) => {
+                if !self.shutting_down {
+                    self.playback_frame(event_loop, token);
+                }
+            },
+            TermcastdTimeout::Release(token) => {
+                self.release_caster(event_loop, token);
+            },
+            TermcastdTimeout::Shutdown => {
+                // Watchers that still haven't taken everything are cut off.
+                let tokens: Vec<Token> = self.watchers.keys().cloned().collect();
+                for token in tokens {
+                    self.handle_disconnect(event_loop, token, DisconnectReason::Timeout);
+                }
+                event_loop.shutdown();
+            },
+            TermcastdTimeout::Sniff(token) => {
+                // Nothing sent yet, so it's not a browser.
+                self.start_watcher(event_loop, token, false);
+            },
+        }
+    }
 }
 
 
 impl TermcastServer {
+    /// Bind the configured listeners, or use the ones systemd passed in when socket activated.
     pub fn new(config: TermcastConfig) -> Result<Self, Error> {
-        let listen_caster = try!(TcpListener::bind(&config.caster));
-        let listen_watcher = try!(TcpListener::bind(&config.watcher));
-        let termcastd = Termcastd::new(listen_caster, listen_watcher);
+        TermcastServer::start(config, InheritedListeners::from_systemd(), FIRST_TOKEN)
+    }
+
+    /// Carry on from a server being upgraded, which sends everything over `sock`. Listeners it
+    /// didn't have, such as ones added to the configuration since, are bound as usual.
+    pub fn take_over(config: TermcastConfig, sock: RawFd) -> Result<Self, Error> {
+        let mut sock = unsafe { UnixStream::from_raw_fd(sock) };
+        let mut handover = try!(Handover::receive(&mut sock));
+        // The sender is this process's child, as the process ID was kept.
+        unsafe { libc::waitpid(handover.sender, ptr::null_mut(), 0) };
+
+        let listeners = mem::replace(&mut handover.listeners, Vec::new());
+        // Numbering carries on past the connections handed over.
+        let next_token_id = handover.next_token_id;
+        let mut server = try!(TermcastServer::start(config, InheritedListeners::new(listeners),
+                                                    next_token_id));
+        server.termcastd.restore(&mut server.event_loop, handover);
+        Ok(server)
+    }
+
+    fn start(config: TermcastConfig, mut inherited: InheritedListeners,
+             next_token_id: usize) -> Result<Self, Error> {
+        let mut listeners = Vec::new();
+        let roles = [(ListenerRole::Caster, &config.caster, WatcherProtocol::Telnet, "caster"),
+                     (ListenerRole::Watcher, &config.watcher, WatcherProtocol::Telnet, "watcher"),
+                     (ListenerRole::Watcher, &config.raw_watcher, WatcherProtocol::Raw, "raw_watcher")];
+        for &(role, ref addrs, protocol, name) in roles.iter() {
+            for addr in addrs.iter() {
+                listeners.push(Listener {
+                    role: role,
+                    addr: *addr,
+                    protocol: protocol,
+                    socket: try!(inherit_or_bind_role(&mut inherited, role, addr, name)),
+                });
+            }
+        }
+        let listen_caster_socket = match try!(inherited.unix("caster_socket")) {
+            Some(listener) => Some(listener),
+            None => {
+                match config.caster_socket {
+                    Some(ref path) => Some(try!(bind_unix_socket(path))),
+                    None => None,
+                }
+            },
+        };
+        let listen_ssh = match config.ssh {
+            Some(ref ssh_config) => {
+                sodiumoxide::init();
+                let host_key = try!(HostKey::load_or_generate(&ssh_config.host_key));
+                try!(inherit_or_bind(&mut inherited, "ssh", Some(&ssh_config.listen)))
+                    .map(|listener| (listener, host_key))
+            },
+            None => None,
+        };
+        let listen_web = try!(inherit_or_bind(&mut inherited, "web", config.web.as_ref()));
+        let listen_web_caster = try!(inherit_or_bind(&mut inherited, "web_caster", config.web_caster.as_ref()));
+        let listen_status = try!(inherit_or_bind(&mut inherited, "status", config.status.as_ref()));
+        let listen_metrics = try!(inherit_or_bind(&mut inherited, "metrics", config.metrics.as_ref()));
+        let listen_admin = match try!(inherited.unix("admin")) {
+            Some(listener) => Some(listener),
+            None => {
+                match config.admin_socket {
+                    Some(ref path) => Some(try!(bind_admin_socket(path))),
+                    None => None,
+                }
+            },
+        };
+        let audit = match config.audit_log {
+            Some(ref path) => try!(AuditLog::open(path)),
+            None => AuditLog::disabled(),
+        };
+        let termcastd = Termcastd::new(listeners, listen_caster_socket, listen_ssh, listen_web,
+                                       listen_web_caster, listen_status, listen_metrics,
+                                       listen_admin, audit, &config, next_token_id);
         let mut event_loop = EventLoop::new().unwrap();
-        event_loop.register(&termcastd.listen_caster, CASTER).unwrap();
-        event_loop.register(&termcastd.listen_watcher, WATCHER).unwrap();
+        for (token, listener) in termcastd.listeners.iter() {
+            event_loop.register(&listener.socket, *token).unwrap();
+        }
+        if let Some(ref listen_caster_socket) = termcastd.listen_caster_socket {
+            event_loop.register(listen_caster_socket, CASTER_SOCKET).unwrap();
+        }
+        if let Some(ref listen_ssh) = termcastd.listen_ssh {
+            event_loop.register(listen_ssh, SSH_WATCHER).unwrap();
+        }
+        if let Some(ref listen_web) = termcastd.listen_web {
+            event_loop.register(listen_web, WEB_WATCHER).unwrap();
+        }
+        if let Some(ref listen_web_caster) = termcastd.listen_web_caster {
+            event_loop.register(listen_web_caster, WEB_CASTER).unwrap();
+        }
+        if let Some(ref listen_status) = termcastd.listen_status {
+            event_loop.register(listen_status, STATUS).unwrap();
+        }
+        if let Some(ref listen_metrics) = termcastd.listen_metrics {
+            event_loop.register(listen_metrics, METRICS).unwrap();
+        }
+        if let Some(ref listen_admin) = termcastd.listen_admin {
+            event_loop.register(listen_admin, ADMIN).unwrap();
+        }
+        event_loop.timeout_ms(TermcastdTimeout::Cleanup, 0).unwrap();
 
         Ok(TermcastServer {
             termcastd: termcastd,
             config: config,
             event_loop: event_loop,
+            handover_sender: None,
         })
     }
 
+    /// Serve until shut down, or until an upgrade is asked for, see `upgrade_requested`.
     pub fn run(&mut self) {
+        if let Some(pid) = self.handover_sender.take() {
+            unsafe { libc::waitpid(pid, ptr::null_mut(), 0) };
+        }
+        // Still here after stopping to upgrade, so the new server never started.
+        if self.termcastd.upgrading {
+            self.termcastd.upgrade_failed(&mut self.event_loop);
+        }
+        self.termcastd.upgrading = false;
         self.event_loop.run(&mut self.termcastd).unwrap();
+        // The new server carries on with the Unix sockets.
+        if self.termcastd.upgrading {
+            return;
+        }
+        let paths = [&self.termcastd.listen_addrs.caster_socket, &self.termcastd.listen_addrs.admin];
+        for path in paths.iter().filter_map(|p| p.as_ref()) {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    /// Whether `run` returned because of SIGUSR2 or "upgrade" on the admin socket.
+    pub fn upgrade_requested(&self) -> bool {
+        self.termcastd.upgrading
+    }
+
+    /// Start handing everything over to a new server: a child is forked to send it all over the
+    /// returned socket, which is for passing on to the new server's `take_over`. Nothing is
+    /// closed here, so if the new server can't be started this one can `run` again once the
+    /// socket is dropped.
+    pub fn hand_over(&mut self) -> Result<UnixStream, Error> {
+        let (receiver, mut sender) = try!(handover::socket_pair());
+        let mut handover = self.termcastd.hand_over();
+        match unsafe { libc::fork() } {
+            -1 => Err(Error::last_os_error()),
+            0 => {
+                drop(receiver);
+                handover.sender = unsafe { libc::getpid() };
+                let status = if handover.send(&mut sender).is_ok() { 0 } else { 1 };
+                unsafe { libc::_exit(status) }
+            },
+            pid => {
+                self.handover_sender = Some(pid);
+                Ok(receiver)
+            },
+        }
+    }
+
+    /// Shut down gracefully on SIGTERM and SIGINT, reload the configuration on SIGHUP, and stop
+    /// to be upgraded on SIGUSR2. Left to the binary so embedding the server doesn't take over
+    /// the process's signals.
+    pub fn handle_signals(&mut self) -> Result<(), Error> {
+        let signals = try!(SignalPipe::new(&[libc::SIGHUP, libc::SIGTERM, libc::SIGINT, libc::SIGUSR2]));
+        try!(self.event_loop.register(signals.reader(), SIGNALS));
+        self.termcastd.signals = Some(signals);
+        Ok(())
     }
 
     pub fn get_channel(&mut self) -> Sender<TermcastdMessage> {
         self.event_loop.channel()
     }
 
+    /// Where the first caster and watcher listeners are bound.
     pub fn get_socket_addrs(&self) -> Result<(SocketAddr, SocketAddr), Error> {
-        let caster_addr = try!(self.termcastd.listen_caster.local_addr());
-        let watcher_addr = try!(self.termcastd.listen_watcher.local_addr());
-        Ok((caster_addr, watcher_addr))
+        let caster_addr = try!(self.get_caster_addrs()).into_iter().next();
+        let watcher_addr = try!(self.get_watcher_addrs()).into_iter().next();
+        match (caster_addr, watcher_addr) {
+            (Some(caster_addr), Some(watcher_addr)) => Ok((caster_addr, watcher_addr)),
+            _ => Err(Error::new(ErrorKind::NotFound, "no caster or watcher listener")),
+        }
+    }
+
+    /// Where each caster listener is bound, in the order they were configured.
+    pub fn get_caster_addrs(&self) -> Result<Vec<SocketAddr>, Error> {
+        self.bound_addrs(ListenerRole::Caster)
+    }
+
+    /// Where each watcher listener is bound, in the order they were configured, raw ones last.
+    pub fn get_watcher_addrs(&self) -> Result<Vec<SocketAddr>, Error> {
+        self.bound_addrs(ListenerRole::Watcher)
+    }
+
+    fn bound_addrs(&self, role: ListenerRole) -> Result<Vec<SocketAddr>, Error> {
+        let mut listeners: Vec<(&Token, &Listener)> = self.termcastd.listeners.iter()
+            .filter(|&(_, l)| l.role == role)
+            .collect();
+        listeners.sort_by_key(|&(token, _)| token.as_usize());
+        listeners.iter().map(|&(_, l)| l.socket.local_addr()).collect()
+    }
+
+    pub fn get_ssh_addr(&self) -> Result<SocketAddr, Error> {
+        match self.termcastd.listen_ssh {
+            Some(ref listener) => listener.local_addr(),
+            None => Err(Error::new(ErrorKind::NotFound, "no SSH listener")),
+        }
+    }
+
+    pub fn get_web_addr(&self) -> Result<SocketAddr, Error> {
+        match self.termcastd.listen_web {
+            Some(ref listener) => listener.local_addr(),
+            None => Err(Error::new(ErrorKind::NotFound, "no web listener")),
+        }
+    }
+
+    pub fn get_status_addr(&self) -> Result<SocketAddr, Error> {
+        match self.termcastd.listen_status {
+            Some(ref listener) => listener.local_addr(),
+            None => Err(Error::new(ErrorKind::NotFound, "no status listener")),
+        }
+    }
+
+    pub fn get_metrics_addr(&self) -> Result<SocketAddr, Error> {
+        match self.termcastd.listen_metrics {
+            Some(ref listener) => listener.local_addr(),
+            None => Err(Error::new(ErrorKind::NotFound, "no metrics listener")),
+        }
+    }
+
+    pub fn get_web_caster_addr(&self) -> Result<SocketAddr, Error> {
+        match self.termcastd.listen_web_caster {
+            Some(ref listener) => listener.local_addr(),
+            None => Err(Error::new(ErrorKind::NotFound, "no web caster listener")),
+        }
     }
 }
@@ -0,0 +1,248 @@
+// Where log lines go: stderr, a file that's moved aside once it grows too big, or syslog. After
+// the time and level each line is made up of key=value pairs, such as
+//
+//     2016-03-01T12:00:00Z INFO  token=12 peer=192.0.2.1:4321 event=connect kind=caster
+//
+// so they can be searched and picked apart with the usual tools.
+
+use chrono::UTC;
+use libc;
+use log::{self, Log, LogLevel, LogLevelFilter, LogMetadata, LogRecord};
+use mio::Token;
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Error, ErrorKind, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use config::{LogConfig, LogFile, LogTarget};
+
+// Not in the libc crate yet.
+const LOG_PID: libc::c_int = 0x01;
+const LOG_ERR: libc::c_int = 3;
+const LOG_WARNING: libc::c_int = 4;
+const LOG_INFO: libc::c_int = 6;
+const LOG_DEBUG: libc::c_int = 7;
+
+extern "C" {
+    fn openlog(ident: *const libc::c_char, option: libc::c_int, facility: libc::c_int);
+    fn syslog(priority: libc::c_int, format: *const libc::c_char, ...);
+}
+
+
+/// Tags a log line with the connection it's about, as `token=12 peer=192.0.2.1:4321`. Peers on
+/// a Unix socket have no address and show up as `peer=local`.
+pub struct Conn(pub Token, pub Option<SocketAddr>);
+
+struct Logger {
+    level: LogLevelFilter,
+    output: Mutex<Output>,
+}
+
+enum Output {
+    Stderr,
+    File(RotatingFile),
+    // Holds on to the name given to openlog, which keeps using it.
+    Syslog(CString),
+}
+
+/// A log file that's moved aside to `<path>.1`, and the one before that to `<path>.2` and so on,
+/// once it reaches the maximum size.
+struct RotatingFile {
+    config: LogFile,
+    file: File,
+    size: u64,
+}
+
+
+/// Send everything logged from now on where the configuration says. Can only be done once.
+pub fn init(config: &LogConfig) -> Result<(), Error> {
+    let output = match config.target {
+        LogTarget::Stderr => Output::Stderr,
+        LogTarget::File(ref log_file) => Output::File(try!(RotatingFile::open(log_file))),
+        LogTarget::Syslog(ref facility) => {
+            let facility = match syslog_facility(facility) {
+                Some(facility) => facility,
+                None => return Err(Error::new(ErrorKind::InvalidInput,
+                                              format!("no syslog facility named {}", facility))),
+            };
+            let ident = CString::new("termcastd").unwrap();
+            unsafe { openlog(ident.as_ptr(), LOG_PID, facility) };
+            Output::Syslog(ident)
+        },
+    };
+
+    let level = config.level;
+    log::set_logger(|max_level| {
+        max_level.set(level);
+        Box::new(Logger {
+            level: level,
+            output: Mutex::new(output),
+        })
+    }).map_err(|_| Error::new(ErrorKind::AlreadyExists, "logging is already set up"))
+}
+
+fn syslog_facility(name: &str) -> Option<libc::c_int> {
+    match name {
+        "user" => Some(1 << 3),
+        "daemon" => Some(3 << 3),
+        "local0" => Some(16 << 3),
+        "local1" => Some(17 << 3),
+        "local2" => Some(18 << 3),
+        "local3" => Some(19 << 3),
+        "local4" => Some(20 << 3),
+        "local5" => Some(21 << 3),
+        "local6" => Some(22 << 3),
+        "local7" => Some(23 << 3),
+        _ => None,
+    }
+}
+
+impl fmt::Display for Conn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.1 {
+            Some(ref addr) => write!(f, "token={} peer={}", self.0.as_usize(), addr),
+            None => write!(f, "token={} peer=local", self.0.as_usize()),
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        // Other crates only get a say when something is wrong.
+        if metadata.target().starts_with("termcastd") {
+            metadata.level() <= self.level
+        }
+        else {
+            metadata.level() <= self.level && metadata.level() <= LogLevel::Warn
+        }
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut output = match self.output.lock() {
+            Ok(output) => output,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        // There's nowhere left to complain to if logging fails.
+        match *output {
+            Output::Stderr => {
+                let line = format_line(record);
+                let _ = io::stderr().write_all(line.as_bytes());
+            },
+            Output::File(ref mut file) => {
+                let _ = file.write_line(&format_line(record));
+            },
+            Output::Syslog(_) => {
+                let priority = match record.level() {
+                    LogLevel::Error => LOG_ERR,
+                    LogLevel::Warn => LOG_WARNING,
+                    LogLevel::Info => LOG_INFO,
+                    LogLevel::Debug | LogLevel::Trace => LOG_DEBUG,
+                };
+                // Syslog adds the time itself.
+                let message = format!("{}", record.args()).replace('\0', "");
+                if let Ok(message) = CString::new(message) {
+                    let format = CString::new("%s").unwrap();
+                    unsafe { syslog(priority, format.as_ptr(), message.as_ptr()) };
+                }
+            },
+        }
+    }
+}
+
+fn format_line(record: &LogRecord) -> String {
+    format!("{} {:5} {}\n", UTC::now().format("%Y-%m-%dT%H:%M:%SZ"), record.level(), record.args())
+}
+
+impl RotatingFile {
+    fn open(config: &LogFile) -> Result<Self, Error> {
+        let file = try!(open_append(&config.path));
+        let size = try!(file.metadata()).len();
+        Ok(RotatingFile {
+            config: config.clone(),
+            file: file,
+            size: size,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), Error> {
+        if let Some(max_size) = self.config.max_size {
+            if self.size > 0 && self.size + line.len() as u64 > max_size {
+                try!(self.rotate());
+            }
+        }
+        try!(self.file.write_all(line.as_bytes()));
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Move each file along one place, dropping the oldest, and start a new one.
+    fn rotate(&mut self) -> Result<(), Error> {
+        if self.config.keep == 0 {
+            try!(fs::remove_file(&self.config.path));
+        }
+        else {
+            for n in (1..self.config.keep).rev() {
+                let _ = fs::rename(self.numbered(n), self.numbered(n + 1));
+            }
+            try!(fs::rename(&self.config.path, self.numbered(1)));
+        }
+        self.file = try!(open_append(&self.config.path));
+        self.size = 0;
+        Ok(())
+    }
+
+    fn numbered(&self, n: usize) -> PathBuf {
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+}
+
+fn open_append(path: &Path) -> Result<File, Error> {
+    OpenOptions::new().append(true).create(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use config::LogFile;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::path::Path;
+    use super::RotatingFile;
+
+    fn read(path: &Path) -> Option<String> {
+        let mut contents = String::new();
+        let res = File::open(path).and_then(|mut file| file.read_to_string(&mut contents));
+        res.ok().map(|_| contents)
+    }
+
+    #[test]
+    fn rotate() {
+        let path = env::temp_dir().join("termcastd-test-rotate.log");
+        let numbered: Vec<_> = (1..4).map(|n| path.with_extension(format!("log.{}", n))).collect();
+        for old in numbered.iter().chain(Some(&path)) {
+            let _ = fs::remove_file(old);
+        }
+
+        let config = LogFile {
+            path: path.clone(),
+            max_size: Some(10),
+            keep: 2,
+        };
+        let mut file = RotatingFile::open(&config).unwrap();
+        for line in &["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_line(line).unwrap();
+        }
+        assert_eq!(read(&path), Some(String::from("fourth\n")));
+        assert_eq!(read(&numbered[0]), Some(String::from("third\n")));
+        assert_eq!(read(&numbered[1]), Some(String::from("second\n")));
+        assert_eq!(read(&numbered[2]), None, "Only two old files are kept.");
+    }
+}
@@ -1,41 +1,155 @@
 extern crate getopts;
+#[macro_use]
+extern crate log;
 extern crate termcastd;
 
 use getopts::Options;
 use std::env;
+use std::io::Error;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::process;
 use termcastd::TermcastServer;
 use termcastd::config::TermcastConfig;
+use termcastd::daemon;
+use termcastd::logger;
 
-fn get_options() -> TermcastConfig {
+// Environment variables naming the descriptors an upgraded binary is left by the old one.
+const HANDOVER_FD: &'static str = "TERMCASTD_HANDOVER_FD";
+const PIDFILE_FD: &'static str = "TERMCASTD_PIDFILE_FD";
+
+fn get_options() -> (TermcastConfig, bool) {
     let args: Vec<String> = env::args().collect();
     let mut options = Options::new();
     options.optflag("f", "foreground", "Run in the foreground.");
     options.optopt("c", "config", "Configuration file to use.", "FILE");
+    options.optopt("l", "log-level", "Log this much: error, warn, info, debug or trace.", "LEVEL");
 
     let matches = match options.parse(&args[1..]) {
         Ok(m) => m,
         Err(e) => panic!(e.to_string()),
     };
 
-    let tc_config = match matches.opt_str("c") {
+    let mut tc_config = match matches.opt_str("c") {
         Some(config_file) => {
             match TermcastConfig::from_config(&config_file) {
                 Ok(c) => c,
-                Err(_e) => TermcastConfig::default(),
+                Err(e) => {
+                    let mut c = TermcastConfig::default();
+                    c.warnings.push(format!("Could not read {}, using the defaults: {:?}.",
+                                            config_file, e));
+                    c
+                },
             }
         },
         None => TermcastConfig::default(),
     };
+    if let Some(level) = matches.opt_str("l") {
+        match level.parse() {
+            Ok(level) => { tc_config.log.level = level },
+            Err(_) => { tc_config.warnings.push(format!("Invalid log level: {}.", level)); },
+        }
+    }
 
-    return tc_config;
+    return (tc_config, matches.opt_present("f"));
 }
 
 fn main() {
-    let tc_config = get_options();
+    let (tc_config, foreground) = get_options();
+    let pidfile = tc_config.pidfile.clone();
+    let user = tc_config.user.clone();
+    let group = tc_config.group.clone();
+    if let Err(e) = logger::init(&tc_config.log) {
+        println!("Could not set up logging: {}.", e);
+        process::exit(1);
+    }
+    for warning in &tc_config.warnings {
+        warn!("event=config_warning message={:?}", warning);
+    }
 
-    if let Ok(mut termcast) = TermcastServer::new(tc_config) {
+    // Bind first so privileged ports can be used, and so binding errors still reach the terminal.
+    let handover = daemon::inherited_fd(HANDOVER_FD);
+    let started = match handover {
+        Some(fd) => TermcastServer::take_over(tc_config, fd),
+        None => TermcastServer::new(tc_config),
+    };
+    let mut termcast = match started {
+        Ok(termcast) => termcast,
+        Err(e) => {
+            error!("event=start_failed error={:?}", e.to_string());
+            process::exit(1);
+        },
+    };
+
+    // The old server already went into the background and switched user, leaving only its
+    // pidfile to carry on with.
+    let pidfile = match (handover, daemon::inherited_fd(PIDFILE_FD), pidfile) {
+        (Some(_), Some(fd), Some(ref path)) => Some(daemon::Pidfile::inherit(fd, path)),
+        (Some(_), _, _) => None,
+        (None, _, pidfile) => detach(pidfile, foreground, user, group),
+    };
+
+    info!("event=start");
+    if let Err(e) = termcast.handle_signals() {
+        error!("event=signals_failed error={:?}", e.to_string());
+    }
+    loop {
         termcast.run();
+        if !termcast.upgrade_requested() {
+            break;
+        }
+        let err = upgrade(&mut termcast, pidfile.as_ref());
+        error!("event=upgrade_failed error={:?}", err.to_string());
     }
-    else {
+}
+
+/// Switch user and group, then go into the background unless running in the foreground. Exits
+/// if any of it fails.
+fn detach(pidfile: Option<PathBuf>, foreground: bool, user: Option<String>,
+          group: Option<String>) -> Option<daemon::Pidfile> {
+    // Taken before forking so a server already running is reported on the terminal.
+    let mut pidfile = match pidfile {
+        Some(ref path) if !foreground => {
+            match daemon::Pidfile::create(path) {
+                Ok(pidfile) => Some(pidfile),
+                Err(e) => {
+                    error!("event=pidfile_failed error={:?}", e.to_string());
+                    process::exit(1);
+                },
+            }
+        },
+        _ => None,
+    };
+    // Also before forking, as nothing logged to stderr afterwards reaches the terminal. The
+    // pidfile is already open, so rewriting it still works as the new user.
+    if let Err(e) = daemon::drop_privileges(user.as_ref().map(|u| &u[..]), group.as_ref().map(|g| &g[..])) {
+        error!("event=switch_user_failed error={:?}", e.to_string());
+        process::exit(1);
+    }
+    if !foreground {
+        if let Err(e) = daemon::daemonize() {
+            error!("event=daemonize_failed error={:?}", e.to_string());
+            process::exit(1);
+        }
+        if let Some(ref mut pidfile) = pidfile {
+            if let Err(e) = pidfile.write_pid() {
+                error!("event=pidfile_failed error={:?}", e.to_string());
+            }
+        }
+    }
+    pidfile
+}
+
+/// Hand everything over to a fresh start of the binary, which takes this process's place. Only
+/// returns if that couldn't be done, in which case this server carries on.
+fn upgrade(termcast: &mut TermcastServer, pidfile: Option<&daemon::Pidfile>) -> Error {
+    let handover = match termcast.hand_over() {
+        Ok(handover) => handover,
+        Err(e) => return e,
+    };
+    let mut fds = vec![(HANDOVER_FD, handover.as_raw_fd())];
+    if let Some(pidfile) = pidfile {
+        fds.push((PIDFILE_FD, pidfile.as_raw_fd()));
     }
+    daemon::reexec(&fds)
 }
@@ -0,0 +1,171 @@
+// Counters kept as things happen, rendered in the Prometheus text format along with gauges taken
+// from the current state.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    caster_bytes_received: BTreeMap<String, u64>,
+    watcher_bytes_sent: u64,
+    auth_results: BTreeMap<&'static str, u64>,
+    disconnects: BTreeMap<&'static str, u64>,
+    dropped_writes: u64,
+    short_writes: u64,
+    replay_bytes: u64,
+    replays: u64,
+}
+
+/// How many watchers are doing what, right now.
+#[derive(Debug, Default)]
+pub struct WatcherCounts {
+    pub menu: usize,
+    pub watching: usize,
+    pub playback: usize,
+}
+
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    pub fn caster_bytes_received(&mut self, name: &str, num_bytes: usize) {
+        *self.caster_bytes_received.entry(String::from(name)).or_insert(0) += num_bytes as u64;
+    }
+
+    /// Stop reporting a caster who has gone, so every name ever used doesn't pile up. Their count
+    /// starts again from zero if they come back, which scrapers take as a counter reset.
+    pub fn caster_disconnected(&mut self, name: &str) {
+        self.caster_bytes_received.remove(name);
+    }
+
+    /// Cast data that made it to a watcher, or at least into their backlog.
+    pub fn watcher_bytes_sent(&mut self, num_bytes: usize) {
+        self.watcher_bytes_sent += num_bytes as u64;
+    }
+
+    pub fn auth_result(&mut self, result: &'static str) {
+        *self.auth_results.entry(result).or_insert(0) += 1;
+    }
+
+    /// A connection was torn down, for the given reason.
+    pub fn disconnect(&mut self, reason: &'static str) {
+        *self.disconnects.entry(reason).or_insert(0) += 1;
+    }
+
+    /// A write to a watcher failed and the output was lost.
+    pub fn dropped_write(&mut self) {
+        self.dropped_writes += 1;
+    }
+
+    /// Writes to watchers the socket only took part of, leaving the rest in their backlog.
+    pub fn short_writes(&mut self, count: u64) {
+        self.short_writes += count;
+    }
+
+    /// A watcher was sent the replay buffer.
+    pub fn replay(&mut self, num_bytes: usize) {
+        self.replay_bytes += num_bytes as u64;
+        self.replays += 1;
+    }
+
+    pub fn render(&self, casters_connected: usize, watchers: &WatcherCounts) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "termcastd_casters_connected", "gauge", "Casters currently casting.");
+        let _ = writeln!(out, "termcastd_casters_connected {}", casters_connected);
+
+        header(&mut out, "termcastd_watchers_connected", "gauge",
+               "Watchers currently connected, by what they are doing.");
+        let _ = writeln!(out, "termcastd_watchers_connected{{state=\"menu\"}} {}", watchers.menu);
+        let _ = writeln!(out, "termcastd_watchers_connected{{state=\"watching\"}} {}", watchers.watching);
+        let _ = writeln!(out, "termcastd_watchers_connected{{state=\"playback\"}} {}", watchers.playback);
+
+        header(&mut out, "termcastd_caster_bytes_received_total", "counter",
+               "Bytes of cast data received, by connected caster.");
+        for (name, num_bytes) in &self.caster_bytes_received {
+            let _ = writeln!(out, "termcastd_caster_bytes_received_total{{caster=\"{}\"}} {}",
+                             label_value(name), num_bytes);
+        }
+
+        header(&mut out, "termcastd_watcher_bytes_sent_total", "counter",
+               "Bytes of cast data sent to watchers.");
+        let _ = writeln!(out, "termcastd_watcher_bytes_sent_total {}", self.watcher_bytes_sent);
+
+        header(&mut out, "termcastd_caster_auth_total", "counter",
+               "Caster log in attempts, by result.");
+        for (result, count) in &self.auth_results {
+            let _ = writeln!(out, "termcastd_caster_auth_total{{result=\"{}\"}} {}", result, count);
+        }
+
+        header(&mut out, "termcastd_disconnects_total", "counter",
+               "Connections closed, by reason.");
+        for (reason, count) in &self.disconnects {
+            let _ = writeln!(out, "termcastd_disconnects_total{{reason=\"{}\"}} {}", reason, count);
+        }
+
+        header(&mut out, "termcastd_watcher_dropped_writes_total", "counter",
+               "Writes to watchers that failed.");
+        let _ = writeln!(out, "termcastd_watcher_dropped_writes_total {}", self.dropped_writes);
+
+        header(&mut out, "termcastd_watcher_short_writes_total", "counter",
+               "Writes to watchers that could only be partly sent right away.");
+        let _ = writeln!(out, "termcastd_watcher_short_writes_total {}", self.short_writes);
+
+        header(&mut out, "termcastd_replay_bytes", "summary",
+               "Size of the replay buffer sent to watchers when they start watching.");
+        let _ = writeln!(out, "termcastd_replay_bytes_sum {}", self.replay_bytes);
+        let _ = writeln!(out, "termcastd_replay_bytes_count {}", self.replays);
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, metric_type);
+}
+
+fn label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Metrics, WatcherCounts};
+
+    #[test]
+    fn render() {
+        let mut metrics = Metrics::new();
+        metrics.caster_bytes_received("a\"b", 10);
+        metrics.caster_bytes_received("a\"b", 5);
+        metrics.auth_result("success");
+        metrics.auth_result("invalid_login");
+        metrics.auth_result("success");
+        metrics.disconnect("hangup");
+        metrics.disconnect("kicked");
+        metrics.disconnect("hangup");
+        metrics.replay(100);
+        metrics.replay(50);
+
+        let watchers = WatcherCounts {
+            menu: 1,
+            watching: 2,
+            playback: 0,
+        };
+        let out = metrics.render(3, &watchers);
+        assert!(out.contains("# TYPE termcastd_casters_connected gauge\ntermcastd_casters_connected 3\n"));
+        assert!(out.contains("termcastd_watchers_connected{state=\"watching\"} 2\n"));
+        assert!(out.contains("termcastd_caster_bytes_received_total{caster=\"a\\\"b\"} 15\n"));
+        assert!(out.contains("termcastd_caster_auth_total{result=\"invalid_login\"} 1\n"));
+        assert!(out.contains("termcastd_caster_auth_total{result=\"success\"} 2\n"));
+        assert!(out.contains("termcastd_disconnects_total{reason=\"hangup\"} 2\n"));
+        assert!(out.contains("termcastd_disconnects_total{reason=\"kicked\"} 1\n"));
+        assert!(out.contains("termcastd_replay_bytes_sum 150\ntermcastd_replay_bytes_count 2\n"));
+
+        metrics.caster_disconnected("a\"b");
+        assert!(!metrics.render(2, &watchers).contains("caster=\"a\\\"b\""));
+    }
+}
@@ -0,0 +1,228 @@
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::io::Write;
+use std::mem;
+use mio::tcp::{Shutdown, TcpStream};
+
+use ssh::SshSession;
+use telnet;
+use web::WebSession;
+
+// Most output a slow watcher can have waiting before they are considered gone. Nothing can be
+// dropped to make room, as a compressed or encrypted stream can't be picked up again part way.
+const MAX_BACKLOG: usize = 1_000_000;
+
+
+/// Everything sent to a watcher goes through here. It is shared between the `Watcher` and any
+/// `WatcherLite` copies so there is a single place to compress output and to hold on to whatever
+/// the socket couldn't take yet.
+pub struct WatcherOutput {
+    sock: TcpStream,
+    // Telnet watchers need IAC bytes in cast data escaped; raw watchers get the bytes untouched.
+    escape: bool,
+    compressor: Option<ZlibEncoder<Vec<u8>>>,
+    // SSH watchers have everything wrapped up in channel data and encrypted.
+    ssh: Option<SshSession>,
+    // Web watchers get everything in WebSocket frames.
+    web: Option<WebSession>,
+    backlog: Vec<u8>,
+    // Set once output has been lost, after which nothing more is sent.
+    failed: bool,
+    // Sends the socket only took part of since this was last checked, for the metrics.
+    short_writes: u64,
+}
+
+impl WatcherOutput {
+    pub fn new(sock: TcpStream, escape: bool) -> Self {
+        WatcherOutput {
+            sock: sock,
+            escape: escape,
+            compressor: None,
+            ssh: None,
+            web: None,
+            backlog: Vec::new(),
+            failed: false,
+            short_writes: 0,
+        }
+    }
+
+    /// Output for a watcher connected over SSH.
+    pub fn with_ssh(sock: TcpStream, session: SshSession) -> Self {
+        let mut output = WatcherOutput::new(sock, false);
+        output.ssh = Some(session);
+        output
+    }
+
+    /// Output for a watcher connected to the web listener.
+    pub fn with_web(sock: TcpStream, session: WebSession) -> Self {
+        let mut output = WatcherOutput::new(sock, false);
+        output.web = Some(session);
+        output
+    }
+
+    /// Send bytes exactly as given. Used for menus and telnet commands.
+    pub fn write_raw(&mut self, buf: &[u8]) -> Result<(), Error> {
+        if let Some(ref mut session) = self.ssh {
+            try!(session.send_data(buf));
+        }
+        if let Some(ref mut session) = self.web {
+            session.send_data(buf);
+        }
+        if self.ssh.is_some() || self.web.is_some() {
+            return self.send_session_reply();
+        }
+
+        let output = match self.compressor {
+            Some(ref mut compressor) => {
+                try!(compressor.write_all(buf));
+                // A sync flush makes everything so far decodable by the client.
+                try!(compressor.flush());
+                mem::replace(compressor.get_mut(), Vec::new())
+            },
+            None => buf.to_vec(),
+        };
+        self.send(output)
+    }
+
+    /// Send cast data, escaping any IAC bytes in it for telnet watchers.
+    pub fn write_data(&mut self, buf: &[u8]) -> Result<(), Error> {
+        if self.escape {
+            let escaped = telnet::escape(buf);
+            self.write_raw(&escaped)
+        }
+        else {
+            self.write_raw(buf)
+        }
+    }
+
+    /// Compress everything sent from now on.
+    pub fn start_compression(&mut self) {
+        if self.compressor.is_none() {
+            self.compressor = Some(ZlibEncoder::new(Vec::new(), Compression::Default));
+        }
+    }
+
+    /// Hand bytes from an SSH watcher to their session, sending back whatever it replies with.
+    /// Returns the keys the watcher typed.
+    pub fn receive_ssh(&mut self, input: &[u8]) -> Result<Vec<u8>, Error> {
+        let keys = match self.ssh {
+            Some(ref mut session) => session.receive(input),
+            None => return Err(Error::new(ErrorKind::InvalidInput, "not an SSH watcher")),
+        };
+        try!(self.send_session_reply());
+        keys.map_err(|_| Error::new(ErrorKind::InvalidData, "SSH protocol error"))
+    }
+
+    /// Hand bytes from a web watcher to their session, sending back whatever it replies with.
+    /// Returns the keys the watcher typed.
+    pub fn receive_web(&mut self, input: &[u8]) -> Result<Vec<u8>, Error> {
+        let keys = match self.web {
+            Some(ref mut session) => session.receive(input),
+            None => return Err(Error::new(ErrorKind::InvalidInput, "not a web watcher")),
+        };
+        try!(self.send_session_reply());
+        keys.map_err(|_| Error::new(ErrorKind::InvalidData, "HTTP or WebSocket protocol error"))
+    }
+
+    pub fn ssh(&mut self) -> Option<&mut SshSession> {
+        self.ssh.as_mut()
+    }
+
+    pub fn web(&mut self) -> Option<&mut WebSession> {
+        self.web.as_mut()
+    }
+
+    /// Send whatever the SSH or web session has waiting, such as handshake replies.
+    pub fn send_session_reply(&mut self) -> Result<(), Error> {
+        let reply = match (&mut self.ssh, &mut self.web) {
+            (&mut Some(ref mut session), _) => session.take_reply(),
+            (_, &mut Some(ref mut session)) => session.take_reply(),
+            _ => return Ok(()),
+        };
+        if reply.is_empty() {
+            return Ok(());
+        }
+        self.send(reply)
+    }
+
+    /// Try again to send whatever the socket couldn't take before.
+    pub fn flush_backlog(&mut self) -> Result<(), Error> {
+        let backlog = mem::replace(&mut self.backlog, Vec::new());
+        self.send(backlog)
+    }
+
+    pub fn has_backlog(&self) -> bool {
+        !self.backlog.is_empty()
+    }
+
+    pub fn backlog(&self) -> &[u8] {
+        &self.backlog
+    }
+
+    /// Output already taken on by another server, to go out before anything else.
+    pub fn restore_backlog(&mut self, backlog: Vec<u8>) {
+        self.backlog = backlog;
+    }
+
+    /// Whether the output is anything other than plain telnet or raw bytes: compressed or part
+    /// of an SSH or WebSocket session.
+    pub fn has_session_state(&self) -> bool {
+        self.compressor.is_some() || self.ssh.is_some() || self.web.is_some()
+    }
+
+    /// How many sends have left output in the backlog since the last call.
+    pub fn take_short_writes(&mut self) -> u64 {
+        mem::replace(&mut self.short_writes, 0)
+    }
+
+    fn send(&mut self, output: Vec<u8>) -> Result<(), Error> {
+        if self.failed {
+            return Err(Error::new(ErrorKind::BrokenPipe, "watcher output already failed"));
+        }
+        // Anything already waiting has to go out first to keep the stream in order.
+        let output = if self.backlog.is_empty() {
+            output
+        }
+        else {
+            let mut backlog = mem::replace(&mut self.backlog, Vec::new());
+            backlog.extend(output);
+            backlog
+        };
+
+        let mut written = 0;
+        while written < output.len() {
+            match self.sock.write(&output[written..]) {
+                Ok(0) => return self.fail(Error::new(ErrorKind::WriteZero, "watcher closed")),
+                Ok(num_bytes) => written += num_bytes,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return self.fail(e),
+            }
+        }
+
+        if written < output.len() {
+            self.short_writes += 1;
+        }
+        if output.len() - written > MAX_BACKLOG {
+            return self.fail(Error::new(ErrorKind::Other, "watcher too far behind"));
+        }
+        self.backlog.extend_from_slice(&output[written..]);
+        Ok(())
+    }
+
+    // Give up on the watcher. Hanging up makes sure they are disconnected even if whoever was
+    // writing ignores the error.
+    fn fail(&mut self, err: Error) -> Result<(), Error> {
+        self.failed = true;
+        let _ = self.sock.shutdown(Shutdown::Both);
+        Err(err)
+    }
+}
+
+impl fmt::Debug for WatcherOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WatcherOutput {{ sock: {:?}, compressed: {}, backlog: {} }}",
+               self.sock, self.compressor.is_some(), self.backlog.len())
+    }
+}
@@ -0,0 +1,330 @@
+use chrono::{DateTime, TimeZone, UTC};
+use mio::Timeout;
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, Error, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use watcher::TerminalInfo;
+
+// Playback speed is 2^speed, limited to between 1/16 and 16 times the original speed.
+const MIN_SPEED: i32 = -4;
+const MAX_SPEED: i32 = 4;
+const TTYREC_HEADER_LEN: usize = 12;
+
+
+/// A ttyrec file and the watcher's position in it. Frames are read from the file as they are
+/// needed, so a long recording is never held in memory.
+pub struct Playback<R: Read + Seek = BufReader<File>> {
+    frames: FrameReader<R>,
+    // The frame to send next, read ahead to know when it is due. None at the end.
+    next: Option<Frame>,
+    // Time of the last frame sent.
+    current_time: u64,
+    speed: i32,
+    paused: bool,
+    timeout: Option<Timeout>,
+}
+
+struct Frame {
+    // Milliseconds since the first frame.
+    time: u64,
+    data: Vec<u8>,
+}
+
+/// Reads frames one at a time from a ttyrec file.
+struct FrameReader<R: Read + Seek> {
+    reader: R,
+    first_frame_time: Option<u64>,
+    last_time: u64,
+}
+
+#[derive(Debug)]
+pub struct RecordingEntry {
+    file_name: String,
+    size: u64,
+    modified: DateTime<UTC>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum PlaybackCommand {
+    Faster,
+    Seek(i64),
+    Slower,
+    TogglePause,
+}
+
+
+impl Playback {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file = try!(File::open(path));
+        Ok(Playback::new(BufReader::new(file)))
+    }
+}
+
+impl<R: Read + Seek> Playback<R> {
+    fn new(reader: R) -> Self {
+        let mut frames = FrameReader::new(reader);
+        Playback {
+            next: frames.next_frame(),
+            frames: frames,
+            current_time: 0,
+            speed: 0,
+            paused: false,
+            timeout: None,
+        }
+    }
+
+    /// Return the next frame to send and move past it.
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let frame = match self.next.take() {
+            Some(frame) => frame,
+            None => return None,
+        };
+        self.current_time = frame.time;
+        self.next = self.frames.next_frame();
+        Some(frame.data)
+    }
+
+    /// Milliseconds to wait, at the current speed, before sending the next frame. None once the
+    /// end of the recording has been reached.
+    pub fn delay(&self) -> Option<u64> {
+        let delay = match self.next {
+            Some(ref frame) => frame.time - self.current_time,
+            None => return None,
+        };
+        let delay = if self.speed >= 0 {
+            delay >> self.speed
+        }
+        else {
+            delay << -self.speed
+        };
+        Some(delay)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    pub fn faster(&mut self) {
+        if self.speed < MAX_SPEED {
+            self.speed += 1;
+        }
+    }
+
+    pub fn slower(&mut self) {
+        if self.speed > MIN_SPEED {
+            self.speed -= 1;
+        }
+    }
+
+    /// Move `offset` milliseconds forwards or backwards. Returns the bytes needed to bring the
+    /// watcher's terminal up to the new position. Seeking backwards has to redraw from the start
+    /// of the recording.
+    pub fn seek(&mut self, offset: i64, terminal: &TerminalInfo) -> Vec<u8> {
+        let current_time = self.current_time as i64;
+        let target = if current_time + offset > 0 { (current_time + offset) as u64 } else { 0 };
+
+        let mut output = Vec::new();
+        if offset < 0 {
+            output.extend_from_slice(terminal.clear_screen().as_bytes());
+            output.extend_from_slice(terminal.reset_cursor().as_bytes());
+            self.current_time = 0;
+            self.next = if self.frames.rewind().is_ok() { self.frames.next_frame() } else { None };
+        }
+
+        while self.next.as_ref().map(|frame| frame.time <= target).unwrap_or(false) {
+            if let Some(frame) = self.next_frame() {
+                output.extend(frame);
+            }
+        }
+
+        output
+    }
+
+    pub fn set_timeout(&mut self, timeout: Timeout) {
+        self.timeout = Some(timeout);
+    }
+
+    pub fn take_timeout(&mut self) -> Option<Timeout> {
+        self.timeout.take()
+    }
+}
+
+impl<R: Read + Seek> FrameReader<R> {
+    fn new(reader: R) -> Self {
+        FrameReader {
+            reader: reader,
+            first_frame_time: None,
+            last_time: 0,
+        }
+    }
+
+    /// Go back to the first frame.
+    fn rewind(&mut self) -> Result<(), Error> {
+        try!(self.reader.seek(SeekFrom::Start(0)));
+        self.first_frame_time = None;
+        self.last_time = 0;
+        Ok(())
+    }
+
+    /// Read the next frame. None at the end of the file, or on reaching a truncated frame, which
+    /// happens when the recording is still in progress, or on an error.
+    fn next_frame(&mut self) -> Option<Frame> {
+        fn le_u32(bytes: &[u8]) -> u32 {
+            (bytes[0] as u32) | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+        }
+
+        let mut header = [0; TTYREC_HEADER_LEN];
+        if self.reader.read_exact(&mut header).is_err() {
+            return None;
+        }
+        let sec = le_u32(&header[0..4]) as u64;
+        let usec = le_u32(&header[4..8]) as u64;
+        let len = le_u32(&header[8..12]) as u64;
+
+        // Only as much as is actually there is allocated, so a corrupt length can't use up memory.
+        let mut data = Vec::new();
+        match (&mut self.reader).take(len).read_to_end(&mut data) {
+            Ok(read) if read as u64 == len => {},
+            _ => return None,
+        }
+
+        let time = sec * 1000 + usec / 1000;
+        let start = *self.first_frame_time.get_or_insert(time);
+        // Clocks can jump backwards; never let a frame come before the previous one.
+        let time = if time > start { time - start } else { 0 };
+        if time > self.last_time {
+            self.last_time = time;
+        }
+
+        Some(Frame {
+            time: self.last_time,
+            data: data,
+        })
+    }
+}
+
+impl RecordingEntry {
+    pub fn file_name(&self) -> &String {
+        &self.file_name
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn modified(&self) -> &DateTime<UTC> {
+        &self.modified
+    }
+}
+
+/// List the ttyrec files in `directory`, newest first.
+pub fn recordings(directory: &Path) -> Result<Vec<RecordingEntry>, Error> {
+    let mut recordings = Vec::new();
+    for entry in try!(fs::read_dir(directory)) {
+        let entry = try!(entry);
+        let path = entry.path();
+        let is_ttyrec = path.extension().map(|ext| ext == "ttyrec").unwrap_or(false);
+        let file_name = entry.file_name().into_string();
+        let metadata = try!(entry.metadata());
+        if !is_ttyrec || !metadata.is_file() || file_name.is_err() {
+            continue;
+        }
+
+        let modified = try!(metadata.modified());
+        let seconds = modified.duration_since(UNIX_EPOCH)
+                              .map(|d| d.as_secs() as i64)
+                              .unwrap_or(0);
+        recordings.push(RecordingEntry {
+            file_name: file_name.unwrap(),
+            size: metadata.len(),
+            modified: UTC.timestamp(seconds, 0),
+        });
+    }
+    recordings.sort_by(|a, b| b.modified.cmp(&a.modified));
+    Ok(recordings)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use watcher::TerminalInfo;
+    use super::{FrameReader, Playback};
+
+    fn frame(sec: u32, usec: u32, data: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        for value in &[sec, usec, data.len() as u32] {
+            frame.push(*value as u8);
+            frame.push((*value >> 8) as u8);
+            frame.push((*value >> 16) as u8);
+            frame.push((*value >> 24) as u8);
+        }
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    fn playback() -> Playback<Cursor<Vec<u8>>> {
+        let mut contents = frame(100, 0, b"a");
+        contents.extend(frame(101, 500_000, b"b"));
+        contents.extend(frame(104, 0, b"c"));
+        Playback::new(Cursor::new(contents))
+    }
+
+    #[test]
+    fn parse() {
+        let mut contents = frame(100, 0, b"a");
+        contents.extend(frame(101, 500_000, b"bc"));
+        // Clock went backwards.
+        contents.extend(frame(100, 0, b""));
+        // Truncated frame.
+        contents.extend_from_slice(&frame(102, 0, b"def")[..14]);
+
+        let mut frames = FrameReader::new(Cursor::new(contents));
+        let first = frames.next_frame().unwrap();
+        assert_eq!((first.time, first.data), (0, b"a".to_vec()));
+        let second = frames.next_frame().unwrap();
+        assert_eq!((second.time, second.data), (1500, b"bc".to_vec()));
+        assert_eq!(frames.next_frame().unwrap().time, 1500);
+        assert!(frames.next_frame().is_none());
+
+        frames.rewind().unwrap();
+        assert_eq!(frames.next_frame().unwrap().data, b"a");
+    }
+
+    #[test]
+    fn timing() {
+        let mut playback = playback();
+        assert_eq!(playback.delay(), Some(0));
+        assert_eq!(playback.next_frame(), Some(b"a".to_vec()));
+        assert_eq!(playback.delay(), Some(1500));
+
+        playback.faster();
+        assert_eq!(playback.delay(), Some(750));
+        playback.slower();
+        playback.slower();
+        assert_eq!(playback.delay(), Some(3000));
+
+        assert_eq!(playback.next_frame(), Some(b"b".to_vec()));
+        assert_eq!(playback.next_frame(), Some(b"c".to_vec()));
+        assert_eq!(playback.delay(), None);
+        assert_eq!(playback.next_frame(), None);
+    }
+
+    #[test]
+    fn seek() {
+        let mut playback = playback();
+        let terminal = TerminalInfo::default();
+        assert_eq!(playback.seek(2000, &terminal), b"ab");
+        assert_eq!(playback.next_frame(), Some(b"c".to_vec()));
+
+        let output = playback.seek(-2000, &terminal);
+        assert!(output.ends_with(b"ab"));
+        assert_eq!(playback.next_frame(), Some(b"c".to_vec()));
+    }
+}
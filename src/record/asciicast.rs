@@ -0,0 +1,162 @@
+use chrono::{DateTime, UTC};
+use std::io::Error;
+use std::io::Write;
+use std::str;
+
+use json::json_string;
+
+
+/// Writes an asciicast v2 file: a JSON header line followed by one `[time, "o", data]` line per
+/// chunk of output.
+#[derive(Debug)]
+pub struct AsciicastWriter<W: Write> {
+    out: W,
+    start: DateTime<UTC>,
+    // The tail of the previous chunk when it ended partway through a UTF-8 sequence.
+    partial: Vec<u8>,
+}
+
+impl<W: Write> AsciicastWriter<W> {
+    pub fn new(mut out: W, width: u16, height: u16, start: &DateTime<UTC>, title: &str) -> Result<Self, Error> {
+        let header = format!("{{\"version\": 2, \"width\": {}, \"height\": {}, \"timestamp\": {}, \"title\": {}}}\n",
+                             width, height, start.timestamp(), json_string(title));
+        try!(out.write_all(header.as_bytes()));
+
+        Ok(AsciicastWriter {
+            out: out,
+            start: *start,
+            partial: Vec::new(),
+        })
+    }
+
+    /// Carry on with a file that already has its header, started at `start`.
+    pub fn resume(out: W, start: &DateTime<UTC>) -> Self {
+        AsciicastWriter {
+            out: out,
+            start: *start,
+            partial: Vec::new(),
+        }
+    }
+
+    pub fn write_event(&mut self, when: &DateTime<UTC>, data: &[u8]) -> Result<(), Error> {
+        let mut input = Vec::with_capacity(self.partial.len() + data.len());
+        input.extend_from_slice(&self.partial);
+        input.extend_from_slice(data);
+        self.partial.clear();
+
+        let (output, partial) = decode_utf8(&input);
+        self.partial.extend_from_slice(partial);
+        if output.is_empty() {
+            return Ok(());
+        }
+        self.write_output(when, &output)
+    }
+
+    /// Write out a sequence still left incomplete by the last chunk, as U+FFFD.
+    pub fn finish(&mut self, when: &DateTime<UTC>) -> Result<(), Error> {
+        if !self.partial.is_empty() {
+            self.partial.clear();
+            try!(self.write_output(when, "\u{fffd}"));
+        }
+        self.out.flush()
+    }
+
+    fn write_output(&mut self, when: &DateTime<UTC>, output: &str) -> Result<(), Error> {
+        let elapsed = *when - self.start;
+        let seconds = elapsed.num_microseconds()
+                             .map(|usec| usec as f64 / 1_000_000.0)
+                             .unwrap_or(elapsed.num_seconds() as f64);
+        let event = format!("[{:.6}, \"o\", {}]\n", seconds, json_string(output));
+        self.out.write_all(event.as_bytes())
+    }
+}
+
+/// Decode as much of `input` as possible. Invalid bytes are replaced with U+FFFD. An incomplete
+/// sequence at the very end is returned separately so it can be completed by the next chunk.
+fn decode_utf8(mut input: &[u8]) -> (String, &[u8]) {
+    let mut output = String::with_capacity(input.len());
+    loop {
+        match str::from_utf8(input) {
+            Ok(valid) => {
+                output.push_str(valid);
+                return (output, &[]);
+            },
+            Err(e) => {
+                let valid_up_to = e.valid_up_to();
+                output.push_str(str::from_utf8(&input[..valid_up_to]).unwrap());
+                match e.error_len() {
+                    Some(invalid_len) => {
+                        output.push('\u{fffd}');
+                        input = &input[valid_up_to + invalid_len..];
+                    },
+                    None => {
+                        return (output, &input[valid_up_to..]);
+                    },
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, UTC};
+    use std::str;
+    use super::{AsciicastWriter, decode_utf8};
+
+    #[test]
+    fn header_and_event() {
+        let mut out = Vec::new();
+        {
+            let start = UTC.timestamp(1000, 0);
+            let mut cast = AsciicastWriter::new(&mut out, 80, 24, &start, "name").unwrap();
+            let when = start + Duration::milliseconds(1500);
+            cast.write_event(&when, b"\x1b[Hhi\r\n").unwrap();
+        }
+        let out = str::from_utf8(&out).unwrap();
+        assert_eq!(out, concat!(
+            "{\"version\": 2, \"width\": 80, \"height\": 24, \"timestamp\": 1000, \"title\": \"name\"}\n",
+            "[1.500000, \"o\", \"\\u001b[Hhi\\r\\n\"]\n",
+        ));
+    }
+
+    #[test]
+    fn split_sequence() {
+        let mut out = Vec::new();
+        {
+            let start = UTC.timestamp(0, 0);
+            let mut cast = AsciicastWriter::new(&mut out, 80, 24, &start, "").unwrap();
+            // U+00E9 split across two chunks.
+            cast.write_event(&start, b"a\xc3").unwrap();
+            cast.write_event(&start, b"\xa9").unwrap();
+        }
+        let out = str::from_utf8(&out).unwrap();
+        let events: Vec<&str> = out.lines().skip(1).collect();
+        assert_eq!(events, vec!["[0.000000, \"o\", \"a\"]", "[0.000000, \"o\", \"\u{e9}\"]"]);
+    }
+
+    #[test]
+    fn finish_partial() {
+        let mut out = Vec::new();
+        {
+            let start = UTC.timestamp(0, 0);
+            let mut cast = AsciicastWriter::new(&mut out, 80, 24, &start, "").unwrap();
+            cast.write_event(&start, b"a\xc3").unwrap();
+            cast.finish(&start).unwrap();
+        }
+        let out = str::from_utf8(&out).unwrap();
+        let events: Vec<&str> = out.lines().skip(1).collect();
+        assert_eq!(events, vec!["[0.000000, \"o\", \"a\"]", "[0.000000, \"o\", \"\u{fffd}\"]"]);
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let (output, partial) = decode_utf8(b"a\xffb\xfe");
+        assert_eq!(output, "a\u{fffd}b\u{fffd}");
+        assert!(partial.is_empty());
+
+        let (output, partial) = decode_utf8(b"ab\xe2\x94");
+        assert_eq!(output, "ab");
+        assert_eq!(partial, b"\xe2\x94");
+    }
+}
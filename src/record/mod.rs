@@ -0,0 +1,277 @@
+use chrono::{DateTime, UTC};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use config::RecordConfig;
+
+mod asciicast;
+mod ttyrec;
+
+use self::asciicast::AsciicastWriter;
+use self::ttyrec::TtyrecWriter;
+
+const TTYREC_EXTENSION: &'static str = "ttyrec";
+const ASCIICAST_EXTENSION: &'static str = "cast";
+const RECENT_SECS: u64 = 10;
+
+
+/// Saves everything a caster sends to disk. Each format is written independently, so one that
+/// fails to open or write doesn't stop the other.
+#[derive(Debug)]
+pub struct Recorder {
+    ttyrec: Option<TtyrecWriter<File>>,
+    asciicast: Option<AsciicastWriter<File>>,
+    paths: Vec<PathBuf>,
+}
+
+impl Recorder {
+    /// Start a new recording for the caster `name` who connected at `connected`. `id` keeps apart
+    /// casters with the same name who connected in the same second. A recording of the same
+    /// connection already on disk, such as from before the server was upgraded, is carried on
+    /// with. Fails only if no format could be opened.
+    pub fn new(config: &RecordConfig, name: &str, connected: &DateTime<UTC>,
+               id: usize) -> Result<Self, Error> {
+        try!(fs::create_dir_all(&config.directory));
+
+        let base_name = format!("{}.{}.{}",
+                                safe_file_name(name),
+                                connected.format("%Y-%m-%d.%H%M%S"),
+                                id);
+        let mut paths = vec![recording_path(config, &base_name, TTYREC_EXTENSION)];
+        let ttyrec = open_recording(&paths[0]).map(TtyrecWriter::new);
+
+        let asciicast = if config.asciicast {
+            paths.push(recording_path(config, &base_name, ASCIICAST_EXTENSION));
+            open_recording(&paths[1]).and_then(|file| {
+                if try!(file.metadata()).len() > 0 {
+                    Ok(AsciicastWriter::resume(file, connected))
+                }
+                else {
+                    AsciicastWriter::new(file, config.width, config.height, connected, name)
+                }
+            }).map(Some)
+        }
+        else {
+            Ok(None)
+        };
+
+        match (ttyrec, asciicast) {
+            (Err(e), Ok(None)) | (Err(_), Err(e)) => Err(e),
+            (ttyrec, asciicast) => {
+                Ok(Recorder {
+                    ttyrec: ttyrec.ok(),
+                    asciicast: asciicast.unwrap_or(None),
+                    paths: paths,
+                })
+            },
+        }
+    }
+
+    /// Write `data` to every format still being recorded. A format that fails is given up on;
+    /// the error is only returned once there is nothing left to record to.
+    pub fn record(&mut self, data: &[u8]) -> Result<(), Error> {
+        let now = UTC::now();
+        let mut error = None;
+        if let Some(Err(e)) = self.ttyrec.as_mut().map(|ttyrec| ttyrec.write_frame(&now, data)) {
+            self.ttyrec = None;
+            error = Some(e);
+        }
+        if let Some(Err(e)) = self.asciicast.as_mut().map(|asciicast| asciicast.write_event(&now, data)) {
+            self.asciicast = None;
+            error = Some(e);
+        }
+        match error {
+            Some(e) if self.ttyrec.is_none() && self.asciicast.is_none() => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Make sure everything recorded so far is written out, for when the caster is going away.
+    pub fn finish(&mut self) -> Result<(), Error> {
+        let ttyrec = self.ttyrec.as_mut().map(|ttyrec| ttyrec.flush()).unwrap_or(Ok(()));
+        let asciicast = self.asciicast.as_mut()
+                            .map(|asciicast| asciicast.finish(&UTC::now()))
+                            .unwrap_or(Ok(()));
+        ttyrec.and(asciicast)
+    }
+
+    /// The files being written, which cleaning up has to leave alone.
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+}
+
+fn recording_path(config: &RecordConfig, base_name: &str, extension: &str) -> PathBuf {
+    config.directory.join(format!("{}.{}", base_name, extension))
+}
+
+fn open_recording(path: &Path) -> Result<File, Error> {
+    OpenOptions::new().append(true).create(true).open(path)
+}
+
+/// Remove recordings that are too old, then remove the oldest recordings until the total size is
+/// under the configured limit. The recordings in `open` are still being written and are left
+/// alone, as is anything changed around or after `since`, when `open` was gathered, which may have
+/// been started since. Errors are ignored; a file that can't be inspected or removed is simply
+/// left alone.
+pub fn cleanup(config: &RecordConfig, open: &[PathBuf], since: SystemTime) {
+    if config.max_age.is_none() && config.max_size.is_none() {
+        return;
+    }
+    // File times come from a coarser clock, so can be a little behind.
+    let since = since - Duration::from_secs(RECENT_SECS);
+
+    let mut recordings = match recordings(&config.directory) {
+        Ok(recordings) => recordings,
+        Err(_) => return,
+    };
+    // Still counted towards the total size.
+    let open_size: u64 = recordings.iter()
+                                   .filter(|r| open.contains(&r.0) || r.2 >= since)
+                                   .map(|r| r.1)
+                                   .sum();
+    recordings.retain(|r| !open.contains(&r.0) && r.2 < since);
+
+    if let Some(max_age) = config.max_age {
+        let max_age = Duration::from_secs(max_age * 24 * 60 * 60);
+        let now = SystemTime::now();
+        recordings.retain(|&(ref path, _size, modified)| {
+            let too_old = now.duration_since(modified)
+                             .map(|age| age > max_age)
+                             .unwrap_or(false);
+            if too_old {
+                fs::remove_file(path).is_err()
+            }
+            else {
+                true
+            }
+        });
+    }
+
+    if let Some(max_size) = config.max_size {
+        // Oldest first.
+        recordings.sort_by(|a, b| a.2.cmp(&b.2));
+        let mut total_size: u64 = open_size + recordings.iter().map(|r| r.1).sum::<u64>();
+        for &(ref path, size, _modified) in &recordings {
+            if total_size <= max_size {
+                break;
+            }
+            if fs::remove_file(path).is_ok() {
+                total_size -= size;
+            }
+        }
+    }
+}
+
+/// List the path, size, and modification time of every recording in `directory`.
+fn recordings(directory: &Path) -> Result<Vec<(PathBuf, u64, SystemTime)>, Error> {
+    let mut recordings = Vec::new();
+    for entry in try!(fs::read_dir(directory)) {
+        let entry = try!(entry);
+        let path = entry.path();
+        let is_recording = path.extension()
+                               .map(|ext| ext == TTYREC_EXTENSION || ext == ASCIICAST_EXTENSION)
+                               .unwrap_or(false);
+        if !is_recording {
+            continue;
+        }
+        let metadata = try!(entry.metadata());
+        if !metadata.is_file() {
+            continue;
+        }
+        recordings.push((path, metadata.len(), try!(metadata.modified())));
+    }
+    Ok(recordings)
+}
+
+/// Caster names can contain any printable character. Replace the ones that would let a name
+/// escape the recording directory or create a hidden file.
+fn safe_file_name(name: &str) -> String {
+    let mut safe: String = name.chars()
+        .map(|c| if c == '/' || c == '\\' { '_' } else { c })
+        .collect();
+    if safe.starts_with('.') {
+        safe.insert(0, '_');
+    }
+    safe
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, UTC};
+    use libc;
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Write;
+    use std::time::{Duration, SystemTime};
+    use config::RecordConfig;
+    use super::{Recorder, cleanup, safe_file_name};
+
+    #[test]
+    fn file_names() {
+        assert_eq!(safe_file_name("name"), "name");
+        assert_eq!(safe_file_name("a/b"), "a_b");
+        assert_eq!(safe_file_name(".."), "_..");
+        assert_eq!(safe_file_name("../x"), "_.._x");
+    }
+
+    #[test]
+    fn formats_independent() {
+        let directory = env::temp_dir().join(format!("termcastd-test-formats-{}", unsafe { libc::getpid() }));
+        let config = RecordConfig {
+            directory: directory.clone(),
+            max_size: None,
+            max_age: None,
+            asciicast: true,
+            width: 80,
+            height: 24,
+        };
+        let connected = UTC.timestamp(0, 0);
+        // A directory where the asciicast file should go makes it impossible to open.
+        let cast = directory.join("name.1970-01-01.000000.7.cast");
+        fs::create_dir_all(&cast).unwrap();
+
+        {
+            let mut recorder = Recorder::new(&config, "name", &connected, 7).unwrap();
+            recorder.record(b"hi").unwrap();
+            recorder.finish().unwrap();
+        }
+        let ttyrec = directory.join("name.1970-01-01.000000.7.ttyrec");
+        assert_eq!(fs::metadata(&ttyrec).unwrap().len(), 12 + 2);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn cleanup_skips_open() {
+        let directory = env::temp_dir().join(format!("termcastd-test-cleanup-{}", unsafe { libc::getpid() }));
+        fs::create_dir_all(&directory).unwrap();
+        let config = RecordConfig {
+            directory: directory.clone(),
+            max_size: Some(0),
+            max_age: None,
+            asciicast: false,
+            width: 80,
+            height: 24,
+        };
+        let old = directory.join("old.ttyrec");
+        let open = directory.join("open.ttyrec");
+        for path in &[&old, &open] {
+            File::create(path).unwrap().write_all(b"recording").unwrap();
+        }
+        // Could have been started after the open recordings were gathered.
+        cleanup(&config, &[open.clone()], SystemTime::now());
+        assert!(old.exists());
+        assert!(open.exists());
+
+        cleanup(&config, &[open.clone()], SystemTime::now() + Duration::from_secs(60));
+        assert!(!old.exists());
+        assert!(open.exists());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
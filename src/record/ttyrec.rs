@@ -0,0 +1,77 @@
+use chrono::{DateTime, Timelike, UTC};
+use std::io::Error;
+use std::io::Write;
+
+
+/// Writes frames in the ttyrec format. Every frame is a 12 byte header of three little-endian
+/// 32-bit integers (seconds, microseconds, length) followed by that many bytes of output.
+#[derive(Debug)]
+pub struct TtyrecWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TtyrecWriter<W> {
+    pub fn new(out: W) -> Self {
+        TtyrecWriter {
+            out: out,
+        }
+    }
+
+    pub fn write_frame(&mut self, when: &DateTime<UTC>, data: &[u8]) -> Result<(), Error> {
+        let sec = when.timestamp() as u32;
+        let usec = when.nanosecond() / 1_000;
+        let mut frame = Vec::with_capacity(12 + data.len());
+        frame.extend_from_slice(&le_bytes(sec));
+        frame.extend_from_slice(&le_bytes(usec));
+        frame.extend_from_slice(&le_bytes(data.len() as u32));
+        frame.extend_from_slice(data);
+        // Write the frame in one go so a reader never sees a header without its data.
+        self.out.write_all(&frame)
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.out.flush()
+    }
+}
+
+fn le_bytes(value: u32) -> [u8; 4] {
+    [
+        value as u8,
+        (value >> 8) as u8,
+        (value >> 16) as u8,
+        (value >> 24) as u8,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, UTC};
+    use super::TtyrecWriter;
+
+    #[test]
+    fn write_frame() {
+        let mut out = Vec::new();
+        {
+            let mut ttyrec = TtyrecWriter::new(&mut out);
+            let when = UTC.timestamp(0x01020304, 5_000);
+            ttyrec.write_frame(&when, b"hi").unwrap();
+        }
+        assert_eq!(out, vec![0x04, 0x03, 0x02, 0x01,
+                             0x05, 0x00, 0x00, 0x00,
+                             0x02, 0x00, 0x00, 0x00,
+                             b'h', b'i']);
+    }
+
+    #[test]
+    fn write_two_frames() {
+        let mut out = Vec::new();
+        {
+            let mut ttyrec = TtyrecWriter::new(&mut out);
+            let when = UTC.timestamp(1, 0);
+            ttyrec.write_frame(&when, b"a").unwrap();
+            ttyrec.write_frame(&when, b"").unwrap();
+        }
+        assert_eq!(out.len(), 13 + 12);
+        assert_eq!(&out[21..25], &[0, 0, 0, 0]);
+    }
+}
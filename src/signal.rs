@@ -0,0 +1,64 @@
+// A signal handler can safely do very little, so each signal caught is written as a byte to a
+// pipe the event loop watches and dealt with there.
+
+use libc;
+use mio::unix::{self, PipeReader, PipeWriter};
+use std::io::{Error, Read};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicIsize, Ordering, ATOMIC_ISIZE_INIT};
+
+// Write end of the pipe, for the handler.
+static SIGNAL_FD: AtomicIsize = ATOMIC_ISIZE_INIT;
+
+
+pub struct SignalPipe {
+    reader: PipeReader,
+    // Kept open for as long as signals are being caught.
+    _writer: PipeWriter,
+}
+
+impl SignalPipe {
+    /// Start catching `signals`. Only one pipe should exist at a time; the handler writes to
+    /// whichever was made last.
+    pub fn new(signals: &[libc::c_int]) -> Result<Self, Error> {
+        let (reader, writer) = try!(unix::pipe());
+        SIGNAL_FD.store(writer.as_raw_fd() as isize, Ordering::SeqCst);
+        for signal in signals {
+            let previous = unsafe { libc::signal(*signal, handle_signal as libc::sighandler_t) };
+            if previous == libc::SIG_ERR {
+                return Err(Error::last_os_error());
+            }
+        }
+
+        Ok(SignalPipe {
+            reader: reader,
+            _writer: writer,
+        })
+    }
+
+    /// Signals caught since the last call, oldest first.
+    pub fn read(&mut self) -> Vec<libc::c_int> {
+        let mut signals = Vec::new();
+        let mut buf = [0; 64];
+        loop {
+            match self.reader.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(num_bytes) => signals.extend(buf[..num_bytes].iter().map(|b| *b as libc::c_int)),
+            }
+        }
+        signals
+    }
+
+    pub fn reader(&self) -> &PipeReader {
+        &self.reader
+    }
+}
+
+extern "C" fn handle_signal(signal: libc::c_int) {
+    let fd = SIGNAL_FD.load(Ordering::SeqCst) as libc::c_int;
+    let byte = signal as u8;
+    // Nothing useful can be done if the pipe is full; the signal is already waiting there.
+    unsafe {
+        libc::write(fd, &byte as *const u8 as *const libc::c_void, 1);
+    }
+}
@@ -0,0 +1,825 @@
+// Just enough of an SSH server (RFC 4251-4254) to let watchers use an ordinary ssh client. The
+// only algorithms offered are curve25519-sha256 key exchange, ssh-ed25519 host keys and the
+// chacha20-poly1305@openssh.com cipher, all of which libsodium already provides.
+
+mod packet;
+
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::scalarmult::curve25519;
+use sodiumoxide::crypto::sign::ed25519;
+use sodiumoxide::randombytes::randombytes;
+use sodiumoxide::utils::memcmp;
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind};
+use std::io::{Read, Write};
+use std::mem;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use self::packet::{CHACHA_KEY_LENGTH, PacketReader, PacketWriter, Transport, mpint};
+
+const VERSION: &'static str = "SSH-2.0-termcastd_0.3";
+// Longest identification line a client may send, including the CR LF.
+const MAX_VERSION_LENGTH: usize = 255;
+
+const KEX_ALGORITHMS: [&'static str; 2] = ["curve25519-sha256", "curve25519-sha256@libssh.org"];
+const HOST_KEY_ALGORITHM: &'static str = "ssh-ed25519";
+const CIPHER: &'static str = "chacha20-poly1305@openssh.com";
+// Never used with an AEAD cipher, but clients expect something to agree on.
+const MAC: &'static str = "hmac-sha2-256";
+const COMPRESSION: &'static str = "none";
+
+// How much input the watcher may send before it has to wait for a window adjust.
+const LOCAL_WINDOW: u32 = 64 * 1024;
+const LOCAL_MAX_PACKET: u32 = 32 * 1024;
+// Largest CHANNEL_DATA payload we send, whatever the client allows.
+const MAX_DATA_PACKET: u32 = 32 * 1024;
+// Most channel data held back waiting for the client's window before giving up on them.
+const MAX_PENDING: usize = 1_000_000;
+// Wrong passwords allowed before the client is disconnected.
+const MAX_AUTH_FAILURES: u32 = 3;
+
+const MSG_DISCONNECT: u8 = 1;
+const MSG_IGNORE: u8 = 2;
+const MSG_UNIMPLEMENTED: u8 = 3;
+const MSG_DEBUG: u8 = 4;
+const MSG_SERVICE_REQUEST: u8 = 5;
+const MSG_SERVICE_ACCEPT: u8 = 6;
+const MSG_KEXINIT: u8 = 20;
+const MSG_NEWKEYS: u8 = 21;
+const MSG_KEX_ECDH_INIT: u8 = 30;
+const MSG_KEX_ECDH_REPLY: u8 = 31;
+const MSG_USERAUTH_REQUEST: u8 = 50;
+const MSG_USERAUTH_FAILURE: u8 = 51;
+const MSG_USERAUTH_SUCCESS: u8 = 52;
+const MSG_GLOBAL_REQUEST: u8 = 80;
+const MSG_REQUEST_FAILURE: u8 = 82;
+const MSG_CHANNEL_OPEN: u8 = 90;
+const MSG_CHANNEL_OPEN_CONFIRMATION: u8 = 91;
+const MSG_CHANNEL_OPEN_FAILURE: u8 = 92;
+const MSG_CHANNEL_WINDOW_ADJUST: u8 = 93;
+const MSG_CHANNEL_DATA: u8 = 94;
+const MSG_CHANNEL_EOF: u8 = 96;
+const MSG_CHANNEL_CLOSE: u8 = 97;
+const MSG_CHANNEL_REQUEST: u8 = 98;
+const MSG_CHANNEL_SUCCESS: u8 = 99;
+const MSG_CHANNEL_FAILURE: u8 = 100;
+
+const DISCONNECT_KEY_EXCHANGE_FAILED: u32 = 3;
+const DISCONNECT_SERVICE_NOT_AVAILABLE: u32 = 7;
+const DISCONNECT_NO_MORE_AUTH_METHODS_AVAILABLE: u32 = 14;
+const OPEN_ADMINISTRATIVELY_PROHIBITED: u32 = 1;
+
+
+/// The server's ed25519 host key.
+#[derive(Clone)]
+pub struct HostKey {
+    public: ed25519::PublicKey,
+    secret: ed25519::SecretKey,
+}
+
+/// One watcher's SSH connection. Bytes from the socket go in through `receive`, and everything
+/// that has to go back out collects in the reply buffer until `take_reply`.
+pub struct SshSession {
+    host_key: HostKey,
+    // None lets anyone in without a password.
+    password: Option<String>,
+    auth_failures: u32,
+    input: Vec<u8>,
+    reply: Vec<u8>,
+    // The client's identification line, without the CR LF, once it has been received.
+    client_version: Option<Vec<u8>>,
+    incoming: Transport,
+    outgoing: Transport,
+    // Our KEXINIT payload while a key exchange is in progress.
+    server_kexinit: Option<Vec<u8>>,
+    client_kexinit: Option<Vec<u8>>,
+    // The client guessed the wrong algorithm and its next key exchange packet must be dropped.
+    ignore_next_packet: bool,
+    // Keys from the finished key exchange, waiting for the client's NEWKEYS.
+    incoming_key: Option<Vec<u8>>,
+    session_id: Option<Vec<u8>>,
+    authenticated: bool,
+    channel: Option<Channel>,
+    // Channel data waiting for a key exchange to finish or for room in the client's window.
+    pending: Vec<u8>,
+    window_size: Option<(u16, u16)>,
+    terminal_type: Option<String>,
+    terminal_changed: bool,
+    shell_started: bool,
+    closed: bool,
+}
+
+struct Channel {
+    remote_id: u32,
+    remote_window: u32,
+    remote_max_packet: u32,
+    local_window: u32,
+}
+
+
+impl HostKey {
+    /// Read a host key from a file holding a 64 byte libsodium ed25519 secret key, creating one
+    /// if the file doesn't exist yet.
+    pub fn load_or_generate(path: &Path) -> Result<Self, Error> {
+        match File::open(path) {
+            Ok(mut file) => {
+                let mut contents = Vec::new();
+                try!(file.read_to_end(&mut contents));
+                let secret = try!(ed25519::SecretKey::from_slice(&contents)
+                                      .ok_or(Error::new(ErrorKind::InvalidData,
+                                                        "host key must be 64 bytes")));
+                let public = ed25519::PublicKey::from_slice(&contents[32..]).unwrap();
+                Ok(HostKey {
+                    public: public,
+                    secret: secret,
+                })
+            },
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                let (public, secret) = ed25519::gen_keypair();
+                let mut file = try!(OpenOptions::new().write(true).create(true).mode(0o600)
+                                                      .open(path));
+                try!(file.write_all(&secret.0));
+                Ok(HostKey {
+                    public: public,
+                    secret: secret,
+                })
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    // The public key as sent to clients.
+    fn blob(&self) -> Vec<u8> {
+        PacketWriter::new(0)
+            .string(HOST_KEY_ALGORITHM.as_bytes())
+            .string(&self.public.0)
+            .into_payload()
+            .split_off(1)
+    }
+
+    fn sign(&self, data: &[u8]) -> Vec<u8> {
+        let signature = ed25519::sign_detached(data, &self.secret);
+        PacketWriter::new(0)
+            .string(HOST_KEY_ALGORITHM.as_bytes())
+            .string(&signature.0)
+            .into_payload()
+            .split_off(1)
+    }
+}
+
+impl SshSession {
+    /// Start a session, queueing our identification and first KEXINIT. With a `password` the
+    /// client has to give it before being let in.
+    pub fn new(host_key: HostKey, password: Option<String>) -> Self {
+        let mut session = SshSession {
+            host_key: host_key,
+            password: password,
+            auth_failures: 0,
+            input: Vec::new(),
+            reply: Vec::new(),
+            client_version: None,
+            incoming: Transport::new(),
+            outgoing: Transport::new(),
+            server_kexinit: None,
+            client_kexinit: None,
+            ignore_next_packet: false,
+            incoming_key: None,
+            session_id: None,
+            authenticated: false,
+            channel: None,
+            pending: Vec::new(),
+            window_size: None,
+            terminal_type: None,
+            terminal_changed: false,
+            shell_started: false,
+            closed: false,
+        };
+        session.reply.extend_from_slice(VERSION.as_bytes());
+        session.reply.extend_from_slice(b"\r\n");
+        session.send_kexinit();
+        session
+    }
+
+    /// Process bytes from the client, returning the keys typed into the session channel. An
+    /// error means the connection is unusable and should be dropped.
+    pub fn receive(&mut self, input: &[u8]) -> Result<Vec<u8>, ()> {
+        self.input.extend_from_slice(input);
+        let mut keys = Vec::new();
+
+        if self.client_version.is_none() {
+            if !try!(self.read_version()) {
+                return Ok(keys);
+            }
+        }
+
+        while let Some(payload) = try!(self.incoming.decode(&mut self.input)) {
+            if payload.is_empty() {
+                return Err(());
+            }
+            try!(self.handle_packet(&payload, &mut keys));
+        }
+        Ok(keys)
+    }
+
+    /// Send cast data or menus to the client over the session channel.
+    pub fn send_data(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.pending.len() + data.len() > MAX_PENDING {
+            return Err(Error::new(ErrorKind::Other, "watcher too far behind"));
+        }
+        self.pending.extend_from_slice(data);
+        self.flush_pending();
+        Ok(())
+    }
+
+    /// Everything that needs to be sent to the client since the last call.
+    pub fn take_reply(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.reply, Vec::new())
+    }
+
+    /// Terminal width and height from the pty request or the last window change.
+    pub fn window_size(&self) -> Option<(u16, u16)> {
+        self.window_size
+    }
+
+    /// The TERM the client asked for with its pty, lowercased.
+    pub fn terminal_type(&self) -> Option<&str> {
+        self.terminal_type.as_ref().map(|t| &t[..])
+    }
+
+    /// Whether the window size or terminal type changed since the last call.
+    pub fn take_terminal_changed(&mut self) -> bool {
+        mem::replace(&mut self.terminal_changed, false)
+    }
+
+    /// Whether the client asked for its shell since the last call. Nothing should be shown to the
+    /// watcher before then.
+    pub fn take_shell_started(&mut self) -> bool {
+        mem::replace(&mut self.shell_started, false)
+    }
+
+    /// The client disconnected or closed its channel.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    // Look for the client's identification line. Returns false until a whole line has arrived.
+    fn read_version(&mut self) -> Result<bool, ()> {
+        let end = match self.input.iter().position(|&b| b == b'\n') {
+            Some(end) => end,
+            None if self.input.len() > MAX_VERSION_LENGTH => return Err(()),
+            None => return Ok(false),
+        };
+        let line: Vec<u8> = self.input.drain(..end + 1).collect();
+        let mut version = &line[..end];
+        if version.last() == Some(&b'\r') {
+            version = &version[..version.len() - 1];
+        }
+        if !version.starts_with(b"SSH-2.0-") && !version.starts_with(b"SSH-1.99-") {
+            return Err(());
+        }
+        self.client_version = Some(version.to_vec());
+        Ok(true)
+    }
+
+    fn send_packet(&mut self, payload: &[u8]) {
+        let packet = self.outgoing.encode(payload);
+        self.reply.extend(packet);
+    }
+
+    fn send_kexinit(&mut self) {
+        let payload = PacketWriter::new(MSG_KEXINIT)
+            .raw(&randombytes(16))
+            .name_list(&KEX_ALGORITHMS)
+            .name_list(&[HOST_KEY_ALGORITHM])
+            .name_list(&[CIPHER])
+            .name_list(&[CIPHER])
+            .name_list(&[MAC])
+            .name_list(&[MAC])
+            .name_list(&[COMPRESSION])
+            .name_list(&[COMPRESSION])
+            .name_list(&[])
+            .name_list(&[])
+            .boolean(false)
+            .uint32(0)
+            .into_payload();
+        self.send_packet(&payload);
+        self.server_kexinit = Some(payload);
+    }
+
+    fn send_disconnect(&mut self, reason: u32, description: &str) {
+        let payload = PacketWriter::new(MSG_DISCONNECT)
+            .uint32(reason)
+            .string(description.as_bytes())
+            .string(b"")
+            .into_payload();
+        self.send_packet(&payload);
+        self.closed = true;
+    }
+
+    fn handle_packet(&mut self, payload: &[u8], keys: &mut Vec<u8>) -> Result<(), ()> {
+        let message_type = payload[0];
+        let mut reader = PacketReader::new(payload);
+
+        // Only key exchange messages are allowed while keys are being exchanged.
+        let in_kex = self.client_kexinit.is_some() || self.incoming_key.is_some();
+        if in_kex && (message_type == MSG_KEXINIT || message_type >= MSG_USERAUTH_REQUEST) {
+            return Err(());
+        }
+
+        match message_type {
+            MSG_DISCONNECT => {
+                self.closed = true;
+            },
+            MSG_IGNORE | MSG_DEBUG | MSG_UNIMPLEMENTED => {},
+            MSG_KEXINIT => {
+                try!(self.handle_kexinit(payload, &mut reader));
+            },
+            MSG_KEX_ECDH_INIT => {
+                if mem::replace(&mut self.ignore_next_packet, false) {
+                    return Ok(());
+                }
+                try!(self.handle_ecdh_init(&mut reader));
+            },
+            MSG_NEWKEYS => {
+                let key = try!(self.incoming_key.take().ok_or(()));
+                self.incoming.set_key(&key);
+            },
+            _ if self.session_id.is_none() => {
+                return Err(());
+            },
+            MSG_SERVICE_REQUEST => {
+                let service = try!(reader.string());
+                if service != &b"ssh-userauth"[..] {
+                    self.send_disconnect(DISCONNECT_SERVICE_NOT_AVAILABLE, "service not available");
+                    return Ok(());
+                }
+                let payload = PacketWriter::new(MSG_SERVICE_ACCEPT).string(service).into_payload();
+                self.send_packet(&payload);
+            },
+            MSG_USERAUTH_REQUEST => {
+                try!(self.handle_userauth(&mut reader));
+            },
+            _ if !self.authenticated => {
+                return Err(());
+            },
+            MSG_GLOBAL_REQUEST => {
+                let _name = try!(reader.string());
+                if try!(reader.boolean()) {
+                    self.send_packet(&[MSG_REQUEST_FAILURE]);
+                }
+            },
+            MSG_CHANNEL_OPEN => {
+                try!(self.handle_channel_open(&mut reader));
+            },
+            MSG_CHANNEL_REQUEST => {
+                try!(self.handle_channel_request(&mut reader));
+            },
+            MSG_CHANNEL_DATA => {
+                let _recipient = try!(reader.uint32());
+                let data = try!(reader.string());
+                try!(self.consume_window(data.len() as u32));
+                keys.extend_from_slice(data);
+            },
+            MSG_CHANNEL_WINDOW_ADJUST => {
+                let _recipient = try!(reader.uint32());
+                let bytes_to_add = try!(reader.uint32());
+                if let Some(ref mut channel) = self.channel {
+                    channel.remote_window = channel.remote_window.saturating_add(bytes_to_add);
+                }
+                self.flush_pending();
+            },
+            MSG_CHANNEL_EOF => {},
+            MSG_CHANNEL_CLOSE => {
+                if let Some(channel) = self.channel.take() {
+                    let payload = PacketWriter::new(MSG_CHANNEL_CLOSE)
+                        .uint32(channel.remote_id)
+                        .into_payload();
+                    self.send_packet(&payload);
+                }
+                self.closed = true;
+            },
+            _ => {
+                let payload = PacketWriter::new(MSG_UNIMPLEMENTED)
+                    .uint32(self.incoming.last_sequence_number())
+                    .into_payload();
+                self.send_packet(&payload);
+            },
+        }
+        Ok(())
+    }
+
+    fn handle_kexinit(&mut self, payload: &[u8], reader: &mut PacketReader) -> Result<(), ()> {
+        let _cookie = try!(reader.raw(16));
+        let kex_algorithms = try!(reader.name_list());
+        let host_key_algorithms = try!(reader.name_list());
+        let ciphers_client_to_server = try!(reader.name_list());
+        let ciphers_server_to_client = try!(reader.name_list());
+        let _macs_client_to_server = try!(reader.name_list());
+        let _macs_server_to_client = try!(reader.name_list());
+        let compression_client_to_server = try!(reader.name_list());
+        let compression_server_to_client = try!(reader.name_list());
+        let _languages_client_to_server = try!(reader.name_list());
+        let _languages_server_to_client = try!(reader.name_list());
+        let first_kex_packet_follows = try!(reader.boolean());
+
+        // The client's first choice that we support wins.
+        let kex = kex_algorithms.iter().find(|k| KEX_ALGORITHMS.iter().any(|a| *a == &k[..]));
+        let agreed = kex.is_some() &&
+                     host_key_algorithms.iter().any(|a| a == HOST_KEY_ALGORITHM) &&
+                     ciphers_client_to_server.iter().any(|c| c == CIPHER) &&
+                     ciphers_server_to_client.iter().any(|c| c == CIPHER) &&
+                     compression_client_to_server.iter().any(|c| c == COMPRESSION) &&
+                     compression_server_to_client.iter().any(|c| c == COMPRESSION);
+        if !agreed {
+            self.send_disconnect(DISCONNECT_KEY_EXCHANGE_FAILED, "no matching algorithms");
+            return Ok(());
+        }
+
+        // A wrong guess means the packet following this one has to be thrown away.
+        self.ignore_next_packet = first_kex_packet_follows &&
+            (kex_algorithms.first() != kex ||
+             host_key_algorithms.first().map(|a| &a[..]) != Some(HOST_KEY_ALGORITHM));
+
+        // The client can start a new key exchange at any time.
+        if self.server_kexinit.is_none() {
+            self.send_kexinit();
+        }
+        self.client_kexinit = Some(payload.to_vec());
+        Ok(())
+    }
+
+    fn handle_ecdh_init(&mut self, reader: &mut PacketReader) -> Result<(), ()> {
+        let client_public = try!(reader.string());
+        let client_public = try!(curve25519::GroupElement::from_slice(client_public).ok_or(()));
+        let client_kexinit = try!(self.client_kexinit.take().ok_or(()));
+        let server_kexinit = try!(self.server_kexinit.take().ok_or(()));
+        let client_version = try!(self.client_version.clone().ok_or(()));
+
+        let secret = curve25519::Scalar::from_slice(&randombytes(32)).unwrap();
+        let server_public = curve25519::scalarmult_base(&secret);
+        let shared = curve25519::scalarmult(&secret, &client_public);
+        if shared.0.iter().all(|&b| b == 0) {
+            return Err(());
+        }
+        let shared_secret = mpint(&shared.0);
+
+        let host_key = self.host_key.blob();
+        let exchange = PacketWriter::new(0)
+            .string(&client_version)
+            .string(VERSION.as_bytes())
+            .string(&client_kexinit)
+            .string(&server_kexinit)
+            .string(&host_key)
+            .string(&client_public.0)
+            .string(&server_public.0)
+            .raw(&shared_secret)
+            .into_payload();
+        let exchange_hash = sha256::hash(&exchange[1..]).0.to_vec();
+        if self.session_id.is_none() {
+            self.session_id = Some(exchange_hash.clone());
+        }
+
+        let signature = self.host_key.sign(&exchange_hash);
+        let payload = PacketWriter::new(MSG_KEX_ECDH_REPLY)
+            .string(&host_key)
+            .string(&server_public.0)
+            .string(&signature)
+            .into_payload();
+        self.send_packet(&payload);
+        self.send_packet(&[MSG_NEWKEYS]);
+
+        let outgoing_key = self.derive_key(&shared_secret, &exchange_hash, b'D');
+        self.outgoing.set_key(&outgoing_key);
+        self.incoming_key = Some(self.derive_key(&shared_secret, &exchange_hash, b'C'));
+
+        // Anything held back during the key exchange can go now.
+        self.flush_pending();
+        Ok(())
+    }
+
+    // RFC 4253 section 7.2, extended to the 64 bytes the cipher needs.
+    fn derive_key(&self, shared_secret: &[u8], exchange_hash: &[u8], letter: u8) -> Vec<u8> {
+        let session_id = self.session_id.as_ref().unwrap();
+        let mut input = Vec::new();
+        input.extend_from_slice(shared_secret);
+        input.extend_from_slice(exchange_hash);
+        input.push(letter);
+        input.extend_from_slice(session_id);
+        let mut key = sha256::hash(&input).0.to_vec();
+
+        while key.len() < CHACHA_KEY_LENGTH {
+            let mut input = Vec::new();
+            input.extend_from_slice(shared_secret);
+            input.extend_from_slice(exchange_hash);
+            input.extend_from_slice(&key);
+            key.extend_from_slice(&sha256::hash(&input).0);
+        }
+        key.truncate(CHACHA_KEY_LENGTH);
+        key
+    }
+
+    fn handle_userauth(&mut self, reader: &mut PacketReader) -> Result<(), ()> {
+        let _user = try!(reader.string());
+        let _service = try!(reader.string());
+        let method = try!(reader.string());
+
+        if self.authenticated {
+            return Ok(());
+        }
+
+        // Typically "none", sent first to find out which methods are allowed.
+        if self.password.is_some() && method != &b"password"[..] {
+            self.send_auth_failure();
+            return Ok(());
+        }
+        let accepted = match self.password {
+            None => true,
+            Some(ref password) => {
+                // A true flag asks to change the password, which isn't supported.
+                let changing = try!(reader.boolean());
+                let given = try!(reader.string());
+                !changing && memcmp(given, password.as_bytes())
+            },
+        };
+
+        if accepted {
+            self.authenticated = true;
+            self.send_packet(&[MSG_USERAUTH_SUCCESS]);
+        }
+        else {
+            self.auth_failures += 1;
+            if self.auth_failures >= MAX_AUTH_FAILURES {
+                self.send_disconnect(DISCONNECT_NO_MORE_AUTH_METHODS_AVAILABLE, "too many wrong passwords");
+                return Ok(());
+            }
+            self.send_auth_failure();
+        }
+        Ok(())
+    }
+
+    fn send_auth_failure(&mut self) {
+        let payload = PacketWriter::new(MSG_USERAUTH_FAILURE)
+            .name_list(&["password"])
+            .boolean(false)
+            .into_payload();
+        self.send_packet(&payload);
+    }
+
+    fn handle_channel_open(&mut self, reader: &mut PacketReader) -> Result<(), ()> {
+        let channel_type = try!(reader.string());
+        let sender = try!(reader.uint32());
+        let window = try!(reader.uint32());
+        let max_packet = try!(reader.uint32());
+
+        // A watcher only ever needs the one session.
+        if channel_type != &b"session"[..] || self.channel.is_some() {
+            let payload = PacketWriter::new(MSG_CHANNEL_OPEN_FAILURE)
+                .uint32(sender)
+                .uint32(OPEN_ADMINISTRATIVELY_PROHIBITED)
+                .string(b"only one session channel is supported")
+                .string(b"")
+                .into_payload();
+            self.send_packet(&payload);
+            return Ok(());
+        }
+
+        self.channel = Some(Channel {
+            remote_id: sender,
+            remote_window: window,
+            remote_max_packet: max_packet,
+            local_window: LOCAL_WINDOW,
+        });
+        let payload = PacketWriter::new(MSG_CHANNEL_OPEN_CONFIRMATION)
+            .uint32(sender)
+            .uint32(0)
+            .uint32(LOCAL_WINDOW)
+            .uint32(LOCAL_MAX_PACKET)
+            .into_payload();
+        self.send_packet(&payload);
+        Ok(())
+    }
+
+    fn handle_channel_request(&mut self, reader: &mut PacketReader) -> Result<(), ()> {
+        let _recipient = try!(reader.uint32());
+        let request_type = try!(reader.string());
+        let want_reply = try!(reader.boolean());
+
+        let is_shell = request_type == &b"shell"[..];
+        let success = if request_type == &b"pty-req"[..] {
+            let terminal_type = try!(reader.utf8());
+            let width = try!(reader.uint32());
+            let height = try!(reader.uint32());
+            if !terminal_type.is_empty() {
+                self.terminal_type = Some(terminal_type.to_lowercase());
+            }
+            self.set_window_size(width, height);
+            self.terminal_changed = true;
+            true
+        }
+        else if request_type == &b"window-change"[..] {
+            let width = try!(reader.uint32());
+            let height = try!(reader.uint32());
+            self.set_window_size(width, height);
+            self.terminal_changed = true;
+            true
+        }
+        else if is_shell {
+            self.shell_started = true;
+            true
+        }
+        else {
+            // exec, env, subsystem and the rest make no sense for a watcher.
+            false
+        };
+
+        let remote_id = match self.channel {
+            Some(ref channel) => channel.remote_id,
+            None => return Err(()),
+        };
+        if want_reply {
+            let message_type = if success { MSG_CHANNEL_SUCCESS } else { MSG_CHANNEL_FAILURE };
+            let payload = PacketWriter::new(message_type).uint32(remote_id).into_payload();
+            self.send_packet(&payload);
+        }
+        if is_shell {
+            self.flush_pending();
+        }
+        Ok(())
+    }
+
+    fn set_window_size(&mut self, width: u32, height: u32) {
+        // Zero means the client doesn't know, so keep whatever we had.
+        if width > 0 && height > 0 {
+            let max = u16::max_value() as u32;
+            self.window_size = Some((cmp::min(width, max) as u16, cmp::min(height, max) as u16));
+        }
+    }
+
+    // Account for input received on the channel, opening the window back up once half is used.
+    fn consume_window(&mut self, length: u32) -> Result<(), ()> {
+        let adjust = match self.channel {
+            Some(ref mut channel) => {
+                if length > channel.local_window {
+                    return Err(());
+                }
+                channel.local_window -= length;
+                if channel.local_window < LOCAL_WINDOW / 2 {
+                    let bytes_to_add = LOCAL_WINDOW - channel.local_window;
+                    channel.local_window = LOCAL_WINDOW;
+                    Some((channel.remote_id, bytes_to_add))
+                }
+                else {
+                    None
+                }
+            },
+            None => return Err(()),
+        };
+
+        if let Some((remote_id, bytes_to_add)) = adjust {
+            let payload = PacketWriter::new(MSG_CHANNEL_WINDOW_ADJUST)
+                .uint32(remote_id)
+                .uint32(bytes_to_add)
+                .into_payload();
+            self.send_packet(&payload);
+        }
+        Ok(())
+    }
+
+    // Send as much pending channel data as the client's window allows.
+    fn flush_pending(&mut self) {
+        // Only key exchange messages may be sent until our NEWKEYS is out.
+        if self.server_kexinit.is_some() || self.closed {
+            return;
+        }
+
+        while !self.pending.is_empty() {
+            let (remote_id, length) = match self.channel {
+                Some(ref mut channel) => {
+                    let length = *[channel.remote_window, channel.remote_max_packet, MAX_DATA_PACKET]
+                                     .iter().min().unwrap() as usize;
+                    let length = if length > self.pending.len() { self.pending.len() } else { length };
+                    channel.remote_window -= length as u32;
+                    (channel.remote_id, length)
+                },
+                None => return,
+            };
+            if length == 0 {
+                return;
+            }
+
+            let data: Vec<u8> = self.pending.drain(..length).collect();
+            let payload = PacketWriter::new(MSG_CHANNEL_DATA)
+                .uint32(remote_id)
+                .string(&data)
+                .into_payload();
+            self.send_packet(&payload);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sodiumoxide::crypto::sign::ed25519;
+    use super::{HostKey, MSG_USERAUTH_REQUEST, SshSession, VERSION};
+    use super::packet::{PacketReader, PacketWriter};
+
+    fn session() -> SshSession {
+        session_with_password(None)
+    }
+
+    fn session_with_password(password: Option<&str>) -> SshSession {
+        let (public, secret) = ed25519::gen_keypair();
+        let host_key = HostKey {
+            public: public,
+            secret: secret,
+        };
+        SshSession::new(host_key, password.map(String::from))
+    }
+
+    fn userauth(session: &mut SshSession, method: &str, password: &str) {
+        let payload = PacketWriter::new(MSG_USERAUTH_REQUEST)
+            .string(b"watcher")
+            .string(b"ssh-connection")
+            .string(method.as_bytes())
+            .boolean(false)
+            .string(password.as_bytes())
+            .into_payload();
+        session.handle_userauth(&mut PacketReader::new(&payload)).unwrap();
+    }
+
+    #[test]
+    fn version_and_kexinit_sent() {
+        let mut session = session();
+        let reply = session.take_reply();
+        assert!(reply.starts_with(VERSION.as_bytes()));
+        assert_eq!(reply[VERSION.len() + 7], 20);
+    }
+
+    #[test]
+    fn client_version() {
+        let mut session = session();
+        assert_eq!(session.receive(b"SSH-2.0-Open"), Ok(vec![]));
+        assert!(session.client_version.is_none());
+        assert_eq!(session.receive(b"SSH_7.4\r\n"), Ok(vec![]));
+        assert_eq!(session.client_version, Some(b"SSH-2.0-OpenSSH_7.4".to_vec()));
+
+        let mut session = self::session();
+        assert!(session.receive(b"GET / HTTP/1.1\r\n").is_err());
+    }
+
+    #[test]
+    fn no_data_before_kex() {
+        let mut session = session();
+        session.take_reply();
+        session.send_data(b"menu").unwrap();
+        assert!(session.take_reply().is_empty());
+    }
+
+    #[test]
+    fn password() {
+        let mut session = session_with_password(Some("letmein"));
+        userauth(&mut session, "none", "");
+        assert!(!session.authenticated);
+        userauth(&mut session, "password", "guess");
+        assert!(!session.authenticated);
+        userauth(&mut session, "password", "letmein");
+        assert!(session.authenticated);
+
+        let mut session = session_with_password(Some("letmein"));
+        for _ in 0..3 {
+            userauth(&mut session, "password", "guess");
+        }
+        assert!(session.is_closed(), "Too many wrong passwords disconnect the client.");
+
+        let mut session = session();
+        userauth(&mut session, "none", "");
+        assert!(session.authenticated, "Anyone is let in without a password.");
+    }
+
+    #[test]
+    fn window_size_clamped() {
+        let mut session = session();
+        session.set_window_size(100_000, 50);
+        assert_eq!(session.window_size(), Some((65535, 50)));
+        session.set_window_size(0, 60);
+        assert_eq!(session.window_size(), Some((65535, 50)));
+    }
+
+    // Worked out independently from RFC 4253 section 7.2.
+    #[test]
+    fn derive_key_known_answer() {
+        let mut session = session();
+        session.session_id = Some((200..232).collect());
+        let shared_secret = [0, 0, 0, 4, 1, 2, 3, 4];
+        let exchange_hash: Vec<u8> = (100..132).collect();
+        let key = session.derive_key(&shared_secret, &exchange_hash, b'C');
+        assert_eq!(key, vec![0x36, 0x24, 0xb5, 0xb9, 0x1c, 0x9e, 0xc0, 0xf6, 0xdf, 0xaf, 0x56, 0x64,
+                             0x12, 0xca, 0xf5, 0x78, 0x71, 0x61, 0xc8, 0xac, 0xd3, 0x37, 0x50, 0xc0,
+                             0x10, 0xf8, 0xce, 0xad, 0xd4, 0xdf, 0xee, 0xd8, 0x23, 0x04, 0xd6, 0x32,
+                             0xc0, 0x4a, 0xe1, 0xbb, 0xc2, 0xdc, 0xe2, 0xaf, 0x7b, 0x75, 0x09, 0xd2,
+                             0x42, 0x13, 0x4d, 0xdb, 0xbb, 0xdb, 0x7a, 0xa0, 0xdf, 0x30, 0xab, 0x37,
+                             0x87, 0x42, 0x87, 0x75]);
+    }
+}
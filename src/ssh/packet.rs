@@ -0,0 +1,368 @@
+// The SSH binary packet protocol (RFC 4253 section 6) and the one cipher termcastd supports,
+// chacha20-poly1305@openssh.com.
+
+use sodiumoxide::crypto::onetimeauth::poly1305;
+use sodiumoxide::crypto::stream::chacha20;
+use sodiumoxide::randombytes::randombytes;
+
+// Largest packet a client may send. RFC 4253 requires at least 35000.
+const MAX_PACKET_LENGTH: usize = 35000;
+const MIN_PADDING: usize = 4;
+const BLOCK_SIZE: usize = 8;
+const TAG_LENGTH: usize = 16;
+pub const CHACHA_KEY_LENGTH: usize = 64;
+
+
+/// Builds the payload of a packet.
+pub struct PacketWriter {
+    payload: Vec<u8>,
+}
+
+/// Reads the fields of a packet payload in order.
+pub struct PacketReader<'a> {
+    payload: &'a [u8],
+    position: usize,
+}
+
+/// Framing, encryption and sequence numbers for one direction of a connection.
+pub struct Transport {
+    sequence_number: u32,
+    cipher: Option<ChaChaPoly>,
+}
+
+struct ChaChaPoly {
+    // Encrypts the packet length.
+    header_key: chacha20::Key,
+    // Encrypts the rest of the packet and provides the poly1305 key.
+    main_key: chacha20::Key,
+}
+
+
+impl PacketWriter {
+    pub fn new(message_type: u8) -> Self {
+        PacketWriter {
+            payload: vec![message_type],
+        }
+    }
+
+    pub fn byte(mut self, value: u8) -> Self {
+        self.payload.push(value);
+        self
+    }
+
+    pub fn boolean(self, value: bool) -> Self {
+        self.byte(if value { 1 } else { 0 })
+    }
+
+    pub fn uint32(mut self, value: u32) -> Self {
+        self.payload.extend_from_slice(&be_bytes(value));
+        self
+    }
+
+    pub fn raw(mut self, bytes: &[u8]) -> Self {
+        self.payload.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn string(mut self, bytes: &[u8]) -> Self {
+        self.payload.extend_from_slice(&be_bytes(bytes.len() as u32));
+        self.payload.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn name_list(self, names: &[&str]) -> Self {
+        self.string(names.join(",").as_bytes())
+    }
+
+    pub fn into_payload(self) -> Vec<u8> {
+        self.payload
+    }
+}
+
+impl<'a> PacketReader<'a> {
+    /// Start reading just after the message type.
+    pub fn new(payload: &'a [u8]) -> Self {
+        PacketReader {
+            payload: payload,
+            position: 1,
+        }
+    }
+
+    pub fn byte(&mut self) -> Result<u8, ()> {
+        let bytes = try!(self.take(1));
+        Ok(bytes[0])
+    }
+
+    pub fn boolean(&mut self) -> Result<bool, ()> {
+        self.byte().map(|b| b != 0)
+    }
+
+    pub fn uint32(&mut self) -> Result<u32, ()> {
+        let bytes = try!(self.take(4));
+        Ok(from_be_bytes(bytes))
+    }
+
+    pub fn raw(&mut self, length: usize) -> Result<&'a [u8], ()> {
+        self.take(length)
+    }
+
+    pub fn string(&mut self) -> Result<&'a [u8], ()> {
+        let length = try!(self.uint32()) as usize;
+        self.take(length)
+    }
+
+    pub fn utf8(&mut self) -> Result<String, ()> {
+        let bytes = try!(self.string());
+        String::from_utf8(bytes.to_vec()).map_err(|_| ())
+    }
+
+    pub fn name_list(&mut self) -> Result<Vec<String>, ()> {
+        let names = try!(self.utf8());
+        Ok(names.split(',').filter(|n| !n.is_empty()).map(String::from).collect())
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], ()> {
+        if self.position + length > self.payload.len() {
+            return Err(());
+        }
+        let bytes = &self.payload[self.position..self.position + length];
+        self.position += length;
+        Ok(bytes)
+    }
+}
+
+impl Transport {
+    pub fn new() -> Self {
+        Transport {
+            sequence_number: 0,
+            cipher: None,
+        }
+    }
+
+    /// Use the new keys for every packet from now on. The key is 64 bytes from the key exchange.
+    pub fn set_key(&mut self, key: &[u8]) {
+        self.cipher = Some(ChaChaPoly {
+            main_key: chacha20::Key::from_slice(&key[..32]).unwrap(),
+            header_key: chacha20::Key::from_slice(&key[32..CHACHA_KEY_LENGTH]).unwrap(),
+        });
+    }
+
+    /// Frame, pad, and, once keys are set, encrypt a packet.
+    pub fn encode(&mut self, payload: &[u8]) -> Vec<u8> {
+        // Without a cipher the length field counts towards the block size. With the AEAD cipher it
+        // doesn't.
+        let unpadded = payload.len() + 1 + if self.cipher.is_some() { 0 } else { 4 };
+        let mut padding = BLOCK_SIZE - unpadded % BLOCK_SIZE;
+        if padding < MIN_PADDING {
+            padding += BLOCK_SIZE;
+        }
+
+        let packet_length = payload.len() + 1 + padding;
+        let mut packet = Vec::with_capacity(4 + packet_length + TAG_LENGTH);
+        packet.extend_from_slice(&be_bytes(packet_length as u32));
+        packet.push(padding as u8);
+        packet.extend_from_slice(payload);
+        packet.extend(randombytes(padding));
+
+        if let Some(ref cipher) = self.cipher {
+            let nonce = nonce(self.sequence_number);
+            let header = chacha20::stream(4, &nonce, &cipher.header_key);
+            xor(&mut packet[..4], &header);
+            let main = main_keystream(packet_length, &nonce, &cipher.main_key);
+            xor(&mut packet[4..], &main);
+            let tag = poly1305::authenticate(&packet, &poly_key(&nonce, &cipher.main_key));
+            packet.extend_from_slice(&tag.0);
+        }
+
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        packet
+    }
+
+    /// Take the next complete packet out of `input`, returning its payload. Ok(None) means more
+    /// input is needed.
+    pub fn decode(&mut self, input: &mut Vec<u8>) -> Result<Option<Vec<u8>>, ()> {
+        if input.len() < 4 {
+            return Ok(None);
+        }
+
+        let nonce = nonce(self.sequence_number);
+        let mut length_bytes = [0; 4];
+        length_bytes.copy_from_slice(&input[..4]);
+        if let Some(ref cipher) = self.cipher {
+            let header = chacha20::stream(4, &nonce, &cipher.header_key);
+            xor(&mut length_bytes, &header);
+        }
+        let packet_length = from_be_bytes(&length_bytes) as usize;
+        if packet_length < 1 + MIN_PADDING || packet_length > MAX_PACKET_LENGTH {
+            return Err(());
+        }
+
+        let tag_length = if self.cipher.is_some() { TAG_LENGTH } else { 0 };
+        let total_length = 4 + packet_length + tag_length;
+        if input.len() < total_length {
+            return Ok(None);
+        }
+
+        let packet: Vec<u8> = input.drain(..total_length).collect();
+        let mut body = packet[4..4 + packet_length].to_vec();
+        if let Some(ref cipher) = self.cipher {
+            let tag = try!(poly1305::Tag::from_slice(&packet[4 + packet_length..]).ok_or(()));
+            if !poly1305::verify(&tag, &packet[..4 + packet_length], &poly_key(&nonce, &cipher.main_key)) {
+                return Err(());
+            }
+            let main = main_keystream(packet_length, &nonce, &cipher.main_key);
+            xor(&mut body, &main);
+        }
+
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+
+        let padding = body[0] as usize;
+        if padding + 1 > body.len() {
+            return Err(());
+        }
+        Ok(Some(body[1..body.len() - padding].to_vec()))
+    }
+
+    /// Sequence number of the last packet decoded.
+    pub fn last_sequence_number(&self) -> u32 {
+        self.sequence_number.wrapping_sub(1)
+    }
+}
+
+/// Encode a big-endian unsigned number as an mpint: no leading zero bytes, except one to keep the
+/// number positive when the high bit is set.
+pub fn mpint(number: &[u8]) -> Vec<u8> {
+    let first_nonzero = number.iter().position(|&b| b != 0).unwrap_or(number.len());
+    let number = &number[first_nonzero..];
+    let mut encoded = Vec::with_capacity(number.len() + 5);
+    if number.first().map(|&b| b & 0x80 != 0).unwrap_or(false) {
+        encoded.extend_from_slice(&be_bytes(number.len() as u32 + 1));
+        encoded.push(0);
+    }
+    else {
+        encoded.extend_from_slice(&be_bytes(number.len() as u32));
+    }
+    encoded.extend_from_slice(number);
+    encoded
+}
+
+pub fn be_bytes(value: u32) -> [u8; 4] {
+    [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]
+}
+
+fn from_be_bytes(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32
+}
+
+// The sequence number is the nonce, as a 64-bit big-endian number.
+fn nonce(sequence_number: u32) -> chacha20::Nonce {
+    let mut nonce = [0; 8];
+    nonce[4..].copy_from_slice(&be_bytes(sequence_number));
+    chacha20::Nonce(nonce)
+}
+
+// The poly1305 key is the start of the first chacha20 block.
+fn poly_key(nonce: &chacha20::Nonce, key: &chacha20::Key) -> poly1305::Key {
+    let stream = chacha20::stream(32, nonce, key);
+    poly1305::Key::from_slice(&stream).unwrap()
+}
+
+// The packet itself is encrypted starting from the second chacha20 block.
+fn main_keystream(length: usize, nonce: &chacha20::Nonce, key: &chacha20::Key) -> Vec<u8> {
+    let mut stream = chacha20::stream(64 + length, nonce, key);
+    stream.drain(..64);
+    stream
+}
+
+fn xor(data: &mut [u8], keystream: &[u8]) {
+    for (byte, key) in data.iter_mut().zip(keystream) {
+        *byte ^= *key;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PacketReader, PacketWriter, Transport, mpint};
+
+    #[test]
+    fn read_write() {
+        let payload = PacketWriter::new(5)
+            .boolean(true)
+            .uint32(0x01020304)
+            .string(b"abc")
+            .name_list(&["a", "b"])
+            .into_payload();
+        assert_eq!(payload, vec![5, 1, 1, 2, 3, 4, 0, 0, 0, 3, b'a', b'b', b'c',
+                                 0, 0, 0, 3, b'a', b',', b'b']);
+
+        let mut reader = PacketReader::new(&payload);
+        assert_eq!(reader.boolean(), Ok(true));
+        assert_eq!(reader.uint32(), Ok(0x01020304));
+        assert_eq!(reader.string(), Ok(&b"abc"[..]));
+        assert_eq!(reader.name_list(), Ok(vec![String::from("a"), String::from("b")]));
+        assert!(reader.byte().is_err());
+    }
+
+    #[test]
+    fn mpints() {
+        assert_eq!(mpint(&[0, 0]), vec![0, 0, 0, 0]);
+        assert_eq!(mpint(&[0, 0x12, 0x34]), vec![0, 0, 0, 2, 0x12, 0x34]);
+        assert_eq!(mpint(&[0x80, 1]), vec![0, 0, 0, 3, 0, 0x80, 1]);
+    }
+
+    #[test]
+    fn plaintext() {
+        let mut sender = Transport::new();
+        let mut receiver = Transport::new();
+        let mut input = sender.encode(b"\x15hello");
+        assert_eq!(input.len() % 8, 0);
+        input.extend(sender.encode(b"\x02"));
+
+        let mut partial = input[..3].to_vec();
+        assert_eq!(receiver.decode(&mut partial), Ok(None));
+
+        assert_eq!(receiver.decode(&mut input), Ok(Some(b"\x15hello".to_vec())));
+        assert_eq!(receiver.decode(&mut input), Ok(Some(b"\x02".to_vec())));
+        assert!(input.is_empty());
+        assert_eq!(receiver.last_sequence_number(), 1);
+    }
+
+    #[test]
+    fn encrypted() {
+        let key: Vec<u8> = (0..64).collect();
+        let mut sender = Transport::new();
+        let mut receiver = Transport::new();
+        // Sequence numbers carry on from before the keys were set.
+        let mut input = sender.encode(b"\x15");
+        assert_eq!(receiver.decode(&mut input), Ok(Some(b"\x15".to_vec())));
+        sender.set_key(&key);
+        receiver.set_key(&key);
+
+        let mut input = sender.encode(b"\x5esecret");
+        assert!(!input.windows(6).any(|w| w == b"secret"));
+        assert_eq!((input.len() - 4 - 16) % 8, 0);
+        assert_eq!(receiver.decode(&mut input.clone()), Ok(Some(b"\x5esecret".to_vec())));
+
+        // A tampered packet is rejected.
+        let mut receiver = Transport::new();
+        receiver.sequence_number = 1;
+        receiver.set_key(&key);
+        let last = input.len() - 1;
+        input[last] ^= 1;
+        assert!(receiver.decode(&mut input).is_err());
+    }
+
+    // A packet built by an independent implementation of chacha20-poly1305@openssh.com, with the
+    // key 0, 1, ..., 63, sequence number 3 and eight 0xaa padding bytes.
+    #[test]
+    fn known_answer() {
+        let key: Vec<u8> = (0..64).collect();
+        let mut receiver = Transport::new();
+        receiver.sequence_number = 3;
+        receiver.set_key(&key);
+        let mut input = vec![0xfb, 0x1a, 0x92, 0x9a, 0x88, 0x1b, 0x6f, 0xde, 0x51, 0x38, 0xc1, 0x05,
+                             0x22, 0xe5, 0x56, 0xa0, 0x11, 0x4d, 0x3c, 0xe9, 0x28, 0x86, 0x23, 0x45,
+                             0x73, 0xf6, 0x85, 0x80, 0x31, 0x23, 0xb3, 0xe3, 0x42, 0x5c, 0xae, 0x33];
+        assert_eq!(receiver.decode(&mut input), Ok(Some(b"\x5esecret".to_vec())));
+    }
+}
@@ -0,0 +1,615 @@
+// Telnet protocol handling for watchers, see RFC 854 and RFC 855. Option negotiation follows the
+// "Q method" of RFC 1143 so the two sides can never get stuck in a negotiation loop.
+
+use std::borrow::Cow;
+
+pub const IAC: u8 = 255;
+pub const DONT: u8 = 254;
+pub const DO: u8 = 253;
+pub const WONT: u8 = 252;
+pub const WILL: u8 = 251;
+pub const SB: u8 = 250;
+pub const SE: u8 = 240;
+
+pub const TRANSMIT_BINARY: u8 = 0;
+pub const ECHO: u8 = 1;
+pub const SUPPRESS_GO_AHEAD: u8 = 3;
+pub const TERMINAL_TYPE: u8 = 24;
+pub const NAWS: u8 = 31;
+pub const LINEMODE: u8 = 34;
+pub const COMPRESS2: u8 = 86;
+
+const CR: u8 = b'\r';
+const NUL: u8 = 0;
+
+const LINEMODE_MODE: u8 = 1;
+const TERMINAL_TYPE_IS: u8 = 0;
+const TERMINAL_TYPE_SEND: u8 = 1;
+// Longest subnegotiation that will be buffered. Anything longer is cut off.
+const MAX_SUBNEGOTIATION: usize = 256;
+
+
+/// Tracks the state of a single watcher's telnet connection: parses commands out of the input and
+/// keeps track of which options have been negotiated.
+pub struct Telnet {
+    state: State,
+    // Options enabled on our side (WILL/WONT) and on the client's side (DO/DONT).
+    local: [OptionState; 256],
+    remote: [OptionState; 256],
+    subnegotiation: Vec<u8>,
+    reply: Vec<u8>,
+    window_size: Option<(u16, u16)>,
+    terminal_type: Option<String>,
+    terminal_changed: bool,
+    // Where in `reply` compression has to start.
+    compress_start: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Data,
+    // Just after a CR, which clients follow with a NUL that isn't part of the input.
+    DataCr,
+    Iac,
+    Negotiate(u8),
+    Subnegotiate,
+    SubnegotiateIac,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OptionState {
+    No,
+    Yes,
+    WantNo(Queue),
+    WantYes(Queue),
+}
+
+// Whether we changed our mind while waiting for an answer, and have to ask for the opposite once
+// it comes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Queue {
+    Empty,
+    Opposite,
+}
+
+
+impl Telnet {
+    pub fn new() -> Self {
+        Telnet {
+            state: State::Data,
+            local: [OptionState::No; 256],
+            remote: [OptionState::No; 256],
+            subnegotiation: Vec::new(),
+            reply: Vec::new(),
+            window_size: None,
+            terminal_type: None,
+            terminal_changed: false,
+            compress_start: None,
+        }
+    }
+
+    /// Carry on with a connection whose options were negotiated by another server: `local` and
+    /// `remote` are the options already enabled on each side.
+    pub fn resume(local: &[u8], remote: &[u8]) -> Self {
+        let mut telnet = Telnet::new();
+        for option in local {
+            telnet.local[*option as usize] = OptionState::Yes;
+        }
+        for option in remote {
+            telnet.remote[*option as usize] = OptionState::Yes;
+        }
+        telnet
+    }
+
+    /// Options termcastd wants at the start of every connection: character at a time input with
+    /// no local echo, 8-bit clean output since casts are arbitrary bytes, the client's window size
+    /// and terminal type, and compressed output for clients that support MCCP2.
+    pub fn negotiate(&mut self) {
+        self.enable_local(TRANSMIT_BINARY);
+        self.enable_remote(TRANSMIT_BINARY);
+        self.enable_local(ECHO);
+        self.enable_local(SUPPRESS_GO_AHEAD);
+        self.enable_remote(LINEMODE);
+        self.enable_remote(NAWS);
+        self.enable_remote(TERMINAL_TYPE);
+        self.enable_local(COMPRESS2);
+    }
+
+    /// Strip telnet commands, and the NUL that follows a CR, out of `input`, returning only the
+    /// data. Any replies the commands need are queued up for `take_reply`.
+    pub fn receive(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(input.len());
+        for &byte in input {
+            self.state = match (self.state, byte) {
+                (State::DataCr, NUL) => State::Data,
+                (State::Data, IAC) | (State::DataCr, IAC) => State::Iac,
+                (State::Data, CR) | (State::DataCr, CR) => {
+                    data.push(byte);
+                    State::DataCr
+                },
+                (State::Data, _) | (State::DataCr, _) => {
+                    data.push(byte);
+                    State::Data
+                },
+                // An escaped 0xff is data.
+                (State::Iac, IAC) => {
+                    data.push(IAC);
+                    State::Data
+                },
+                (State::Iac, WILL) | (State::Iac, WONT) | (State::Iac, DO) | (State::Iac, DONT) => {
+                    State::Negotiate(byte)
+                },
+                (State::Iac, SB) => {
+                    self.subnegotiation.clear();
+                    State::Subnegotiate
+                },
+                // Every other command (NOP, AYT, GA, ...) is ignored.
+                (State::Iac, _) => State::Data,
+                (State::Negotiate(command), option) => {
+                    self.negotiation(command, option);
+                    State::Data
+                },
+                (State::Subnegotiate, IAC) => State::SubnegotiateIac,
+                (State::Subnegotiate, _) => {
+                    if self.subnegotiation.len() < MAX_SUBNEGOTIATION {
+                        self.subnegotiation.push(byte);
+                    }
+                    State::Subnegotiate
+                },
+                (State::SubnegotiateIac, SE) => {
+                    self.subnegotiation_done();
+                    State::Data
+                },
+                (State::SubnegotiateIac, IAC) => {
+                    if self.subnegotiation.len() < MAX_SUBNEGOTIATION {
+                        self.subnegotiation.push(IAC);
+                    }
+                    State::Subnegotiate
+                },
+                // Not valid. Treat it as the end of the subnegotiation.
+                (State::SubnegotiateIac, _) => {
+                    self.subnegotiation_done();
+                    State::Data
+                },
+            };
+        }
+        data
+    }
+
+    /// Bytes that need to be sent to the client in response to what it has sent or what has been
+    /// requested.
+    pub fn take_reply(&mut self) -> Vec<u8> {
+        ::std::mem::replace(&mut self.reply, Vec::new())
+    }
+
+    /// Position in the next `take_reply` after which everything sent has to be compressed. Only
+    /// returned once.
+    pub fn take_compress_start(&mut self) -> Option<usize> {
+        self.compress_start.take()
+    }
+
+    /// Whether we have agreed to perform the option.
+    pub fn local_enabled(&self, option: u8) -> bool {
+        self.local[option as usize] == OptionState::Yes
+    }
+
+    /// Whether the client has agreed to perform the option.
+    pub fn remote_enabled(&self, option: u8) -> bool {
+        self.remote[option as usize] == OptionState::Yes
+    }
+
+    /// Every option enabled on our side and on the client's side.
+    pub fn enabled_options(&self) -> (Vec<u8>, Vec<u8>) {
+        let enabled = |options: &[OptionState; 256]| -> Vec<u8> {
+            (0..256).filter(|option| options[*option] == OptionState::Yes)
+                    .map(|option| option as u8)
+                    .collect()
+        };
+        (enabled(&self.local), enabled(&self.remote))
+    }
+
+    /// Window width and height, once the client has sent them.
+    pub fn window_size(&self) -> Option<(u16, u16)> {
+        self.window_size
+    }
+
+    pub fn terminal_type(&self) -> Option<&str> {
+        self.terminal_type.as_ref().map(|t| t.as_str())
+    }
+
+    /// Whether the window size or terminal type changed since the last call.
+    pub fn take_terminal_changed(&mut self) -> bool {
+        let changed = self.terminal_changed;
+        self.terminal_changed = false;
+        changed
+    }
+
+    pub fn enable_local(&mut self, option: u8) {
+        if request(&mut self.local[option as usize], true) {
+            self.send_command(WILL, option);
+        }
+    }
+
+    pub fn enable_remote(&mut self, option: u8) {
+        if request(&mut self.remote[option as usize], true) {
+            self.send_command(DO, option);
+        }
+    }
+
+    pub fn disable_local(&mut self, option: u8) {
+        if request(&mut self.local[option as usize], false) {
+            self.send_command(WONT, option);
+        }
+    }
+
+    fn negotiation(&mut self, command: u8, option: u8) {
+        let idx = option as usize;
+        match command {
+            WILL => {
+                let (answer, enabled) = peer_enable(&mut self.remote[idx], supported_remote(option));
+                if let Some(yes) = answer {
+                    self.send_command(if yes { DO } else { DONT }, option);
+                }
+                if enabled {
+                    self.remote_enabled_changed(option);
+                }
+            },
+            WONT => {
+                if let Some(yes) = peer_disable(&mut self.remote[idx]) {
+                    self.send_command(if yes { DO } else { DONT }, option);
+                }
+            },
+            DO => {
+                let (answer, enabled) = peer_enable(&mut self.local[idx], supported_local(option));
+                if let Some(yes) = answer {
+                    self.send_command(if yes { WILL } else { WONT }, option);
+                }
+                if enabled {
+                    self.local_enabled_changed(option);
+                }
+            },
+            DONT => {
+                if let Some(yes) = peer_disable(&mut self.local[idx]) {
+                    self.send_command(if yes { WILL } else { WONT }, option);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    // Called when we start performing an option.
+    fn local_enabled_changed(&mut self, option: u8) {
+        if option == COMPRESS2 && self.compress_start.is_none() {
+            // Everything after this subnegotiation is compressed. Compression is never turned off
+            // again; MCCP leaves that to the server and termcastd has no reason to.
+            self.send_subnegotiation(COMPRESS2, &[]);
+            self.compress_start = Some(self.reply.len());
+        }
+    }
+
+    // Called when the client starts performing an option.
+    fn remote_enabled_changed(&mut self, option: u8) {
+        match option {
+            // Turn off all of the linemode features so every key press is sent straight away.
+            LINEMODE => self.send_subnegotiation(LINEMODE, &[LINEMODE_MODE, 0]),
+            TERMINAL_TYPE => self.send_subnegotiation(TERMINAL_TYPE, &[TERMINAL_TYPE_SEND]),
+            _ => {},
+        }
+    }
+
+    fn subnegotiation_done(&mut self) {
+        let subnegotiation = ::std::mem::replace(&mut self.subnegotiation, Vec::new());
+        match subnegotiation.split_first() {
+            Some((&NAWS, data)) if data.len() == 4 => {
+                let width = (data[0] as u16) << 8 | data[1] as u16;
+                let height = (data[2] as u16) << 8 | data[3] as u16;
+                // Zero means the client doesn't know.
+                if width > 0 && height > 0 && self.window_size != Some((width, height)) {
+                    self.window_size = Some((width, height));
+                    self.terminal_changed = true;
+                }
+            },
+            Some((&TERMINAL_TYPE, data)) if data.first() == Some(&TERMINAL_TYPE_IS) => {
+                let terminal_type = String::from_utf8_lossy(&data[1..]).to_lowercase();
+                // Only the first type the client offers is used, so there's no need to ask for
+                // any more.
+                if self.terminal_type.is_none() && !terminal_type.is_empty() {
+                    self.terminal_type = Some(terminal_type);
+                    self.terminal_changed = true;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn send_command(&mut self, command: u8, option: u8) {
+        self.reply.extend_from_slice(&[IAC, command, option]);
+    }
+
+    fn send_subnegotiation(&mut self, option: u8, data: &[u8]) {
+        self.reply.extend_from_slice(&[IAC, SB, option]);
+        for &byte in data {
+            if byte == IAC {
+                self.reply.push(IAC);
+            }
+            self.reply.push(byte);
+        }
+        self.reply.extend_from_slice(&[IAC, SE]);
+    }
+}
+
+/// Double every IAC byte so the client sees it as data instead of the start of a command. This is
+/// needed with or without binary mode.
+pub fn escape(data: &[u8]) -> Cow<[u8]> {
+    if !data.contains(&IAC) {
+        return Cow::Borrowed(data);
+    }
+
+    let mut escaped = Vec::with_capacity(data.len() + 16);
+    for &byte in data {
+        if byte == IAC {
+            escaped.push(IAC);
+        }
+        escaped.push(byte);
+    }
+    Cow::Owned(escaped)
+}
+
+// Ask for an option to be enabled or disabled. Returns whether the request has to be sent now; if
+// an answer to an earlier request is still awaited it is queued until then instead.
+fn request(state: &mut OptionState, enable: bool) -> bool {
+    let (new_state, send) = match (*state, enable) {
+        (OptionState::No, true) => (OptionState::WantYes(Queue::Empty), true),
+        (OptionState::Yes, false) => (OptionState::WantNo(Queue::Empty), true),
+        (OptionState::WantNo(_), true) => (OptionState::WantNo(Queue::Opposite), false),
+        (OptionState::WantYes(_), false) => (OptionState::WantYes(Queue::Opposite), false),
+        (OptionState::WantNo(_), false) => (OptionState::WantNo(Queue::Empty), false),
+        (OptionState::WantYes(_), true) => (OptionState::WantYes(Queue::Empty), false),
+        (state, _) => (state, false),
+    };
+    *state = new_state;
+    send
+}
+
+// The other side said it will perform an option (WILL) or asked us to (DO). Returns the answer to
+// send, if any, true meaning agree, and whether the option is now enabled.
+fn peer_enable(state: &mut OptionState, supported: bool) -> (Option<bool>, bool) {
+    let (new_state, answer, enabled) = match *state {
+        OptionState::No if supported => (OptionState::Yes, Some(true), true),
+        OptionState::No => (OptionState::No, Some(false), false),
+        OptionState::Yes => (OptionState::Yes, None, false),
+        // Contradicting our request to disable it, which RFC 1143 treats as a refusal.
+        OptionState::WantNo(Queue::Empty) => (OptionState::No, None, false),
+        OptionState::WantNo(Queue::Opposite) => (OptionState::Yes, None, true),
+        OptionState::WantYes(Queue::Empty) => (OptionState::Yes, None, true),
+        OptionState::WantYes(Queue::Opposite) => (OptionState::WantNo(Queue::Empty), Some(false), false),
+    };
+    *state = new_state;
+    (answer, enabled)
+}
+
+// The other side said it won't perform an option (WONT) or asked us not to (DONT). Returns the
+// answer to send, if any, true meaning ask for it again.
+fn peer_disable(state: &mut OptionState) -> Option<bool> {
+    let (new_state, answer) = match *state {
+        OptionState::No => (OptionState::No, None),
+        OptionState::Yes => (OptionState::No, Some(false)),
+        OptionState::WantNo(Queue::Empty) => (OptionState::No, None),
+        OptionState::WantNo(Queue::Opposite) => (OptionState::WantYes(Queue::Empty), Some(true)),
+        OptionState::WantYes(_) => (OptionState::No, None),
+    };
+    *state = new_state;
+    answer
+}
+
+// Options we are willing to perform.
+fn supported_local(option: u8) -> bool {
+    match option {
+        TRANSMIT_BINARY | ECHO | SUPPRESS_GO_AHEAD | COMPRESS2 => true,
+        _ => false,
+    }
+}
+
+// Options we are willing to let the client perform.
+fn supported_remote(option: u8) -> bool {
+    match option {
+        TRANSMIT_BINARY | SUPPRESS_GO_AHEAD | LINEMODE => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_data() {
+        let mut telnet = Telnet::new();
+        assert_eq!(telnet.receive(b"abc"), b"abc");
+        assert!(telnet.take_reply().is_empty());
+    }
+
+    #[test]
+    fn strips_commands() {
+        let mut telnet = Telnet::new();
+        let input = [b'a', IAC, DO, ECHO, b'b', IAC, IAC, IAC, SB, 24, 0, b'x', IAC, SE, b'c'];
+        assert_eq!(telnet.receive(&input), vec![b'a', b'b', IAC, b'c']);
+    }
+
+    #[test]
+    fn split_command() {
+        let mut telnet = Telnet::new();
+        assert_eq!(telnet.receive(&[b'a', IAC]), b"a");
+        assert_eq!(telnet.receive(&[WILL]), b"");
+        assert_eq!(telnet.receive(&[SUPPRESS_GO_AHEAD, b'b']), b"b");
+        assert!(telnet.remote_enabled(SUPPRESS_GO_AHEAD));
+    }
+
+    #[test]
+    fn negotiate() {
+        let mut telnet = Telnet::new();
+        telnet.negotiate();
+        assert_eq!(telnet.take_reply(), vec![IAC, WILL, TRANSMIT_BINARY,
+                                             IAC, DO, TRANSMIT_BINARY,
+                                             IAC, WILL, ECHO,
+                                             IAC, WILL, SUPPRESS_GO_AHEAD,
+                                             IAC, DO, LINEMODE,
+                                             IAC, DO, NAWS,
+                                             IAC, DO, TERMINAL_TYPE,
+                                             IAC, WILL, COMPRESS2]);
+
+        // Agreeing to a request needs no reply, except for setting up linemode.
+        telnet.receive(&[IAC, DO, ECHO, IAC, WILL, LINEMODE]);
+        assert!(telnet.local_enabled(ECHO));
+        assert!(telnet.remote_enabled(LINEMODE));
+        assert_eq!(telnet.take_reply(), vec![IAC, SB, LINEMODE, LINEMODE_MODE, 0, IAC, SE]);
+
+        telnet.receive(&[IAC, DONT, SUPPRESS_GO_AHEAD]);
+        assert!(!telnet.local_enabled(SUPPRESS_GO_AHEAD));
+        assert!(telnet.take_reply().is_empty());
+    }
+
+    #[test]
+    fn disable() {
+        let mut telnet = Telnet::new();
+        telnet.negotiate();
+        telnet.receive(&[IAC, DO, ECHO]);
+        telnet.take_reply();
+        telnet.disable_local(ECHO);
+        // Never agreed to, so there's nothing to take back.
+        telnet.disable_local(SUPPRESS_GO_AHEAD);
+        assert_eq!(telnet.take_reply(), vec![IAC, WONT, ECHO]);
+        assert!(!telnet.local_enabled(ECHO));
+
+        telnet.receive(&[IAC, DONT, ECHO]);
+        assert!(!telnet.local_enabled(ECHO));
+        assert!(telnet.take_reply().is_empty());
+    }
+
+    #[test]
+    fn binary() {
+        let mut telnet = Telnet::new();
+        telnet.negotiate();
+        telnet.take_reply();
+        telnet.receive(&[IAC, DO, TRANSMIT_BINARY, IAC, WONT, TRANSMIT_BINARY]);
+        assert!(telnet.local_enabled(TRANSMIT_BINARY));
+        assert!(!telnet.remote_enabled(TRANSMIT_BINARY));
+        assert!(telnet.take_reply().is_empty());
+    }
+
+    #[test]
+    fn window_size() {
+        let mut telnet = Telnet::new();
+        telnet.negotiate();
+        telnet.take_reply();
+        telnet.receive(&[IAC, WILL, NAWS, IAC, SB, NAWS, 0, 132, 0, 43, IAC, SE]);
+        assert_eq!(telnet.window_size(), Some((132, 43)));
+        assert!(telnet.take_terminal_changed());
+        assert!(!telnet.take_terminal_changed());
+
+        // A width of 255 has to be escaped.
+        telnet.receive(&[IAC, SB, NAWS, 0, IAC, IAC, 0, 50, IAC, SE]);
+        assert_eq!(telnet.window_size(), Some((255, 50)));
+    }
+
+    #[test]
+    fn terminal_type() {
+        let mut telnet = Telnet::new();
+        telnet.negotiate();
+        telnet.take_reply();
+        telnet.receive(&[IAC, WILL, TERMINAL_TYPE]);
+        assert_eq!(telnet.take_reply(), vec![IAC, SB, TERMINAL_TYPE, TERMINAL_TYPE_SEND, IAC, SE]);
+
+        let mut input = vec![IAC, SB, TERMINAL_TYPE, TERMINAL_TYPE_IS];
+        input.extend_from_slice(b"XTERM");
+        input.extend_from_slice(&[IAC, SE]);
+        telnet.receive(&input);
+        assert_eq!(telnet.terminal_type(), Some("xterm"));
+    }
+
+    #[test]
+    fn compress() {
+        let mut telnet = Telnet::new();
+        telnet.negotiate();
+        telnet.take_reply();
+        assert_eq!(telnet.take_compress_start(), None);
+
+        telnet.receive(&[IAC, DO, COMPRESS2, IAC, DO, 200]);
+        assert_eq!(telnet.take_compress_start(), Some(5));
+        assert_eq!(telnet.take_reply(), vec![IAC, SB, COMPRESS2, IAC, SE, IAC, WONT, 200]);
+        assert_eq!(telnet.take_compress_start(), None);
+    }
+
+    #[test]
+    fn resume() {
+        let mut telnet = Telnet::new();
+        telnet.negotiate();
+        telnet.take_reply();
+        telnet.receive(&[IAC, DO, ECHO, IAC, WILL, NAWS]);
+        let (local, remote) = telnet.enabled_options();
+        assert_eq!((local.clone(), remote.clone()), (vec![ECHO], vec![NAWS]));
+
+        // Already agreed, so nothing is negotiated again.
+        let mut resumed = Telnet::resume(&local, &remote);
+        assert!(resumed.local_enabled(ECHO));
+        assert!(resumed.remote_enabled(NAWS));
+        resumed.receive(&[IAC, DO, ECHO]);
+        assert!(resumed.take_reply().is_empty());
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(&*escape(b"abc"), b"abc");
+        assert_eq!(&*escape(&[1, IAC, 2, IAC]), &[1, IAC, IAC, 2, IAC, IAC]);
+    }
+
+    #[test]
+    fn refuse_unsupported() {
+        let mut telnet = Telnet::new();
+        telnet.receive(&[IAC, WILL, 200, IAC, DO, 201]);
+        assert_eq!(telnet.take_reply(), vec![IAC, DONT, 200, IAC, WONT, 201]);
+        assert!(!telnet.remote_enabled(200));
+        assert!(!telnet.local_enabled(201));
+
+        // Repeating a refused request gets the same answer, not a loop.
+        telnet.receive(&[IAC, WILL, 200]);
+        assert_eq!(telnet.take_reply(), vec![IAC, DONT, 200]);
+    }
+
+    #[test]
+    fn queued_request() {
+        let mut telnet = Telnet::new();
+        telnet.enable_local(ECHO);
+        telnet.receive(&[IAC, DO, ECHO]);
+        telnet.take_reply();
+
+        // Changing our mind while the WONT is unanswered waits for the answer.
+        telnet.disable_local(ECHO);
+        telnet.enable_local(ECHO);
+        assert_eq!(telnet.take_reply(), vec![IAC, WONT, ECHO]);
+        telnet.receive(&[IAC, DONT, ECHO]);
+        assert_eq!(telnet.take_reply(), vec![IAC, WILL, ECHO]);
+        assert!(!telnet.local_enabled(ECHO));
+        telnet.receive(&[IAC, DO, ECHO]);
+        assert!(telnet.local_enabled(ECHO));
+        assert!(telnet.take_reply().is_empty());
+
+        // Changing it back again before the answer cancels the queued request.
+        telnet.disable_local(ECHO);
+        telnet.enable_local(ECHO);
+        telnet.disable_local(ECHO);
+        assert_eq!(telnet.take_reply(), vec![IAC, WONT, ECHO]);
+        telnet.receive(&[IAC, DONT, ECHO]);
+        assert!(telnet.take_reply().is_empty());
+        assert!(!telnet.local_enabled(ECHO));
+    }
+
+    #[test]
+    fn cr_nul() {
+        let mut telnet = Telnet::new();
+        assert_eq!(telnet.receive(b"a\r\0b\r\n"), b"a\rb\r\n");
+        // Split across reads.
+        assert_eq!(telnet.receive(b"\r"), b"\r");
+        assert_eq!(telnet.receive(b"\0c\0"), b"c\0");
+    }
+}
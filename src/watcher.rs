@@ -1,86 +1,303 @@
+use chrono::{DateTime, UTC};
+use libc;
 use mio::Token;
 use mio::tcp::TcpStream;
-use std::io::Error;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
 use std::io::Read;
 use std::io::Write;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::rc::Rc;
 
-use super::MenuView;
+use config::WatcherProtocol;
+use handover::{SavedState, SavedWatcher};
+use output::WatcherOutput;
+use playback::{Playback, PlaybackCommand};
+use ssh::SshSession;
+use telnet::{self, Telnet};
+use term;
+use web::WebSession;
+use super::{DisconnectReason, MENU_CHOICES, MENU_CHROME_LINES, MenuView, RecordingsView};
+
+// How far the seek keys move through a recording, in milliseconds.
+const SEEK_STEP: i64 = 10_000;
+// How far the time shift keys move through a live cast, in seconds.
+const TIME_SHIFT_STEP: i64 = 10;
+// Longest caster name a raw watcher can send.
+const MAX_NAME_LENGTH: usize = 256;
+// Assume the traditional terminal until the client says otherwise.
+const DEFAULT_WIDTH: u16 = 80;
+const DEFAULT_HEIGHT: u16 = 24;
 
 
 pub struct Watcher {
     pub state: WatcherState,
+    pub playback: Option<Playback>,
     offset: usize,
+    recordings_offset: usize,
+    // The recordings as last listed for the watcher, so choices refer to what they were shown.
+    recordings: Option<RecordingsView>,
     sock: TcpStream,
+    output: Rc<RefCell<WatcherOutput>>,
     input_buffer: [u8; 128],
+    // Bytes read from the socket before this watcher existed, still to be handled.
+    unread_input: Vec<u8>,
+    // Keys received but not yet acted on, with the telnet commands already stripped out.
+    pending_input: VecDeque<u8>,
+    protocol: WatcherProtocol,
+    telnet: Telnet,
+    // The first line from a raw watcher, naming the caster to watch.
+    name_buffer: Vec<u8>,
+    terminal: TerminalInfo,
     token: Token,
 }
 
 #[derive(Debug)]
 pub struct WatcherLite {
-    sock: TcpStream,
+    output: Rc<RefCell<WatcherOutput>>,
     token: Token,
+    // Set while the watcher is looking back through the history instead of the live stream.
+    pub time_shifted: bool,
+    pub terminal: TerminalInfo,
+}
+
+/// What the watcher's client has told us about its terminal.
+#[derive(Clone, Debug)]
+pub struct TerminalInfo {
+    pub width: u16,
+    pub height: u16,
+    pub terminal_type: Option<String>,
 }
 
 #[derive(Debug)]
 pub enum WatcherState {
+    AwaitingName,
     Connecting,
     Disconnecting,
     MainMenu,
+    RecordingsMenu,
+    Playback,
+    TimeShifted(Token, DateTime<UTC>),
     Watching(Token),
 }
 
 #[derive(Debug)]
 pub enum WatcherAction {
-    Exit,
+    Exit(DisconnectReason),
+    GoLive,
     Nothing,
+    Play(usize),
+    Playback(PlaybackCommand),
+    Resized,
+    ShowRecordings,
+    StopPlayback,
     StopWatching,
+    TimeShift(i64),
     Watch(usize),
+    WatchName(String),
 }
 
 
 impl Watcher {
-    pub fn new(token: Token, sock: TcpStream) -> Self {
-        Watcher {
+    pub fn new(token: Token, sock: TcpStream, protocol: WatcherProtocol) -> Result<Self, Error> {
+        let escape = protocol == WatcherProtocol::Telnet;
+        let output = WatcherOutput::new(try!(clone_socket(&sock)), escape);
+        Ok(Watcher {
             offset: 0,
+            recordings_offset: 0,
+            recordings: None,
+            playback: None,
             sock: sock,
+            output: Rc::new(RefCell::new(output)),
             input_buffer: [0; 128],
+            unread_input: Vec::new(),
+            pending_input: VecDeque::new(),
+            protocol: protocol,
+            telnet: Telnet::new(),
+            name_buffer: Vec::new(),
+            terminal: TerminalInfo::default(),
             token: token,
             state: WatcherState::Connecting,
+        })
+    }
+
+    /// A watcher connected to the SSH listener. They start at the main menu once their client
+    /// asks for a shell.
+    pub fn with_ssh(token: Token, sock: TcpStream, session: SshSession) -> Result<Self, Error> {
+        let mut watcher = try!(Watcher::new(token, sock, WatcherProtocol::Ssh));
+        let output = WatcherOutput::with_ssh(try!(clone_socket(&watcher.sock)), session);
+        watcher.output = Rc::new(RefCell::new(output));
+        Ok(watcher)
+    }
+
+    /// A watcher connected to the web listener. They start at the main menu once the WebSocket
+    /// is open.
+    pub fn with_web(token: Token, sock: TcpStream, session: WebSession) -> Result<Self, Error> {
+        let mut watcher = try!(Watcher::new(token, sock, WatcherProtocol::WebSocket));
+        let output = WatcherOutput::with_web(try!(clone_socket(&watcher.sock)), session);
+        watcher.output = Rc::new(RefCell::new(output));
+        Ok(watcher)
+    }
+
+    /// A watcher handed over by the server this one replaced. Their state still has to be
+    /// attached to the caster they were watching.
+    pub fn restore(saved: SavedWatcher, sock: TcpStream) -> Result<Self, Error> {
+        let mut watcher = try!(Watcher::new(Token(saved.token), sock, saved.protocol));
+        watcher.output.borrow_mut().restore_backlog(saved.backlog);
+        watcher.telnet = Telnet::resume(&saved.telnet_local, &saved.telnet_remote);
+        watcher.terminal = saved.terminal;
+        watcher.state = match saved.state {
+            SavedState::AwaitingName => WatcherState::AwaitingName,
+            SavedState::MainMenu => WatcherState::MainMenu,
+            SavedState::TimeShifted(caster, position) => WatcherState::TimeShifted(Token(caster), position),
+            SavedState::Watching(caster) => WatcherState::Watching(Token(caster)),
+        };
+        Ok(watcher)
+    }
+
+    /// Everything needed to carry on with this watcher in another server. None for watchers
+    /// whose connection has state that can't be handed over, or who are on their way out.
+    pub fn save(&self) -> Option<SavedWatcher> {
+        let output = self.output.borrow();
+        if output.has_session_state() {
+            return None;
         }
+        let state = match self.state {
+            WatcherState::AwaitingName => SavedState::AwaitingName,
+            WatcherState::Connecting | WatcherState::Disconnecting => return None,
+            WatcherState::MainMenu | WatcherState::RecordingsMenu | WatcherState::Playback => SavedState::MainMenu,
+            WatcherState::TimeShifted(caster, position) => SavedState::TimeShifted(caster.as_usize(), position),
+            WatcherState::Watching(caster) => SavedState::Watching(caster.as_usize()),
+        };
+        let (telnet_local, telnet_remote) = self.telnet.enabled_options();
+        Some(SavedWatcher {
+            token: self.token.as_usize(),
+            fd: self.sock.as_raw_fd(),
+            protocol: self.protocol,
+            state: state,
+            terminal: self.terminal.clone(),
+            telnet_local: telnet_local,
+            telnet_remote: telnet_remote,
+            backlog: output.backlog().to_vec(),
+        })
     }
 
     pub fn parse_input(&mut self, menu_view: &MenuView) -> WatcherAction {
-        while let Ok(num_bytes) = self.sock.read(&mut self.input_buffer) {
-            let each_byte = 0..num_bytes;
-            for (_offset, byte) in each_byte.zip(self.input_buffer.iter()) {
+        loop {
+            // Handle the keys left over from the last read before reading any more.
+            while let Some(byte) = self.pending_input.pop_front() {
                 match self.state {
                     WatcherState::Watching(_) => {
-                        // Pressing 'q' while watching returns the watcher to the main menu.
-                        if *byte == b'q' {
+                        match byte {
+                            // Pressing 'q' while watching returns the watcher to the main menu.
                             // This will reset the state back to the main menu.
-                            return WatcherAction::StopWatching;
+                            b'q' => return WatcherAction::StopWatching,
+                            b'<' => return WatcherAction::TimeShift(-TIME_SHIFT_STEP),
+                            _ => {},
+                        }
+                    },
+                    WatcherState::TimeShifted(_, _) => {
+                        match byte {
+                            b'q' => return WatcherAction::StopWatching,
+                            b'<' => return WatcherAction::TimeShift(-TIME_SHIFT_STEP),
+                            b'>' => return WatcherAction::TimeShift(TIME_SHIFT_STEP),
+                            b'l' => return WatcherAction::GoLive,
+                            _ => {},
                         }
                     },
                     WatcherState::MainMenu => {
-                        match *byte {
-                            b'a'...b'p' => {
-                                let page_offset = (*byte - b'a') as usize;
+                        match byte {
+                            b'a'...b'p' if ((byte - b'a') as usize) < self.terminal.menu_rows() => {
+                                let page_offset = (byte - b'a') as usize;
                                 let caster_offset = self.offset + page_offset;
                                 return WatcherAction::Watch(caster_offset);
                             }
                             b'q' => {
                                 self.state = WatcherState::Disconnecting;
-                                return WatcherAction::Exit;
+                                return WatcherAction::Exit(DisconnectReason::Quit);
+                            },
+                            b'r' if menu_view.has_recordings() => {
+                                self.state = WatcherState::RecordingsMenu;
+                                self.recordings_offset = 0;
+                                return WatcherAction::ShowRecordings;
+                            },
+                            b'>' => {
+                                self.offset += self.terminal.menu_rows();
+                                let _ = self.send_menu(&menu_view);
+                            },
+                            b'<' => {
+                                self.offset = self.offset.saturating_sub(self.terminal.menu_rows());
+                                let _ = self.send_menu(&menu_view);
                             },
                             // Any other character, refresh the menu.
                             _ => {
-                                // TODO: Replace rest of this block with the following line.
-                                //self.send_menu(&menu_view);
-                                let (menu, fixed_offset) = menu_view.render(self.offset);
-                                if let Some(offset) = fixed_offset {
-                                    self.offset = offset;
+                                let _ = self.send_menu(&menu_view);
+                            },
+                        }
+                    },
+                    WatcherState::RecordingsMenu => {
+                        match byte {
+                            b'a'...b'p' if ((byte - b'a') as usize) < self.terminal.menu_rows() => {
+                                let page_offset = (byte - b'a') as usize;
+                                return WatcherAction::Play(self.recordings_offset + page_offset);
+                            },
+                            b'>' => {
+                                self.recordings_offset += self.terminal.menu_rows();
+                                let _ = self.resend_recordings();
+                            },
+                            b'<' => {
+                                let page_length = self.terminal.menu_rows();
+                                self.recordings_offset = if self.recordings_offset > page_length {
+                                    self.recordings_offset - page_length
+                                }
+                                else {
+                                    0
+                                };
+                                let _ = self.resend_recordings();
+                            },
+                            b'q' => {
+                                self.state = WatcherState::MainMenu;
+                                self.recordings = None;
+                                let _ = self.send_menu(&menu_view);
+                            },
+                            _ => {
+                                let _ = self.resend_recordings();
+                            },
+                        }
+                    },
+                    WatcherState::Playback => {
+                        match byte {
+                            b' ' => return WatcherAction::Playback(PlaybackCommand::TogglePause),
+                            b'+' => return WatcherAction::Playback(PlaybackCommand::Faster),
+                            b'-' => return WatcherAction::Playback(PlaybackCommand::Slower),
+                            b'>' => return WatcherAction::Playback(PlaybackCommand::Seek(SEEK_STEP)),
+                            b'<' => return WatcherAction::Playback(PlaybackCommand::Seek(-SEEK_STEP)),
+                            b'q' => return WatcherAction::StopPlayback,
+                            _ => {},
+                        }
+                    },
+                    WatcherState::AwaitingName => {
+                        match byte {
+                            b'\n' => {
+                                let name = String::from_utf8_lossy(&self.name_buffer).trim().to_string();
+                                self.name_buffer.clear();
+                                if name.is_empty() {
+                                    self.state = WatcherState::MainMenu;
+                                    let _ = self.send_menu(&menu_view);
+                                }
+                                else {
+                                    return WatcherAction::WatchName(name);
+                                }
+                            },
+                            _ => {
+                                if self.name_buffer.len() >= MAX_NAME_LENGTH {
+                                    self.state = WatcherState::Disconnecting;
+                                    return WatcherAction::Exit(DisconnectReason::IoError);
                                 }
-                                self.sock.write(&menu.as_bytes());
+                                self.name_buffer.push(byte);
                             },
                         }
                     },
@@ -88,28 +305,282 @@ impl Watcher {
                     WatcherState::Disconnecting => { return WatcherAction::Nothing },
                 }
             }
+
+            // Bytes read before the watcher was set up come before anything still in the socket.
+            let input = if !self.unread_input.is_empty() {
+                mem::replace(&mut self.unread_input, Vec::new())
+            }
+            else {
+                match self.sock.read(&mut self.input_buffer) {
+                    Ok(0) => {
+                        self.state = WatcherState::Disconnecting;
+                        return WatcherAction::Exit(DisconnectReason::Hangup);
+                    },
+                    Ok(num_bytes) => self.input_buffer[..num_bytes].to_vec(),
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(_) => {
+                        self.state = WatcherState::Disconnecting;
+                        return WatcherAction::Exit(DisconnectReason::IoError);
+                    },
+                }
+            };
+
+            match self.protocol {
+                WatcherProtocol::Raw => {
+                    self.pending_input.extend(&input);
+                },
+                WatcherProtocol::Ssh => {
+                    match self.receive_ssh(&input, menu_view) {
+                        WatcherAction::Nothing => {},
+                        action => return action,
+                    }
+                },
+                WatcherProtocol::WebSocket => {
+                    match self.receive_web(&input, menu_view) {
+                        WatcherAction::Nothing => {},
+                        action => return action,
+                    }
+                },
+                WatcherProtocol::Telnet => {
+                    let keys = self.telnet.receive(&input);
+                    self.pending_input.extend(keys);
+                    let _ = self.send_telnet_reply();
+                    if self.telnet.take_terminal_changed() {
+                        let window_size = self.telnet.window_size();
+                        let terminal_type = self.telnet.terminal_type().map(String::from);
+                        self.update_terminal(window_size, terminal_type);
+                        return WatcherAction::Resized;
+                    }
+                },
+            }
         }
 
         return WatcherAction::Nothing;
     }
 
-    pub fn send_menu(&mut self, menu_view: &MenuView) -> Result<usize, Error> {
-        let (menu, fixed_offset) = menu_view.render(self.offset);
-        if let Some(offset) = fixed_offset {
-            self.offset = offset;
+    /// Hand over bytes already read from the socket, to be handled by the next `parse_input`
+    /// before anything else.
+    pub fn unread(&mut self, input: Vec<u8>) {
+        self.unread_input = input;
+    }
+
+    /// Start negotiating the telnet options termcastd needs, or send the SSH greeting. Raw
+    /// watchers skip this entirely.
+    pub fn negotiate(&mut self) -> Result<usize, Error> {
+        match self.protocol {
+            WatcherProtocol::Raw | WatcherProtocol::WebSocket => Ok(0),
+            WatcherProtocol::Ssh => {
+                try!(self.output.borrow_mut().send_session_reply());
+                Ok(0)
+            },
+            WatcherProtocol::Telnet => {
+                self.telnet.negotiate();
+                self.send_telnet_reply()
+            },
+        }
+    }
+
+    pub fn protocol(&self) -> WatcherProtocol {
+        self.protocol
+    }
+
+    /// Send a line of text outside of any menu.
+    pub fn send_message(&mut self, message: &str) -> Result<usize, Error> {
+        try!(self.output.borrow_mut().write_raw(message.as_bytes()));
+        Ok(message.len())
+    }
+
+    /// Send the last thing the watcher will see. Telnet clients are first given their own echo and
+    /// line editing back, so the terminal is usable once the connection closes.
+    pub fn send_goodbye(&mut self, message: &str) -> Result<usize, Error> {
+        if self.protocol == WatcherProtocol::Telnet {
+            self.telnet.disable_local(telnet::ECHO);
+            self.telnet.disable_local(telnet::SUPPRESS_GO_AHEAD);
+            try!(self.send_telnet_reply());
+        }
+        self.send_message(message)
+    }
+
+    /// The options negotiated with the watcher's telnet client.
+    pub fn telnet(&self) -> &Telnet {
+        &self.telnet
+    }
+
+    pub fn terminal(&self) -> &TerminalInfo {
+        &self.terminal
+    }
+
+    fn update_terminal(&mut self, window_size: Option<(u16, u16)>, terminal_type: Option<String>) {
+        // A size of 0 means the client doesn't know it.
+        if let Some((width, height)) = window_size {
+            self.terminal.width = if width > 0 { width } else { DEFAULT_WIDTH };
+            self.terminal.height = if height > 0 { height } else { DEFAULT_HEIGHT };
+        }
+        self.terminal.terminal_type = terminal_type;
+    }
+
+    // Pass input from an SSH watcher through their session and act on what their client asked for.
+    fn receive_ssh(&mut self, input: &[u8], menu_view: &MenuView) -> WatcherAction {
+        let (shell_started, terminal) = {
+            let mut output = self.output.borrow_mut();
+            let keys = match output.receive_ssh(input) {
+                Ok(keys) => keys,
+                Err(_) => {
+                    self.state = WatcherState::Disconnecting;
+                    return WatcherAction::Exit(DisconnectReason::IoError);
+                },
+            };
+            let session = match output.ssh() {
+                Some(session) if !session.is_closed() => session,
+                _ => {
+                    self.state = WatcherState::Disconnecting;
+                    return WatcherAction::Exit(DisconnectReason::Quit);
+                },
+            };
+            self.pending_input.extend(keys);
+
+            let terminal = if session.take_terminal_changed() {
+                Some((session.window_size(), session.terminal_type().map(String::from)))
+            }
+            else {
+                None
+            };
+            (session.take_shell_started(), terminal)
+        };
+
+        self.session_started(shell_started, terminal, menu_view)
+    }
+
+    // Pass input from a web watcher through their session, the same way as for SSH.
+    fn receive_web(&mut self, input: &[u8], menu_view: &MenuView) -> WatcherAction {
+        let (websocket_started, terminal) = {
+            let mut output = self.output.borrow_mut();
+            let keys = match output.receive_web(input) {
+                Ok(keys) => keys,
+                Err(_) => {
+                    self.state = WatcherState::Disconnecting;
+                    return WatcherAction::Exit(DisconnectReason::IoError);
+                },
+            };
+            let session = match output.web() {
+                Some(session) if !session.is_closed() => session,
+                _ => {
+                    self.state = WatcherState::Disconnecting;
+                    return WatcherAction::Exit(DisconnectReason::Quit);
+                },
+            };
+            self.pending_input.extend(keys);
+
+            let terminal = if session.take_terminal_changed() {
+                Some((session.window_size(), None))
+            }
+            else {
+                None
+            };
+            (session.take_websocket_started(), terminal)
+        };
+
+        self.session_started(websocket_started, terminal, menu_view)
+    }
+
+    // Apply a terminal change from an SSH or web session, and show the menu once the session is
+    // ready for it.
+    fn session_started(&mut self, started: bool,
+                       terminal: Option<(Option<(u16, u16)>, Option<String>)>,
+                       menu_view: &MenuView) -> WatcherAction {
+        let resized = terminal.is_some();
+        if let Some((window_size, terminal_type)) = terminal {
+            self.update_terminal(window_size, terminal_type);
+        }
+        if started {
+            if let WatcherState::Connecting = self.state {
+                self.state = WatcherState::MainMenu;
+                let _ = self.send_menu(menu_view);
+                return WatcherAction::Nothing;
+            }
         }
-        self.sock.write(&menu.as_bytes())
+        if resized { WatcherAction::Resized } else { WatcherAction::Nothing }
+    }
+
+    fn send_telnet_reply(&mut self) -> Result<usize, Error> {
+        let compress_start = self.telnet.take_compress_start();
+        let reply = self.telnet.take_reply();
+        if reply.is_empty() {
+            return Ok(0);
+        }
+
+        let mut output = self.output.borrow_mut();
+        if let Some(compress_start) = compress_start {
+            try!(output.write_raw(&reply[..compress_start]));
+            output.start_compression();
+            try!(output.write_raw(&reply[compress_start..]));
+        }
+        else {
+            try!(output.write_raw(&reply));
+        }
+        Ok(reply.len())
+    }
+
+    pub fn send_menu(&mut self, menu_view: &MenuView) -> Result<usize, Error> {
+        let (menu, offset) = menu_view.render(self.offset, &self.terminal);
+        self.offset = offset;
+        try!(self.output.borrow_mut().write_raw(&menu.as_bytes()));
+        Ok(menu.len())
+    }
+
+    /// Show the watcher a new listing of the recordings, which their choices then refer to.
+    pub fn send_recordings(&mut self, recordings_view: RecordingsView) -> Result<usize, Error> {
+        self.recordings = Some(recordings_view);
+        self.resend_recordings()
+    }
+
+    /// Show the listing the watcher was last sent again, such as after changing page.
+    pub fn resend_recordings(&mut self) -> Result<usize, Error> {
+        let menu = match self.recordings {
+            Some(ref recordings_view) => {
+                let (menu, offset) = recordings_view.render(self.recordings_offset, &self.terminal);
+                self.recordings_offset = offset;
+                menu
+            },
+            None => return Ok(0),
+        };
+        try!(self.output.borrow_mut().write_raw(&menu.as_bytes()));
+        Ok(menu.len())
+    }
+
+    /// The file name of the recording at `offset` in the listing the watcher was shown.
+    pub fn recording_file_name(&self, offset: usize) -> Option<&String> {
+        self.recordings.as_ref().and_then(|recordings_view| recordings_view.get_offset_file_name(offset))
+    }
+
+    /// Send anything that couldn't be written earlier, now that the socket is writable again.
+    pub fn flush_output(&mut self) -> Result<(), Error> {
+        self.output.borrow_mut().flush_backlog()
+    }
+
+    /// Whether everything sent to the watcher has made it to their socket.
+    pub fn output_flushed(&self) -> bool {
+        !self.output.borrow().has_backlog()
     }
 
     pub fn caster_copy(&mut self) -> Result<WatcherLite, Error> {
-        let socket = try!(self.sock.try_clone());
+        let time_shifted = match self.state {
+            WatcherState::TimeShifted(_, _) => true,
+            _ => false,
+        };
         let lite = WatcherLite {
-            sock: socket,
+            output: self.output.clone(),
             token: self.token,
+            time_shifted: time_shifted,
+            terminal: self.terminal.clone(),
         };
         Ok(lite)
     }
 
+    pub fn take_short_writes(&self) -> u64 {
+        self.output.borrow_mut().take_short_writes()
+    }
+
     pub fn sock(&self) -> &TcpStream {
         &self.sock
     }
@@ -120,12 +591,47 @@ impl Watcher {
 }
 
 impl Write for Watcher {
+    // Only cast data goes through here. Menus and telnet commands are written with write_raw so
+    // they are not escaped.
     fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
-        self.sock.write(buf)
+        try!(self.output.borrow_mut().write_data(buf));
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> Result<(), Error> {
-        self.sock.flush()
+        Ok(())
+    }
+}
+
+impl TerminalInfo {
+    /// How many entries fit in a menu on this terminal. Never more than there are menu keys.
+    pub fn menu_rows(&self) -> usize {
+        let rows = (self.height as usize).saturating_sub(MENU_CHROME_LINES);
+        cmp::max(1, cmp::min(rows, MENU_CHOICES.len()))
+    }
+
+    /// Terminals known not to understand ANSI escape codes.
+    pub fn is_dumb(&self) -> bool {
+        self.terminal_type.as_ref().map(|t| t == "dumb").unwrap_or(false)
+    }
+
+    /// Clears the screen, or starts a new line on a dumb terminal.
+    pub fn clear_screen(&self) -> &'static str {
+        if self.is_dumb() { "\r\n" } else { term::clear_screen() }
+    }
+
+    pub fn reset_cursor(&self) -> &'static str {
+        if self.is_dumb() { "" } else { term::reset_cursor() }
+    }
+}
+
+impl Default for TerminalInfo {
+    fn default() -> Self {
+        TerminalInfo {
+            width: DEFAULT_WIDTH,
+            height: DEFAULT_HEIGHT,
+            terminal_type: None,
+        }
     }
 }
 
@@ -133,14 +639,29 @@ impl WatcherLite {
     pub fn token(&self) -> Token {
         self.token
     }
+
+    pub fn take_short_writes(&self) -> u64 {
+        self.output.borrow_mut().take_short_writes()
+    }
 }
 
 impl Write for WatcherLite {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
-        self.sock.write(buf)
+        try!(self.output.borrow_mut().write_data(buf));
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> Result<(), Error> {
-        self.sock.flush()
+        Ok(())
+    }
+}
+
+// The copy of the socket output is written through. dup() doesn't carry close-on-exec over, and a
+// copy left open across an upgrade would keep the watcher connected after the new server hangs up.
+fn clone_socket(sock: &TcpStream) -> Result<TcpStream, Error> {
+    let clone = try!(sock.try_clone());
+    if unsafe { libc::fcntl(clone.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(Error::last_os_error());
     }
+    Ok(clone)
 }
@@ -0,0 +1,457 @@
+// The web frontend: a page with a terminal emulator, and the WebSocket it connects back to. Once
+// upgraded, the socket carries the same menus and cast data a telnet watcher would see.
+
+mod websocket;
+
+use std::mem;
+use std::str;
+
+use self::websocket::{OPCODE_BINARY, OPCODE_CLOSE, OPCODE_CONTINUATION, OPCODE_PING, OPCODE_PONG,
+                      OPCODE_TEXT};
+
+const INDEX_HTML: &'static str = include_str!("index.html");
+// Longest HTTP request head accepted before giving up on the client.
+const MAX_REQUEST_LENGTH: usize = 8192;
+const WEBSOCKET_PATH: &'static str = "/ws";
+// Longest text message from a watcher, however many frames it comes in. Resizes are tiny.
+const MAX_TEXT_LENGTH: usize = 1024;
+// Request line starts that mark a connection to the watcher port as HTTP.
+const HTTP_METHODS: [&'static [u8]; 6] = [b"GET ", b"HEAD ", b"POST ", b"PUT ", b"DELETE ",
+                                          b"OPTIONS "];
+
+
+/// One connection to the web listener. Bytes from the socket go in through `receive`, and
+/// everything that has to go back out collects in the reply buffer until `take_reply`.
+#[derive(Debug)]
+pub struct WebSession {
+    // Casters get no page, and their text messages are cast data like anything else.
+    caster: bool,
+    // A caster's first message is their hello line rather than cast data.
+    hello_pending: bool,
+    input: Vec<u8>,
+    reply: Vec<u8>,
+    upgraded: bool,
+    // A text message being put together from several frames.
+    text: Option<Vec<u8>>,
+    // Opcode of the fragmented message in progress, if any.
+    fragment_opcode: Option<u8>,
+    window_size: Option<(u16, u16)>,
+    terminal_changed: bool,
+    websocket_started: bool,
+    closed: bool,
+}
+
+/// The parts of an HTTP request termcastd looks at.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, String)>,
+}
+
+
+impl WebSession {
+    pub fn new() -> Self {
+        WebSession {
+            caster: false,
+            hello_pending: false,
+            input: Vec::new(),
+            reply: Vec::new(),
+            upgraded: false,
+            text: None,
+            fragment_opcode: None,
+            window_size: None,
+            terminal_changed: false,
+            websocket_started: false,
+            closed: false,
+        }
+    }
+
+    /// A session for a caster. The first text message is their `hello` line, and the cast itself
+    /// follows in binary messages.
+    pub fn for_caster() -> Self {
+        let mut session = WebSession::new();
+        session.caster = true;
+        session.hello_pending = true;
+        session
+    }
+
+    /// Process bytes from the browser, returning the keys typed into the terminal, or for casters
+    /// the cast data. An error means the connection is unusable and should be dropped.
+    pub fn receive(&mut self, input: &[u8]) -> Result<Vec<u8>, ()> {
+        self.input.extend_from_slice(input);
+        let mut keys = Vec::new();
+
+        if !self.upgraded {
+            try!(self.read_request());
+        }
+
+        while self.upgraded && !self.closed {
+            let frame = match try!(websocket::decode(&mut self.input)) {
+                Some(frame) => frame,
+                None => break,
+            };
+
+            // Continuation frames carry on whatever message was started before them. Control
+            // frames can come in between the fragments, but can't be fragmented themselves.
+            let opcode = if frame.opcode == OPCODE_CONTINUATION {
+                try!(self.fragment_opcode.ok_or(()))
+            }
+            else {
+                frame.opcode
+            };
+            if opcode & 0x08 != 0 {
+                if !frame.fin {
+                    return Err(());
+                }
+            }
+            else if frame.opcode != OPCODE_CONTINUATION && self.fragment_opcode.is_some() {
+                return Err(());
+            }
+            else {
+                self.fragment_opcode = if frame.fin { None } else { Some(opcode) };
+            }
+
+            match opcode {
+                OPCODE_BINARY => {
+                    keys.extend(frame.payload);
+                    if frame.fin {
+                        self.hello_pending = false;
+                    }
+                },
+                OPCODE_TEXT if self.caster => {
+                    keys.extend(frame.payload);
+                    // The hello line has to end in a newline, which a text message needn't. Later
+                    // text messages are cast data and are passed on untouched.
+                    if frame.fin && mem::replace(&mut self.hello_pending, false) &&
+                       keys.last() != Some(&b'\n') {
+                        keys.push(b'\n');
+                    }
+                },
+                OPCODE_TEXT => {
+                    let mut text = self.text.take().unwrap_or(Vec::new());
+                    if text.len() + frame.payload.len() > MAX_TEXT_LENGTH {
+                        return Err(());
+                    }
+                    text.extend(frame.payload);
+                    if frame.fin {
+                        self.handle_text(&text);
+                    }
+                    else {
+                        self.text = Some(text);
+                    }
+                },
+                OPCODE_PING => {
+                    self.reply.extend(websocket::encode(OPCODE_PONG, &frame.payload));
+                },
+                OPCODE_CLOSE => {
+                    self.reply.extend(websocket::encode(OPCODE_CLOSE, &frame.payload));
+                    self.closed = true;
+                },
+                OPCODE_PONG => {},
+                _ => return Err(()),
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Send menus or cast data to the browser. Nothing is sent before the WebSocket is open.
+    pub fn send_data(&mut self, data: &[u8]) {
+        if self.upgraded && !self.closed {
+            self.reply.extend(websocket::encode(OPCODE_BINARY, data));
+        }
+    }
+
+    /// Everything that needs to be sent to the browser since the last call.
+    pub fn take_reply(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.reply, Vec::new())
+    }
+
+    /// Terminal width and height from the browser's last resize message.
+    pub fn window_size(&self) -> Option<(u16, u16)> {
+        self.window_size
+    }
+
+    /// Whether the window size changed since the last call.
+    pub fn take_terminal_changed(&mut self) -> bool {
+        mem::replace(&mut self.terminal_changed, false)
+    }
+
+    /// Whether the WebSocket opened since the last call.
+    pub fn take_websocket_started(&mut self) -> bool {
+        mem::replace(&mut self.websocket_started, false)
+    }
+
+    /// The page has been served, or the WebSocket closed.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    fn read_request(&mut self) -> Result<(), ()> {
+        let request = match try!(Request::read(&mut self.input)) {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+
+        if request.method != "GET" {
+            self.respond("405 Method Not Allowed", "text/plain", "Method not allowed.\n");
+        }
+        else if !self.caster && (request.path == "/" || request.path == "/index.html") {
+            self.respond("200 OK", "text/html; charset=utf-8", INDEX_HTML);
+        }
+        else if self.caster || request.path == WEBSOCKET_PATH {
+            match request.websocket_key() {
+                Some(key) => {
+                    self.reply.extend_from_slice(format!(
+                        concat!(
+                            "HTTP/1.1 101 Switching Protocols\r\n",
+                            "Upgrade: websocket\r\n",
+                            "Connection: Upgrade\r\n",
+                            "Sec-WebSocket-Accept: {}\r\n\r\n",
+                        ),
+                        websocket::accept_key(key)).as_bytes());
+                    self.upgraded = true;
+                    self.websocket_started = true;
+                },
+                None => {
+                    self.respond("400 Bad Request", "text/plain", "Expected a WebSocket upgrade.\n");
+                },
+            }
+        }
+        else {
+            self.respond("404 Not Found", "text/plain", "Not found.\n");
+        }
+        Ok(())
+    }
+
+    // Send a whole response and close the connection after it.
+    fn respond(&mut self, status: &str, content_type: &str, body: &str) {
+        self.reply.extend(response(status, content_type, body));
+        self.closed = true;
+    }
+
+    // The page sends "resize <columns> <rows>" whenever the terminal changes size. Keys go in
+    // binary messages.
+    fn handle_text(&mut self, text: &[u8]) {
+        let text = String::from_utf8_lossy(text);
+        let mut words = text.split_whitespace();
+        if words.next() != Some("resize") {
+            return;
+        }
+        let width = words.next().and_then(|w| w.parse::<u16>().ok());
+        let height = words.next().and_then(|h| h.parse::<u16>().ok());
+        if let (Some(width), Some(height)) = (width, height) {
+            if width > 0 && height > 0 {
+                self.window_size = Some((width, height));
+                self.terminal_changed = true;
+            }
+        }
+    }
+}
+
+/// Whether the first bytes of a connection are an HTTP request line. None means there isn't
+/// enough yet to tell.
+pub fn looks_like_http(input: &[u8]) -> Option<bool> {
+    let mut undecided = false;
+    for method in HTTP_METHODS.iter() {
+        if input.starts_with(method) {
+            return Some(true);
+        }
+        if method.starts_with(input) {
+            undecided = true;
+        }
+    }
+    if undecided { None } else { Some(false) }
+}
+
+/// A whole HTTP response, after which the connection is closed.
+pub fn response(status: &str, content_type: &str, body: &str) -> Vec<u8> {
+    format!(
+        concat!(
+            "HTTP/1.1 {}\r\n",
+            "Content-Type: {}\r\n",
+            "Content-Length: {}\r\n",
+            "Connection: close\r\n\r\n",
+            "{}",
+        ),
+        status, content_type, body.len(), body).into_bytes()
+}
+
+impl Request {
+    /// Take a complete request head out of `input`. Ok(None) means more input is needed.
+    pub fn read(input: &mut Vec<u8>) -> Result<Option<Self>, ()> {
+        let end = match input.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => end + 4,
+            None if input.len() > MAX_REQUEST_LENGTH => return Err(()),
+            None => return Ok(None),
+        };
+        let head: Vec<u8> = input.drain(..end).collect();
+        let head = try!(str::from_utf8(&head).map_err(|_| ()));
+        Request::parse(head).map(Some)
+    }
+
+    fn parse(head: &str) -> Result<Self, ()> {
+        let mut lines = head.split("\r\n");
+        let mut request_line = try!(lines.next().ok_or(())).split(' ');
+        let method = try!(request_line.next().ok_or(()));
+        let target = try!(request_line.next().ok_or(()));
+        // Query strings don't mean anything here.
+        let path = target.split('?').next().unwrap_or(target);
+
+        let headers = lines.filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => Some((name.trim().to_lowercase(),
+                                                   String::from(value.trim()))),
+                _ => None,
+            }
+        }).collect();
+
+        Ok(Request {
+            method: String::from(method),
+            path: String::from(path),
+            headers: headers,
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        self.headers.iter()
+                    .find(|&&(ref header, _)| *header == name)
+                    .map(|&(_, ref value)| &value[..])
+    }
+
+    fn header_contains(&self, name: &str, token: &str) -> bool {
+        self.header(name)
+            .map(|value| value.split(',').any(|v| v.trim().to_lowercase() == token))
+            .unwrap_or(false)
+    }
+
+    // The client's key, if this is a WebSocket handshake termcastd can accept.
+    fn websocket_key(&self) -> Option<&str> {
+        if !self.header_contains("Upgrade", "websocket") ||
+           !self.header_contains("Connection", "upgrade") ||
+           self.header("Sec-WebSocket-Version") != Some("13") {
+            return None;
+        }
+        self.header("Sec-WebSocket-Key")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WebSession, looks_like_http};
+
+    const UPGRADE: &'static [u8] = b"GET /ws HTTP/1.1\r\n\
+                                     Host: localhost\r\n\
+                                     Upgrade: websocket\r\n\
+                                     Connection: keep-alive, Upgrade\r\n\
+                                     Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                                     Sec-WebSocket-Version: 13\r\n\r\n";
+
+    fn masked(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8, 1, 2, 3, 4];
+        frame.extend(payload.iter().enumerate().map(|(i, &b)| b ^ (i as u8 % 4 + 1)));
+        frame
+    }
+
+    #[test]
+    fn sniffing() {
+        assert_eq!(looks_like_http(b""), None);
+        assert_eq!(looks_like_http(b"GE"), None);
+        assert_eq!(looks_like_http(b"GET / HTTP/1.1"), Some(true));
+        assert_eq!(looks_like_http(b"OPTIONS *"), Some(true));
+        assert_eq!(looks_like_http(b"\xff\xfd\x18"), Some(false));
+        assert_eq!(looks_like_http(b"GEX"), Some(false));
+        assert_eq!(looks_like_http(b"caster1\n"), Some(false));
+    }
+
+    #[test]
+    fn caster_session() {
+        let mut session = WebSession::for_caster();
+        let mut input = UPGRADE.to_vec();
+        input.extend(masked(1, b"hello name pass"));
+        input.extend(masked(2, b"cast"));
+        assert_eq!(session.receive(&input), Ok(b"hello name pass\ncast".to_vec()));
+        assert!(session.take_reply().starts_with(b"HTTP/1.1 101"));
+        // Only the hello line gets a newline added.
+        assert_eq!(session.receive(&masked(1, b"text")), Ok(b"text".to_vec()));
+
+        let mut session = WebSession::for_caster();
+        session.receive(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(session.take_reply().starts_with(b"HTTP/1.1 400"));
+    }
+
+    #[test]
+    fn serves_page() {
+        let mut session = WebSession::new();
+        assert_eq!(session.receive(b"GET / HTTP/1.1\r\n"), Ok(vec![]));
+        assert!(session.take_reply().is_empty());
+        assert_eq!(session.receive(b"Host: localhost\r\n\r\n"), Ok(vec![]));
+        let reply = session.take_reply();
+        assert!(reply.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(session.is_closed());
+
+        let mut session = WebSession::new();
+        session.receive(b"GET /nothing HTTP/1.1\r\n\r\n").unwrap();
+        assert!(session.take_reply().starts_with(b"HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn upgrade_and_frames() {
+        let mut session = WebSession::new();
+        let mut input = UPGRADE.to_vec();
+        input.extend(masked(2, b"a"));
+        assert_eq!(session.receive(&input), Ok(b"a".to_vec()));
+        assert!(session.take_websocket_started());
+        let reply = session.take_reply();
+        let reply = String::from_utf8_lossy(&reply);
+        assert!(reply.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(reply.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        assert_eq!(session.receive(&masked(1, b"resize 120 40")), Ok(vec![]));
+        assert!(session.take_terminal_changed());
+        assert_eq!(session.window_size(), Some((120, 40)));
+
+        session.send_data(b"menu");
+        assert_eq!(session.take_reply(), vec![0x82, 4, b'm', b'e', b'n', b'u']);
+
+        session.receive(&masked(9, b"hi")).unwrap();
+        assert_eq!(session.take_reply(), vec![0x8a, 2, b'h', b'i']);
+        session.receive(&masked(8, b"")).unwrap();
+        assert!(session.is_closed());
+    }
+
+    #[test]
+    fn fragments() {
+        let mut session = WebSession::new();
+        session.receive(UPGRADE).unwrap();
+        session.take_reply();
+
+        // A ping between the fragments is answered and leaves the message going.
+        let mut input = masked(1, b"resize ");
+        input[0] &= 0x7f;
+        input.extend(masked(9, b""));
+        input.extend(masked(0, b"100 30"));
+        assert_eq!(session.receive(&input), Ok(vec![]));
+        assert_eq!(session.take_reply(), vec![0x8a, 0]);
+        assert_eq!(session.window_size(), Some((100, 30)));
+
+        // A new message can't start before the last one is finished.
+        let mut input = masked(1, b"resize ");
+        input[0] &= 0x7f;
+        input.extend(masked(2, b"a"));
+        assert_eq!(session.receive(&input), Err(()));
+
+        // Nor can text messages grow without end.
+        let mut session = WebSession::new();
+        session.receive(UPGRADE).unwrap();
+        let mut fragment = masked(1, &[b' '; 100]);
+        fragment[0] &= 0x7f;
+        let mut result = Ok(vec![]);
+        for _ in 0..11 {
+            result = session.receive(&fragment);
+            fragment[0] = 0;
+        }
+        assert_eq!(result, Err(()));
+    }
+}
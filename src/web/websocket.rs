@@ -0,0 +1,226 @@
+// WebSocket framing (RFC 6455), along with the SHA-1 and base64 the opening handshake needs.
+
+const GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Largest frame a browser may send. Keystrokes and resizes are tiny.
+const MAX_FRAME_LENGTH: u64 = 65536;
+
+pub const OPCODE_CONTINUATION: u8 = 0;
+pub const OPCODE_TEXT: u8 = 1;
+pub const OPCODE_BINARY: u8 = 2;
+pub const OPCODE_CLOSE: u8 = 8;
+pub const OPCODE_PING: u8 = 9;
+pub const OPCODE_PONG: u8 = 10;
+
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// The Sec-WebSocket-Accept value for a client's Sec-WebSocket-Key.
+pub fn accept_key(key: &str) -> String {
+    let mut input = String::from(key.trim());
+    input.push_str(GUID);
+    base64(&sha1(input.as_bytes()))
+}
+
+/// A single unmasked frame, as servers send them.
+pub fn encode(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    let length = payload.len();
+    if length < 126 {
+        frame.push(length as u8);
+    }
+    else if length < 65536 {
+        frame.push(126);
+        frame.push((length >> 8) as u8);
+        frame.push(length as u8);
+    }
+    else {
+        frame.push(127);
+        for shift in (0..8).rev() {
+            frame.push(((length as u64) >> (shift * 8)) as u8);
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Take the next complete frame out of `input`. Ok(None) means more input is needed. Frames from
+/// clients have to be masked.
+pub fn decode(input: &mut Vec<u8>) -> Result<Option<Frame>, ()> {
+    if input.len() < 2 {
+        return Ok(None);
+    }
+    let fin = input[0] & 0x80 != 0;
+    let opcode = input[0] & 0x0f;
+    if input[0] & 0x70 != 0 || input[1] & 0x80 == 0 {
+        return Err(());
+    }
+
+    let (length, mut position) = match input[1] & 0x7f {
+        126 => {
+            if input.len() < 4 {
+                return Ok(None);
+            }
+            ((input[2] as u64) << 8 | input[3] as u64, 4)
+        },
+        127 => {
+            if input.len() < 10 {
+                return Ok(None);
+            }
+            (input[2..10].iter().fold(0, |length, &b| length << 8 | b as u64), 10)
+        },
+        length => (length as u64, 2),
+    };
+    if length > MAX_FRAME_LENGTH {
+        return Err(());
+    }
+
+    let length = length as usize;
+    if input.len() < position + 4 + length {
+        return Ok(None);
+    }
+    let mut mask = [0; 4];
+    mask.copy_from_slice(&input[position..position + 4]);
+    position += 4;
+
+    let payload = input[position..position + length].iter().enumerate()
+                      .map(|(i, &b)| b ^ mask[i % 4])
+                      .collect();
+    input.drain(..position + length);
+    Ok(Some(Frame {
+        fin: fin,
+        opcode: opcode,
+        payload: payload,
+    }))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    let bit_length = (data.len() as u64) * 8;
+    for shift in (0..8).rev() {
+        message.push((bit_length >> (shift * 8)) as u8);
+    }
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = (block[i * 4] as u32) << 24 | (block[i * 4 + 1] as u32) << 16 |
+                   (block[i * 4 + 2] as u32) << 8 | block[i * 4 + 3] as u32;
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for i in 0..80 {
+            let (f, k) = match i {
+                0...19 => ((b & c) | (!b & d), 0x5A827999),
+                20...39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40...59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e)
+                        .wrapping_add(k).wrapping_add(w[i]);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut digest = [0; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4] = (word >> 24) as u8;
+        digest[i * 4 + 1] = (word >> 16) as u8;
+        digest[i * 4 + 2] = (word >> 8) as u8;
+        digest[i * 4 + 3] = *word as u8;
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as u32) << 16 |
+                (*chunk.get(1).unwrap_or(&0) as u32) << 8 |
+                *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - i * 6)) as usize & 0x3f] as char);
+            }
+            else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Frame, OPCODE_BINARY, OPCODE_TEXT, accept_key, base64, decode, encode, sha1};
+
+    #[test]
+    fn handshake_key() {
+        // The example from RFC 6455.
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn sha1_digests() {
+        assert_eq!(&sha1(b"abc")[..4], &[0xa9, 0x99, 0x3e, 0x36]);
+        assert_eq!(&sha1(&[b'a'; 1000])[16..], &[0xfc, 0x36, 0xb1, 0xba]);
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+    }
+
+    #[test]
+    fn encode_lengths() {
+        assert_eq!(encode(OPCODE_BINARY, b"hi"), vec![0x82, 2, b'h', b'i']);
+        let frame = encode(OPCODE_BINARY, &[0; 300]);
+        assert_eq!(&frame[..4], &[0x82, 126, 1, 44]);
+        let frame = encode(OPCODE_BINARY, &[0; 70000]);
+        assert_eq!(&frame[..10], &[0x82, 127, 0, 0, 0, 0, 0, 1, 0x11, 0x70]);
+    }
+
+    #[test]
+    fn decode_masked() {
+        // "Hello" from RFC 6455 section 5.7, sent in two pieces.
+        let mut input = vec![0x81, 0x85, 0x37, 0xfa];
+        assert_eq!(decode(&mut input), Ok(None));
+        input.extend_from_slice(&[0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]);
+        assert_eq!(decode(&mut input), Ok(Some(Frame {
+            fin: true,
+            opcode: OPCODE_TEXT,
+            payload: b"Hello".to_vec(),
+        })));
+        assert!(input.is_empty());
+
+        // Unmasked frames from a client are an error.
+        let mut input = vec![0x81, 0x05, b'H', b'e', b'l', b'l', b'o'];
+        assert!(decode(&mut input).is_err());
+    }
+}
@@ -1,14 +1,22 @@
 extern crate mio;
 extern crate termcastd;
 
+use std::env;
+use std::fs;
+use std::fs::File;
 use std::thread;
 use std::io::{Read, Write};
+use std::iter;
 use std::net::{SocketAddr, TcpStream};
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
 use std::sync::mpsc::channel;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::str;
 
-use termcastd::config::TermcastConfig;
+use termcastd::config::{RecordConfig, SshConfig, TermcastConfig};
 use termcastd::TermcastServer;
 use termcastd::TermcastdMessage;
 
@@ -18,9 +26,9 @@ use mio::tcp::TcpListener;
 #[test]
 fn listen() {
     let config = TermcastConfig {
-        caster: "127.0.0.1:0".parse().unwrap(),
-        watcher: "127.0.0.1:0".parse().unwrap(),
-        motd: None,
+        caster: vec!["127.0.0.1:0".parse().unwrap()],
+        watcher: vec!["127.0.0.1:0".parse().unwrap()],
+        ..TermcastConfig::default()
     };
 
     assert!(TermcastServer::new(config).is_ok(), "Can bind both ports.");
@@ -32,9 +40,9 @@ fn bind_taken() {
     let l = TcpListener::bind(&sock).unwrap();
 
     let config = TermcastConfig {
-        caster: l.local_addr().unwrap(),
-        watcher: "127.0.0.1:0".parse().unwrap(),
-        motd: None,
+        caster: vec![l.local_addr().unwrap()],
+        watcher: vec!["127.0.0.1:0".parse().unwrap()],
+        ..TermcastConfig::default()
     };
 
     let tc = TermcastServer::new(config);
@@ -155,29 +163,717 @@ fn can_cast() {
 
 }
 
+#[test]
+fn raw_watcher_attach() {
+    let config = TermcastConfig {
+        caster: vec!["127.0.0.1:0".parse().unwrap()],
+        watcher: vec!["127.0.0.1:0".parse().unwrap()],
+        raw_watcher: vec!["127.0.0.1:0".parse().unwrap()],
+        ..local_config()
+    };
+    let server = start_server(config);
+    // Telnet watcher listeners come first.
+    assert_eq!(server.watcher.len(), 2);
+
+    let mut caster = caster_login(&server.caster[0], "caster1", "secret");
+    caster.write("cast data".as_bytes()).unwrap();
+
+    // The caster may not have finished logging in yet, so try a few times.
+    let mut found = false;
+    for _ in 0..10 {
+        let mut watcher = connect_timeout(&server.watcher[1]);
+        watcher.write("caster1\n".as_bytes()).unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0; 2048];
+        while let Ok(num_bytes) = watcher.read(&mut buf) {
+            if num_bytes == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..num_bytes]);
+            if received.windows(9).any(|w| w == b"cast data") {
+                found = true;
+                break;
+            }
+        }
+        if found {
+            // No telnet negotiation should have been sent.
+            assert!(!received.contains(&0xff));
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(found, "Raw watcher received the cast.");
+
+    // The other listener on the same server still negotiates telnet.
+    let mut watcher = connect_timeout(&server.watcher[0]);
+    let mut buf = [0; 2048];
+    let num_bytes = watcher.read(&mut buf).unwrap();
+    assert!(buf[..num_bytes].contains(&0xff), "Telnet watcher is negotiated with.");
 
-fn make_termcastd() -> TermcastServer {
+    server.channel.send(TermcastdMessage::Quit).unwrap();
+}
+
+#[test]
+fn several_listeners() {
+    let path = temp_path("caster.sock");
     let config = TermcastConfig {
-        caster: "127.0.0.1:0".parse().unwrap(),
-        watcher: "127.0.0.1:0".parse().unwrap(),
-        motd: None,
+        caster: vec!["127.0.0.1:0".parse().unwrap(), "127.0.0.1:0".parse().unwrap()],
+        watcher: Vec::new(),
+        raw_watcher: vec!["127.0.0.1:0".parse().unwrap(), "127.0.0.1:0".parse().unwrap()],
+        caster_socket: Some(path.clone()),
+        ..TermcastConfig::default()
     };
+    let server = start_server(config);
+    let (caster_addrs, watcher_addrs) = (&server.caster, &server.watcher);
+    assert_eq!(caster_addrs.len(), 2);
+    assert_eq!(watcher_addrs.len(), 2);
+
+    // A caster on each TCP listener and one on the Unix socket, all seen from either watcher
+    // listener.
+    let mut caster1 = caster_login(&caster_addrs[0], "caster1", "secret");
+    caster1.write("from caster1".as_bytes()).unwrap();
+    let mut caster2 = caster_login(&caster_addrs[1], "caster2", "secret");
+    caster2.write("from caster2".as_bytes()).unwrap();
+    let mut caster3 = UnixStream::connect(&path).unwrap();
+    caster3.write("hello caster3 secret\nfrom caster3".as_bytes()).unwrap();
+
+    assert!(raw_watch(&watcher_addrs[1], "caster1", b"from caster1").is_some(), "Watcher received caster1.");
+    assert!(raw_watch(&watcher_addrs[0], "caster2", b"from caster2").is_some(), "Watcher received caster2.");
+    assert!(raw_watch(&watcher_addrs[1], "caster3", b"from caster3").is_some(), "Watcher received caster3.");
+
+    server.channel.send(TermcastdMessage::Quit).unwrap();
+    server.thread.join().unwrap();
+    assert!(!path.exists(), "Caster socket is removed on exit.");
+}
 
-    TermcastServer::new(config).unwrap()
+// Runs against the system's OpenSSH client, when there is one.
+#[test]
+fn openssh_watcher() {
+    if Command::new("ssh").arg("-V").output().is_err() {
+        return;
+    }
+    let host_key = temp_path("ssh-host-key");
+    let config = TermcastConfig {
+        ssh: Some(SshConfig {
+            listen: "127.0.0.1:0".parse().unwrap(),
+            host_key: host_key.clone(),
+            password: None,
+        }),
+        ..local_config()
+    };
+    let server = start_server(config);
+    let ssh_addr = server.ssh.unwrap();
+
+    let mut ssh = Command::new("ssh")
+        .args(&["-tt", "-p", &ssh_addr.port().to_string(), "-o", "BatchMode=yes",
+                "-o", "StrictHostKeyChecking=no", "-o", "UserKnownHostsFile=/dev/null",
+                "-o", "LogLevel=ERROR", "watcher@127.0.0.1"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdout = ssh.stdout.take().unwrap();
+    let (output_tx, output_rx) = channel();
+    thread::spawn(move || {
+        let mut buf = [0; 2048];
+        while let Ok(num_bytes) = stdout.read(&mut buf) {
+            if num_bytes == 0 || output_tx.send(buf[..num_bytes].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut received = Vec::new();
+    while !received.windows(21).any(|w| w == b"0 sessions available.") {
+        match output_rx.recv_timeout(Duration::new(10, 0)) {
+            Ok(output) => received.extend(output),
+            Err(_) => {
+                let _ = ssh.kill();
+                panic!("OpenSSH client was shown the menu.");
+            },
+        }
+    }
+    ssh.stdin.as_mut().unwrap().write_all(b"q").unwrap();
+    assert!(ssh.wait().unwrap().success(), "Quitting from the menu ends the session cleanly.");
+
+    server.channel.send(TermcastdMessage::Quit).unwrap();
+    let _ = fs::remove_file(&host_key);
 }
 
-fn termcastd_thread() -> (thread::JoinHandle<()>, Sender<TermcastdMessage>, SocketAddr, SocketAddr) {
-    let (tx, rx) = channel();
+#[test]
+fn web_watcher() {
+    let config = TermcastConfig {
+        web: Some("127.0.0.1:0".parse().unwrap()),
+        ..local_config()
+    };
+    let server = start_server(config);
+    let web_addr = server.web.unwrap();
+
+    // The page is served and the connection closed.
+    let mut browser = connect_timeout(&web_addr);
+    browser.write("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".as_bytes()).unwrap();
+    let mut page = String::new();
+    browser.read_to_string(&mut page).unwrap();
+    assert!(page.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(page.contains("new WebSocket"));
+
+    // The WebSocket gets the main menu in a binary frame.
+    let mut socket = connect_timeout(&web_addr);
+    socket.write(concat!("GET /ws HTTP/1.1\r\n",
+                         "Host: localhost\r\n",
+                         "Upgrade: websocket\r\n",
+                         "Connection: Upgrade\r\n",
+                         "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n",
+                         "Sec-WebSocket-Version: 13\r\n\r\n").as_bytes()).unwrap();
+    let mut received = Vec::new();
+    let mut buf = [0; 2048];
+    while let Ok(num_bytes) = socket.read(&mut buf) {
+        if num_bytes == 0 {
+            break;
+        }
+        received.extend_from_slice(&buf[..num_bytes]);
+        if received.windows(11).any(|w| w == b"## Termcast") {
+            break;
+        }
+    }
+    let received = String::from_utf8_lossy(&received);
+    assert!(received.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    assert!(received.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    assert!(received.contains("## Termcast"), "Web watcher received the menu.");
+
+    server.channel.send(TermcastdMessage::Quit).unwrap();
+}
+
+#[test]
+fn web_on_watcher_port() {
+    let config = TermcastConfig {
+        watcher_http: true,
+        ..local_config()
+    };
+    let server = start_server(config);
+
+    let mut browser = connect_timeout(&server.watcher[0]);
+    browser.write("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".as_bytes()).unwrap();
+    let mut page = String::new();
+    browser.read_to_string(&mut page).unwrap();
+    assert!(page.starts_with("HTTP/1.1 200 OK\r\n"), "Watcher port serves the web page.");
+
+    // Telnet clients that negotiate straight away don't have to wait to be told apart.
+    let mut watcher = connect_timeout(&server.watcher[0]);
+    watcher.write(&[255, 251, 24]).unwrap();
+    let mut buf = [0; 2048];
+    assert!(watcher.read(&mut buf).unwrap() > 0, "Telnet watcher is negotiated with.");
+
+    server.channel.send(TermcastdMessage::Quit).unwrap();
+}
+
+#[test]
+fn web_caster() {
+    let config = TermcastConfig {
+        watcher: Vec::new(),
+        raw_watcher: vec!["127.0.0.1:0".parse().unwrap()],
+        web_caster: Some("127.0.0.1:0".parse().unwrap()),
+        ..local_config()
+    };
+    let server = start_server(config);
+
+    let mut caster = connect_timeout(&server.web_caster.unwrap());
+    caster.write(concat!("GET /cast HTTP/1.1\r\n",
+                         "Host: localhost\r\n",
+                         "Upgrade: websocket\r\n",
+                         "Connection: Upgrade\r\n",
+                         "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n",
+                         "Sec-WebSocket-Version: 13\r\n\r\n").as_bytes()).unwrap();
+    caster.write(&websocket_frame(1, b"hello webcaster secret")).unwrap();
+    caster.write(&websocket_frame(2, b"cast data")).unwrap();
+
+    // The caster may not have finished logging in yet, so try a few times.
+    let mut found = false;
+    for _ in 0..10 {
+        let mut watcher = connect_timeout(&server.watcher[0]);
+        watcher.write("webcaster\n".as_bytes()).unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0; 2048];
+        while let Ok(num_bytes) = watcher.read(&mut buf) {
+            if num_bytes == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..num_bytes]);
+            if received.windows(9).any(|w| w == b"cast data") {
+                found = true;
+                break;
+            }
+        }
+        if found {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(found, "WebSocket caster was relayed to the watcher.");
+
+    server.channel.send(TermcastdMessage::Quit).unwrap();
+}
+
+#[test]
+fn status_and_metrics() {
+    let config = TermcastConfig {
+        status: Some("127.0.0.1:0".parse().unwrap()),
+        metrics: Some("127.0.0.1:0".parse().unwrap()),
+        ..local_config()
+    };
+    let server = start_server(config);
+
+    let mut caster = caster_login(&server.caster[0], "caster1", "secret");
+    caster.write("cast data".as_bytes()).unwrap();
+
+    let status = http_get_until(&server.status.unwrap(), "/status", |response| {
+        response.contains("\"name\": \"caster1\"")
+    });
+    let status = status.expect("Status lists the caster.");
+    assert!(status.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(status.contains("\"total_watchers\": "));
+
+    let metrics = http_get_until(&server.metrics.unwrap(), "/metrics", |response| {
+        response.contains("termcastd_casters_connected 1\n") &&
+        response.contains("termcastd_caster_auth_total{result=\"success\"} 1\n") &&
+        response.contains("termcastd_caster_bytes_received_total{caster=\"caster1\"} 9\n")
+    });
+    let metrics = metrics.expect("Metrics count the caster.");
+    assert!(metrics.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(metrics.contains("# TYPE termcastd_casters_connected gauge\n"));
+
+    // A caster who has gone is no longer reported on.
+    drop(caster);
+    let metrics = http_get_until(&server.metrics.unwrap(), "/metrics", |response| {
+        response.contains("termcastd_casters_connected 0\n")
+    });
+    assert!(!metrics.expect("Metrics see the caster go.").contains("caster=\"caster1\""));
+
+    server.channel.send(TermcastdMessage::Quit).unwrap();
+}
+
+#[test]
+fn disconnect_reasons() {
+    let admin_path = temp_path("disconnect-reasons.sock");
+    let config = TermcastConfig {
+        watcher: Vec::new(),
+        raw_watcher: vec!["127.0.0.1:0".parse().unwrap()],
+        metrics: Some("127.0.0.1:0".parse().unwrap()),
+        admin_socket: Some(admin_path.clone()),
+        shutdown_timeout: 1,
+        ..local_config()
+    };
+    let server = start_server(config);
+    let (caster_addr, watcher_addr) = (server.caster[0], server.watcher[0]);
+    let metrics_addr = server.metrics.unwrap();
+    let mut buf = [0; 2048];
+
+    let mut caster = connect_timeout(&caster_addr);
+    caster.write("hello\n".as_bytes()).unwrap();
+    assert_eq!(caster.read(&mut buf).unwrap(), 0, "Refused caster is disconnected.");
+
+    let mut caster = caster_login(&caster_addr, "caster1", "secret");
+    caster.write("cast data".as_bytes()).unwrap();
+    let mut watcher = raw_watch(&watcher_addr, "caster1", b"cast data").expect("Watcher received the cast.");
+
+    // Hanging up sends the caster's watcher back to the menu.
+    drop(caster);
+    let mut received = Vec::new();
+    while !received.windows(21).any(|w| w == b"0 sessions available.") {
+        let num_bytes = watcher.read(&mut buf).unwrap();
+        assert!(num_bytes > 0, "Watcher is still connected.");
+        received.extend_from_slice(&buf[..num_bytes]);
+    }
+
+    watcher.write("q".as_bytes()).unwrap();
+    assert_eq!(watcher.read(&mut buf).unwrap(), 0, "Watcher quit from the menu.");
+
+    // A watcher who stops reading falls too far behind and is dropped.
+    let mut caster = caster_login(&caster_addr, "caster2", "secret");
+    caster.write("cast data".as_bytes()).unwrap();
+    let _stalled = raw_watch(&watcher_addr, "caster2", b"cast data").expect("Watcher received the cast.");
+    let flood = vec![b'x'; 64 * 1024];
+    for _ in 0..256 {
+        caster.write_all(&flood).unwrap();
+    }
+
+    let mut admin = UnixStream::connect(&admin_path).unwrap();
+    admin.set_read_timeout(Some(Duration::new(1, 0))).unwrap();
+    assert_eq!(admin_command(&mut admin, "kick caster2"), "ok\n");
+    assert_eq!(caster.read(&mut buf).unwrap(), 0, "Kicked caster is disconnected.");
+
+    // Failed attempts to watch and answered requests for the metrics are quits as well.
+    let metrics = http_get_until(&metrics_addr, "/metrics", |response| {
+        response.contains("termcastd_disconnects_total{reason=\"auth_failed\"} 1\n") &&
+        response.contains("termcastd_disconnects_total{reason=\"hangup\"} 1\n") &&
+        response.contains("termcastd_disconnects_total{reason=\"io_error\"} 1\n") &&
+        response.contains("termcastd_disconnects_total{reason=\"kicked\"} 1\n") &&
+        response.contains("termcastd_disconnects_total{reason=\"quit\"} ")
+    });
+    assert!(metrics.is_some(), "Metrics count each disconnect by reason.");
+
+    // A watcher who never takes the goodbye is timed out rather than holding up the shutdown.
+    let mut caster = caster_login(&caster_addr, "caster3", "secret");
+    caster.write("cast data".as_bytes()).unwrap();
+    let _stalled = raw_watch(&watcher_addr, "caster3", b"cast data").expect("Watcher received the cast.");
+    for _ in 0..8 {
+        caster.write_all(&flood).unwrap();
+    }
+    thread::sleep(Duration::from_millis(100));
+    server.channel.send(TermcastdMessage::Shutdown).unwrap();
+    server.thread.join().unwrap();
+}
+
+#[test]
+fn admin_socket() {
+    let path = temp_path("admin.sock");
+    let config = TermcastConfig {
+        admin_socket: Some(path.clone()),
+        ..local_config()
+    };
+    let server = start_server(config);
+    let caster_addr = server.caster[0];
+
+    let mut caster = caster_login(&caster_addr, "caster1", "secret");
+    caster.set_read_timeout(Some(Duration::new(1, 0))).unwrap();
+
+    let mut admin = UnixStream::connect(&path).unwrap();
+    admin.set_read_timeout(Some(Duration::new(1, 0))).unwrap();
 
+    // A second caster is named after the first one's token, so a name can't be mistaken for it.
+    let token = admin_caster_token(&mut admin, "caster1").expect("List shows the caster.");
+    let mut digits = caster_login(&caster_addr, &token, "secret");
+    digits.set_read_timeout(Some(Duration::new(1, 0))).unwrap();
+    assert!(admin_caster_token(&mut admin, &token).is_some(), "List shows the caster named with digits.");
+
+    // The socket of a running server isn't taken over.
+    let second = TermcastConfig {
+        admin_socket: Some(path.clone()),
+        ..local_config()
+    };
+    assert!(TermcastServer::new(second).is_err(), "A second server can't bind the admin socket.");
+
+    assert!(admin_command(&mut admin, "bogus").starts_with("error: "));
+    assert!(admin_command(&mut admin, "kick nobody").starts_with("error: "));
+    assert!(admin_command(&mut admin, "kick token:999999").starts_with("error: "));
+    let mut buf = [0; 64];
+    assert_eq!(admin_command(&mut admin, &format!("kick {}", token)), "ok\n");
+    assert_eq!(digits.read(&mut buf).unwrap(), 0, "Caster named with digits is kicked by name.");
+    assert!(caster.read(&mut buf).is_err(), "The caster with that token is still connected.");
+    assert_eq!(admin_command(&mut admin, &format!("kick token:{}", token)), "ok\n");
+    assert_eq!(caster.read(&mut buf).unwrap(), 0, "Caster is kicked by token.");
+
+    assert_eq!(admin_command(&mut admin, "shutdown"), "ok\n");
+    server.thread.join().unwrap();
+    assert!(!path.exists(), "Admin socket is removed on shutdown.");
+}
+
+#[test]
+fn graceful_shutdown() {
+    let server = start_server(local_config());
+
+    let mut caster = caster_login(&server.caster[0], "caster1", "secret");
+    caster.set_read_timeout(Some(Duration::new(2, 0))).unwrap();
+
+    // Wait for the menu so the watcher is known to be past the protocol sniffing.
+    let mut watcher = connect(&server.watcher[0]);
+    watcher.set_read_timeout(Some(Duration::new(2, 0))).unwrap();
+    let mut buf = [0; 2048];
+    assert!(watcher.read(&mut buf).unwrap() > 0);
+
+    server.channel.send(TermcastdMessage::Shutdown).unwrap();
+
+    let mut received = Vec::new();
+    watcher.read_to_end(&mut received).unwrap();
+    let received = String::from_utf8_lossy(&received);
+    assert!(received.contains("The server is restarting."), "Watcher is told about the shutdown.");
+    assert_eq!(caster.read(&mut buf).unwrap(), 0, "Caster is disconnected.");
+    server.thread.join().unwrap();
+}
+
+#[test]
+fn reload_config() {
+    let config_path = temp_path("reload.toml");
+    let admin_path = temp_path("reload.sock");
+    // A port that was free a moment ago, so the watcher listener can be widened to every address
+    // on it.
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let listen = |host: &str| {
+        format!("[server]\ncaster_listen = \"127.0.0.1:0\"\nwatcher_listen = \"{}:{}\"\n", host, port)
+    };
+    let admin = format!("[admin]\nsocket = \"{}\"\n", admin_path.display());
+    File::create(&config_path).unwrap()
+         .write_all(format!("{}{}", listen("127.0.0.1"), admin).as_bytes()).unwrap();
+
+    let config = TermcastConfig::from_config(&config_path.to_string_lossy()).unwrap();
+    let server = start_server(config);
+
+    File::create(&config_path).unwrap()
+         .write_all(format!("{}motd = \"Hello there\"\n{}[metrics]\nlisten = \"127.0.0.1:0\"\n",
+                            listen("127.0.0.1"), admin).as_bytes()).unwrap();
+    let mut admin_sock = UnixStream::connect(&admin_path).unwrap();
+    admin_sock.set_read_timeout(Some(Duration::new(1, 0))).unwrap();
+    assert_eq!(admin_command(&mut admin_sock, "reload"), "Metrics listener moved to 127.0.0.1:0.\nok\n");
+    // Nothing changed the second time.
+    assert_eq!(admin_command(&mut admin_sock, "reload"), "ok\n");
+
+    // The old listener is in the way of the new one, so it's closed first.
+    File::create(&config_path).unwrap()
+         .write_all(format!("{}motd = \"Hello there\"\n{}[metrics]\nlisten = \"127.0.0.1:0\"\n",
+                            listen("0.0.0.0"), admin).as_bytes()).unwrap();
+    assert_eq!(admin_command(&mut admin_sock, "reload"),
+               format!("Now listening for watchers on 0.0.0.0:{}.\n\
+                        No longer listening for watchers on 127.0.0.1:{}.\nok\n", port, port));
+
+    // One address that can't be listened on means none of the new ones are.
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let free = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    File::create(&config_path).unwrap()
+         .write_all(format!("[server]\ncaster_listen = \"127.0.0.1:0\"\n\
+                             watcher_listen = [\"{}\", \"{}\"]\n\
+                             motd = \"Hello there\"\n{}[metrics]\nlisten = \"127.0.0.1:0\"\n",
+                            free, taken.local_addr().unwrap(), admin).as_bytes()).unwrap();
+    let reply = admin_command(&mut admin_sock, "reload");
+    assert!(reply.starts_with(&format!("Could not listen for watchers on {}: ",
+                                       taken.local_addr().unwrap())));
+    assert!(!reply.contains("Now listening"), "Nothing was moved.");
+    assert!(TcpStream::connect(&free).is_err(), "The address that could be listened on was let go.");
+
+    let mut watcher = connect(&server.watcher[0]);
+    watcher.set_read_timeout(Some(Duration::new(2, 0))).unwrap();
+    let mut received = Vec::new();
+    let mut buf = [0; 2048];
+    while !received.windows(11).any(|w| w == b"Hello there") {
+        let num_bytes = watcher.read(&mut buf).unwrap();
+        assert!(num_bytes > 0, "Menu shows the new MOTD.");
+        received.extend_from_slice(&buf[..num_bytes]);
+    }
+
+    server.channel.send(TermcastdMessage::Quit).unwrap();
+    let _ = fs::remove_file(&config_path);
+}
+
+#[test]
+fn upgrade_handover() {
+    fn raw_config() -> TermcastConfig {
+        TermcastConfig {
+            watcher: Vec::new(),
+            raw_watcher: vec!["127.0.0.1:0".parse().unwrap()],
+            ..local_config()
+        }
+    }
+
+    let (tx, rx) = channel();
     let thd = thread::spawn(move || {
-        let mut tc = make_termcastd();
+        let mut tc = TermcastServer::new(raw_config()).unwrap();
         let (caster_addr, watcher_addr) = tc.get_socket_addrs().unwrap();
         tx.send((tc.get_channel(), caster_addr, watcher_addr)).unwrap();
         tc.run();
+        assert!(tc.upgrade_requested());
+
+        // Taken over within the same process instead of by a new binary, but otherwise the way
+        // termcastd does it.
+        let handover = tc.hand_over().unwrap();
+        let mut new_tc = TermcastServer::take_over(raw_config(), handover.into_raw_fd()).unwrap();
+        assert_eq!(new_tc.get_socket_addrs().unwrap(), (caster_addr, watcher_addr));
+        tx.send((new_tc.get_channel(), caster_addr, watcher_addr)).unwrap();
+        new_tc.run();
     });
-
     let (ev_channel, caster_addr, watcher_addr) = rx.recv().unwrap();
 
+    let mut caster = caster_login(&caster_addr, "caster1", "secret");
+    caster.write("before".as_bytes()).unwrap();
+
+    // The caster may not have finished logging in yet, so try a few times.
+    let mut watcher = None;
+    for _ in 0..10 {
+        let mut candidate = connect_timeout(&watcher_addr);
+        candidate.write("caster1\n".as_bytes()).unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0; 2048];
+        while let Ok(num_bytes) = candidate.read(&mut buf) {
+            if num_bytes == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..num_bytes]);
+            if received.windows(6).any(|w| w == b"before") {
+                watcher = Some(candidate);
+                break;
+            }
+        }
+        if watcher.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let mut watcher = watcher.expect("Watcher received the cast.");
+
+    ev_channel.send(TermcastdMessage::Upgrade).unwrap();
+    let (ev_channel, _, _) = rx.recv().unwrap();
+
+    caster.write("after".as_bytes()).unwrap();
+    let mut received = Vec::new();
+    let mut buf = [0; 2048];
+    while !received.windows(5).any(|w| w == b"after") {
+        let num_bytes = watcher.read(&mut buf).unwrap();
+        assert!(num_bytes > 0, "Watcher kept watching across the upgrade.");
+        received.extend_from_slice(&buf[..num_bytes]);
+    }
+
+    ev_channel.send(TermcastdMessage::Quit).unwrap();
+    thd.join().unwrap();
+}
+
+#[test]
+fn recording() {
+    let directory = temp_path("recordings");
+    fs::create_dir_all(&directory).unwrap();
+    let old = directory.join("old.2000-01-01.000000.9.ttyrec");
+    File::create(&old).unwrap().write_all(b"old recording").unwrap();
+    let touched = Command::new("touch").arg("-d").arg("2000-01-01").arg(&old).status().unwrap();
+    assert!(touched.success());
+
+    let config = TermcastConfig {
+        record: Some(RecordConfig {
+            directory: directory.clone(),
+            max_size: None,
+            max_age: Some(30),
+            asciicast: true,
+            width: 80,
+            height: 24,
+        }),
+        ..local_config()
+    };
+    let server = start_server(config);
+
+    let mut caster = caster_login(&server.caster[0], "caster1", "secret");
+    caster.write("cast data".as_bytes()).unwrap();
+    assert!(raw_watch(&server.watcher[0], "caster1", b"cast data").is_some(), "Watcher received the cast.");
+
+    // Cleaned up in the background once the server has started.
+    for _ in 0..50 {
+        if !old.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(!old.exists(), "Old recording is removed.");
+
+    let mut names: Vec<String> = fs::read_dir(&directory).unwrap()
+                                     .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                                     .collect();
+    names.sort();
+    assert_eq!(names.len(), 2, "Recorded in both formats: {:?}", names);
+    assert!(names[0].starts_with("caster1.") && names[0].ends_with(".cast"));
+    assert!(names[1].starts_with("caster1.") && names[1].ends_with(".ttyrec"));
+    // Each caster's recording has their token in the name, so two casters with the same name
+    // connecting in the same second don't share a file.
+    assert_eq!(names[0].split('.').count(), 5);
+    let ttyrec = fs::metadata(directory.join(&names[1])).unwrap();
+    assert!(ttyrec.len() >= 12 + 9, "The cast is recorded.");
+
+    server.channel.send(TermcastdMessage::Quit).unwrap();
+    server.thread.join().unwrap();
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn recordings_menu() {
+    let directory = temp_path("playback");
+    fs::create_dir_all(&directory).unwrap();
+    let long_name: String = iter::repeat('x').take(200).collect();
+    for name in &[String::from("evil\x1b[2J\x07"), long_name] {
+        File::create(directory.join(format!("{}.ttyrec", name))).unwrap();
+    }
+    let config = TermcastConfig {
+        playback: Some(directory.clone()),
+        ..local_config()
+    };
+    let server = start_server(config);
+
+    let mut watcher = connect_timeout(&server.watcher[0]);
+    watcher.write(b"r").unwrap();
+    let prompt = b"Play which recording?";
+    let mut received = Vec::new();
+    let mut buf = [0; 2048];
+    while !received.windows(prompt.len()).any(|w| w == prompt) {
+        let num_bytes = watcher.read(&mut buf).unwrap();
+        assert!(num_bytes > 0, "Watcher was disconnected.");
+        received.extend_from_slice(&buf[..num_bytes]);
+    }
+    let menu = String::from_utf8_lossy(&received).into_owned();
+    let entries: Vec<&str> = menu.split("\r\n")
+                                 .filter(|line| line.starts_with(" a) ") || line.starts_with(" b) "))
+                                 .collect();
+    assert_eq!(entries.len(), 2, "Both recordings are listed: {:?}", menu);
+    for entry in entries {
+        assert!(!entry.chars().any(|c| c.is_control()), "No control characters: {:?}", entry);
+        assert!(entry.chars().count() < 80, "Fits the terminal: {:?}", entry);
+    }
+
+    server.channel.send(TermcastdMessage::Quit).unwrap();
+    server.thread.join().unwrap();
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+// A server running on its own thread, and the addresses its listeners ended up on.
+struct Server {
+    thread: thread::JoinHandle<()>,
+    channel: Sender<TermcastdMessage>,
+    caster: Vec<SocketAddr>,
+    watcher: Vec<SocketAddr>,
+    ssh: Option<SocketAddr>,
+    web: Option<SocketAddr>,
+    web_caster: Option<SocketAddr>,
+    status: Option<SocketAddr>,
+    metrics: Option<SocketAddr>,
+}
+
+// One caster and one watcher listener on ports picked by the system.
+fn local_config() -> TermcastConfig {
+    TermcastConfig {
+        caster: vec!["127.0.0.1:0".parse().unwrap()],
+        watcher: vec!["127.0.0.1:0".parse().unwrap()],
+        ..TermcastConfig::default()
+    }
+}
+
+fn start_server(config: TermcastConfig) -> Server {
+    let (tx, rx) = channel();
+    let handle = thread::spawn(move || {
+        let mut tc = TermcastServer::new(config).unwrap();
+        tx.send((tc.get_channel(),
+                 tc.get_caster_addrs().unwrap(),
+                 tc.get_watcher_addrs().unwrap(),
+                 tc.get_ssh_addr().ok(),
+                 tc.get_web_addr().ok(),
+                 tc.get_web_caster_addr().ok(),
+                 tc.get_status_addr().ok(),
+                 tc.get_metrics_addr().ok())).unwrap();
+        tc.run();
+    });
+
+    let (channel, caster, watcher, ssh, web, web_caster, status, metrics) = rx.recv().unwrap();
+    Server {
+        thread: handle,
+        channel: channel,
+        caster: caster,
+        watcher: watcher,
+        ssh: ssh,
+        web: web,
+        web_caster: web_caster,
+        status: status,
+        metrics: metrics,
+    }
+}
+
+fn termcastd_thread() -> (thread::JoinHandle<()>, Sender<TermcastdMessage>, SocketAddr, SocketAddr) {
+    let server = start_server(local_config());
+    let (ev_channel, caster_addr, watcher_addr) = (server.channel, server.caster[0],
+                                                   server.watcher[0]);
+    let thd = server.thread;
+
     // Connect to the watcher address. Once something is received, termcastd can be considered
     // running. Then return connection details for the tests.
     {
@@ -207,3 +903,85 @@ fn caster_login(addr: &SocketAddr, name: &str, password: &str) -> TcpStream {
     stream.write_fmt(format_args!("hello {} {}\n", name, password)).unwrap();
     return stream;
 }
+
+// Watch the named caster as a raw watcher until `expected` shows up, returning the watcher's
+// connection. The caster may not have finished logging in yet, so this tries a few times.
+fn raw_watch(addr: &SocketAddr, name: &str, expected: &[u8]) -> Option<TcpStream> {
+    for _ in 0..10 {
+        let mut watcher = connect_timeout(addr);
+        watcher.write_fmt(format_args!("{}\n", name)).unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0; 2048];
+        while let Ok(num_bytes) = watcher.read(&mut buf) {
+            if num_bytes == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..num_bytes]);
+            if received.windows(expected.len()).any(|w| w == expected) {
+                return Some(watcher);
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    None
+}
+
+// Ask for `path` until the response passes `check`, returning that response. What's being checked
+// for may take a moment to happen, so this tries a few times.
+fn http_get_until<F: Fn(&str) -> bool>(addr: &SocketAddr, path: &str, check: F) -> Option<String> {
+    for _ in 0..10 {
+        let mut client = connect_timeout(addr);
+        client.write_fmt(format_args!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path)).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        if check(&response) {
+            return Some(response);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    None
+}
+
+// A path in the temporary directory no other test run is using.
+fn temp_path(name: &str) -> PathBuf {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    env::temp_dir().join(format!("termcastd-test-{}-{}-{}", now.as_secs(), now.subsec_nanos(), name))
+}
+
+// A masked frame, as clients have to send them.
+fn websocket_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mask = [1, 2, 3, 4];
+    let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, &b)| b ^ mask[i % 4]));
+    frame
+}
+
+// The token of the caster with this name, as the admin socket lists it. The caster may not have
+// finished logging in yet, so this tries a few times.
+fn admin_caster_token(admin: &mut UnixStream, name: &str) -> Option<String> {
+    for _ in 0..10 {
+        let list = admin_command(admin, "list");
+        for line in list.lines() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            if fields.len() > 2 && fields[0] == "caster" && fields[2] == name {
+                return Some(String::from(fields[1]));
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    None
+}
+
+// Send one command over the admin socket and read back the reply, up to its closing line.
+fn admin_command(admin: &mut UnixStream, command: &str) -> String {
+    admin.write_fmt(format_args!("{}\n", command)).unwrap();
+    let mut reply = Vec::new();
+    let mut buf = [0; 1024];
+    while !(reply.ends_with(b"ok\n") || (reply.starts_with(b"error: ") && reply.ends_with(b"\n"))) {
+        let num_bytes = admin.read(&mut buf).unwrap();
+        assert!(num_bytes > 0, "Admin socket closed.");
+        reply.extend_from_slice(&buf[..num_bytes]);
+    }
+    String::from_utf8(reply).unwrap()
+}